@@ -0,0 +1,12 @@
+#![no_main]
+//! `Transfer-Encoding: chunked` bodies; decoding never yields more than was sent.
+
+use http::chunked::ChunkedReader;
+use libfuzzer_sys::fuzz_target;
+use std::io::Read;
+
+fuzz_target!(|data: &[u8]| {
+    let mut body = Vec::new();
+    let _ = ChunkedReader::new(data).take(1 << 20).read_to_end(&mut body);
+    assert!(body.len() <= data.len());
+});
@@ -0,0 +1,12 @@
+#![no_main]
+//! Query strings and form bodies; re-encoding decoded fields must give them back unchanged.
+
+use http::form::{encode_urlencoded, parse_urlencoded, percent_decode};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let query = String::from_utf8_lossy(data);
+    let fields = parse_urlencoded(&query);
+    assert_eq!(parse_urlencoded(&encode_urlencoded(&fields)), fields);
+    let _ = percent_decode(&query);
+});
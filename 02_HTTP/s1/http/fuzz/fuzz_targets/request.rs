@@ -0,0 +1,16 @@
+#![no_main]
+//! Request heads and bodies, as `handle_connection` reads them off a socket.
+
+use http::httprequest::HttpRequest;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = data;
+    if let Ok(mut req) = HttpRequest::read_head(&mut reader) {
+        let _ = req.path();
+        let _ = req.query_params();
+        let _ = req.header("Host");
+        let _ = req.read_body(&mut reader, 64 * 1024);
+    }
+    let _: HttpRequest = String::from_utf8_lossy(data).into_owned().into();
+});
@@ -0,0 +1,13 @@
+#![no_main]
+//! Response heads from upstream servers, as the reverse proxy reads them.
+
+use http::httpresponse::ResponseHead;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(head) = ResponseHead::read_from(&mut &data[..]) {
+        assert!((100..1000).contains(&head.status_code));
+        let _ = head.content_length();
+        let _ = head.is_chunked();
+    }
+});
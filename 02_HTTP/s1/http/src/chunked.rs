@@ -0,0 +1,249 @@
+use crate::httprequest::MAX_HEAD_SIZE;
+use std::io::{self, BufRead, Read, Write};
+
+/// Largest chunk size line accepted by `ChunkedReader`, including extensions.
+const MAX_SIZE_LINE: usize = 1024;
+
+/// Whether a `Transfer-Encoding` value ends in `chunked`, the coding that frames the
+/// message whatever codings come before it (RFC 7230, 3.3.3).
+pub fn is_chunked(transfer_encoding: &str) -> bool {
+    transfer_encoding
+        .rsplit(',')
+        .next()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Writes a body using `Transfer-Encoding: chunked`; each `write` becomes one chunk.
+/// `finish` (or drop) sends the terminating zero-length chunk.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    finished: bool,
+}
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter {
+            inner,
+            finished: false,
+        }
+    }
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()
+    }
+}
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "chunked body already finished"));
+        }
+        // A zero-length chunk would end the body, so empty writes are skipped.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:X}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<W: Write> Drop for ChunkedWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, yielding the payload bytes.
+/// Trailers after the last chunk are read and discarded; like a request head, they
+/// may take up to 64 KiB.
+pub struct ChunkedReader<R: BufRead> {
+    inner: R,
+    remaining: u64,
+    done: bool,
+}
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        (&mut self.inner)
+            .take(MAX_SIZE_LINE as u64)
+            .read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\n") {
+            return Err(invalid("unterminated chunk line"));
+        }
+        String::from_utf8(line).map_err(|_| invalid("chunk line is not UTF-8"))
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let line = self.read_line()?;
+        let size = line.trim_end().split(';').next().unwrap_or("").trim();
+        self.remaining = parse_chunk_size(size)?;
+        if self.remaining == 0 {
+            let mut trailer_size = 0;
+            loop {
+                let trailer = self.read_line()?;
+                trailer_size += trailer.len() as u64;
+                if trailer_size > MAX_HEAD_SIZE {
+                    return Err(invalid("trailers too large"));
+                }
+                if trailer.trim_end().is_empty() {
+                    break;
+                }
+            }
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.next_chunk()?;
+            if self.done {
+                return Ok(0);
+            }
+        }
+        let max = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunk"));
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            let mut crlf = [0; 2];
+            self.inner.read_exact(&mut crlf)?;
+            if &crlf != b"\r\n" {
+                return Err(invalid("chunk not followed by CRLF"));
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn parse_chunk_size(size: &str) -> io::Result<u64> {
+    if size.is_empty() || size.len() > 16 || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid("bad chunk size"));
+    }
+    u64::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_chunked_writer() {
+        let mut out = Vec::new();
+        {
+            let mut w = ChunkedWriter::new(&mut out);
+            w.write_all(b"Hello, ").unwrap();
+            w.write_all(b"").unwrap();
+            w.write_all(b"world!!!!!!!!!!").unwrap();
+        }
+        assert_eq!(out, b"7\r\nHello, \r\nF\r\nworld!!!!!!!!!!\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_chunked_reader_roundtrip() {
+        let body = b"7;ext=1\r\nHello, \r\n6\r\nworld!\r\n0\r\nX-Trailer: yes\r\n\r\nNEXT";
+        let mut reader = ChunkedReader::new(&body[..]);
+        let mut decoded = String::new();
+        reader.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "Hello, world!");
+        let mut rest = String::new();
+        reader.into_inner().read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+    }
+
+    #[test]
+    fn test_chunked_reader_bounds_trailers() {
+        let trailers = |count: usize| format!("3\r\nabc\r\n0\r\n{}\r\n", "X-Checksum: 0123456789abcdef\r\n".repeat(count));
+        let mut out = Vec::new();
+        ChunkedReader::new(trailers(100).as_bytes()).read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"abc");
+        let err = ChunkedReader::new(trailers(3000).as_bytes()).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_chunked_reader_rejects_bad_input() {
+        for body in [&b"zz\r\nabc"[..], b"3\r\nabcXY0\r\n\r\n", b"5\r\nab", b"ffffffffffffffffff\r\n"] {
+            let mut out = Vec::new();
+            assert!(ChunkedReader::new(body).read_to_end(&mut out).is_err());
+        }
+    }
+
+    /// Chunk framing for `test_garbage_never_panics` to mix with random bytes.
+    const PIECES: [&[u8]; 6] = [b"\r\n", b"0", b"f", b";x=y", b"ffffffff", b"\n"];
+
+    proptest! {
+        #[test]
+        fn test_random_bodies_roundtrip(
+            body in prop::collection::vec(any::<u8>(), 0..300),
+            splits in prop::collection::vec(any::<prop::sample::Index>(), 0..8),
+            capacity in 1..8usize,
+        ) {
+            let mut encoded = Vec::new();
+            {
+                let mut w = ChunkedWriter::new(&mut encoded);
+                let mut rest = &body[..];
+                for split in &splits {
+                    let (chunk, tail) = rest.split_at(split.index(rest.len() + 1));
+                    w.write_all(chunk).unwrap();
+                    rest = tail;
+                }
+                w.write_all(rest).unwrap();
+            }
+            // Small buffers split chunk lines and data across reads.
+            let buffered = io::BufReader::with_capacity(capacity, &encoded[..]);
+            let mut decoded = Vec::new();
+            ChunkedReader::new(buffered).read_to_end(&mut decoded).unwrap();
+            prop_assert_eq!(decoded, body);
+        }
+
+        #[test]
+        fn test_garbage_never_panics(
+            input in prop::collection::vec(
+                prop_oneof![
+                    prop::sample::select(&PIECES[..]).prop_map(<[u8]>::to_vec),
+                    any::<u8>().prop_map(|b| vec![b]),
+                ],
+                0..30,
+            ).prop_map(|pieces| pieces.concat()),
+        ) {
+            let mut out = Vec::new();
+            let _ = ChunkedReader::new(&input[..]).take(1 << 16).read_to_end(&mut out);
+            prop_assert!(out.len() <= input.len());
+        }
+    }
+}
@@ -0,0 +1,710 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Size limits enforced while decoding a form body.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Largest single field or file part, in bytes.
+    pub max_part_size: usize,
+    /// Largest body as a whole, in bytes.
+    pub max_total_size: usize,
+    /// File parts bigger than this are written to a temp file instead of memory.
+    pub spill_threshold: usize,
+    /// Directory used for spilled file parts.
+    pub temp_dir: PathBuf,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_part_size: 10 * 1024 * 1024,
+            max_total_size: 20 * 1024 * 1024,
+            spill_threshold: 64 * 1024,
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FormError {
+    Io(io::Error),
+    PartTooLarge,
+    BodyTooLarge,
+    Malformed(String),
+}
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::Io(e) => write!(f, "io error: {}", e),
+            FormError::PartTooLarge => write!(f, "form part exceeds size limit"),
+            FormError::BodyTooLarge => write!(f, "form body exceeds size limit"),
+            FormError::Malformed(msg) => write!(f, "malformed form body: {}", msg),
+        }
+    }
+}
+impl std::error::Error for FormError {}
+impl From<io::Error> for FormError {
+    fn from(e: io::Error) -> Self {
+        FormError::Io(e)
+    }
+}
+
+/// Decodes `%XX` escapes and `+` as space. Invalid escapes are kept verbatim.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_val(bytes[i + 1]), hex_val(bytes[i + 2])) {
+                    (Some(h), Some(l)) => {
+                        out.push(h << 4 | l);
+                        i += 2;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_val(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Percent-encodes `s` for a form body or query string; spaces become `+`.
+pub fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => out.push(b as char),
+            b' ' => out.push('+'),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Builds an `application/x-www-form-urlencoded` string from `pairs`, the inverse of `parse_urlencoded`.
+pub fn encode_urlencoded(pairs: &[(String, String)]) -> String {
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", percent_encode(k), percent_encode(v)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Parses an `application/x-www-form-urlencoded` string (a body or a query string).
+pub fn parse_urlencoded(s: &str) -> Vec<(String, String)> {
+    s.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            let key = percent_decode(kv.next().unwrap_or(""));
+            let value = percent_decode(kv.next().unwrap_or(""));
+            (key, value)
+        })
+        .collect()
+}
+
+/// Streaming decoder for `application/x-www-form-urlencoded` bodies,
+/// yielding one `(name, value)` field at a time.
+pub struct UrlEncodedDecoder<R: Read> {
+    reader: R,
+    limits: Limits,
+    buf: Vec<u8>,
+    total: usize,
+    eof: bool,
+}
+impl<R: Read> UrlEncodedDecoder<R> {
+    pub fn new(reader: R, limits: Limits) -> Self {
+        UrlEncodedDecoder {
+            reader,
+            limits,
+            buf: Vec::new(),
+            total: 0,
+            eof: false,
+        }
+    }
+    fn next_field(&mut self) -> Result<Option<(String, String)>, FormError> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|b| *b == b'&') {
+                let field: Vec<u8> = self.buf.drain(..=pos).collect();
+                if field.len() > 1 {
+                    return Ok(Some(decode_pair(&field[..field.len() - 1])));
+                }
+                continue;
+            }
+            if self.buf.len() > self.limits.max_part_size {
+                return Err(FormError::PartTooLarge);
+            }
+            if self.eof {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                let field = std::mem::take(&mut self.buf);
+                return Ok(Some(decode_pair(&field)));
+            }
+            let mut chunk = [0; READ_CHUNK];
+            let n = self.reader.read(&mut chunk)?;
+            self.total += n;
+            if self.total > self.limits.max_total_size {
+                return Err(FormError::BodyTooLarge);
+            }
+            if n == 0 {
+                self.eof = true;
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+impl<R: Read> Iterator for UrlEncodedDecoder<R> {
+    type Item = Result<(String, String), FormError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_field().transpose()
+    }
+}
+
+fn decode_pair(field: &[u8]) -> (String, String) {
+    let field = String::from_utf8_lossy(field);
+    let mut kv = field.splitn(2, '=');
+    let key = percent_decode(kv.next().unwrap_or(""));
+    let value = percent_decode(kv.next().unwrap_or(""));
+    (key, value)
+}
+
+/// Extracts the `boundary` parameter from a `multipart/form-data` content type.
+pub fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let mime = params.next()?.trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v.trim().trim_matches('"').to_string())
+        .filter(|b| !b.is_empty())
+}
+
+/// A decoded multipart part: a plain field when `filename` is `None`, otherwise a file upload.
+#[derive(Debug)]
+pub struct Part {
+    pub headers: Vec<(String, String)>,
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: PartData,
+}
+
+#[derive(Debug)]
+pub enum PartData {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+impl Part {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+    pub fn len(&self) -> usize {
+        match &self.data {
+            PartData::Memory(b) => b.len(),
+            PartData::File(f) => f.len,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The part body as text; `None` for spilled parts.
+    pub fn text(&self) -> Option<String> {
+        match &self.data {
+            PartData::Memory(b) => Some(String::from_utf8_lossy(b).into_owned()),
+            PartData::File(_) => None,
+        }
+    }
+}
+
+/// A file part spilled to disk. The file is removed on drop unless `persist` is called.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    len: usize,
+    keep: bool,
+}
+impl TempFile {
+    fn create(dir: &Path) -> io::Result<(TempFile, File)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let name = format!(
+            "http-form-{}-{}-{}",
+            std::process::id(),
+            nanos,
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok((
+            TempFile {
+                path,
+                len: 0,
+                keep: false,
+            },
+            file,
+        ))
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+    /// Moves the file to `dest`, keeping it after this value is dropped.
+    pub fn persist(mut self, dest: &Path) -> io::Result<()> {
+        fs::rename(&self.path, dest)?;
+        self.keep = true;
+        Ok(())
+    }
+}
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Preamble,
+    Headers,
+    Done,
+}
+
+/// Streaming decoder for `multipart/form-data` bodies, yielding one `Part` at a time.
+pub struct MultipartDecoder<R: Read> {
+    reader: R,
+    limits: Limits,
+    /// `--boundary`, the delimiter as it appears at the very start of the body.
+    dash_boundary: Vec<u8>,
+    /// `\r\n--boundary`, the delimiter that terminates each part body.
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    total: usize,
+    eof: bool,
+    state: State,
+}
+impl<R: Read> MultipartDecoder<R> {
+    pub fn new(reader: R, boundary: &str, limits: Limits) -> Self {
+        let dash_boundary = format!("--{}", boundary).into_bytes();
+        let mut delimiter = b"\r\n".to_vec();
+        delimiter.extend_from_slice(&dash_boundary);
+        MultipartDecoder {
+            reader,
+            limits,
+            dash_boundary,
+            delimiter,
+            buf: Vec::new(),
+            total: 0,
+            eof: false,
+            state: State::Preamble,
+        }
+    }
+
+    /// Reads another chunk into the buffer; returns false at end of input.
+    fn fill(&mut self) -> Result<bool, FormError> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0; READ_CHUNK];
+        let n = self.reader.read(&mut chunk)?;
+        self.total += n;
+        if self.total > self.limits.max_total_size {
+            return Err(FormError::BodyTooLarge);
+        }
+        if n == 0 {
+            self.eof = true;
+            return Ok(false);
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(true)
+    }
+
+    fn unexpected_eof() -> FormError {
+        FormError::Malformed("unexpected end of body".into())
+    }
+
+    /// Skips the preamble and the first boundary line.
+    fn skip_preamble(&mut self) -> Result<(), FormError> {
+        loop {
+            if let Some(pos) = find(&self.buf, &self.dash_boundary) {
+                self.buf.drain(..pos + self.dash_boundary.len());
+                return Ok(());
+            }
+            // Keep a tail long enough to hold a boundary split across reads.
+            let keep = self.dash_boundary.len();
+            if self.buf.len() > keep {
+                self.buf.drain(..self.buf.len() - keep);
+            }
+            if !self.fill()? {
+                return Err(Self::unexpected_eof());
+            }
+        }
+    }
+
+    /// After a boundary: `--` closes the body, `\r\n` starts another part.
+    fn after_boundary(&mut self) -> Result<bool, FormError> {
+        while self.buf.len() < 2 {
+            if !self.fill()? {
+                return Err(Self::unexpected_eof());
+            }
+        }
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(false);
+        }
+        if self.buf.starts_with(b"\r\n") {
+            self.buf.drain(..2);
+            return Ok(true);
+        }
+        Err(FormError::Malformed("bad boundary line".into()))
+    }
+
+    fn read_headers(&mut self) -> Result<Vec<(String, String)>, FormError> {
+        loop {
+            if self.buf.starts_with(b"\r\n") {
+                self.buf.drain(..2);
+                return Ok(Vec::new());
+            }
+            if let Some(pos) = find(&self.buf, b"\r\n\r\n") {
+                let raw: Vec<u8> = self.buf.drain(..pos + 4).collect();
+                let text = String::from_utf8_lossy(&raw[..pos]);
+                let headers = text
+                    .split("\r\n")
+                    .map(|line| match line.split_once(':') {
+                        Some((k, v)) => Ok((k.trim().to_string(), v.trim().to_string())),
+                        None => Err(FormError::Malformed(format!("bad part header: {}", line))),
+                    })
+                    .collect();
+                return headers;
+            }
+            if self.buf.len() > self.limits.max_part_size {
+                return Err(FormError::PartTooLarge);
+            }
+            if !self.fill()? {
+                return Err(Self::unexpected_eof());
+            }
+        }
+    }
+
+    fn read_body(&mut self, spillable: bool) -> Result<PartData, FormError> {
+        let mut sink = Sink::Memory(Vec::new());
+        let mut len = 0;
+        loop {
+            let (chunk_end, found) = match find(&self.buf, &self.delimiter) {
+                Some(pos) => (pos, true),
+                // Everything except a possible partial delimiter at the end is body.
+                None => (self.buf.len().saturating_sub(self.delimiter.len()), false),
+            };
+            len += chunk_end;
+            if len > self.limits.max_part_size {
+                return Err(FormError::PartTooLarge);
+            }
+            sink.write(&self.buf[..chunk_end])?;
+            self.buf.drain(..chunk_end);
+            if spillable && len > self.limits.spill_threshold {
+                sink = sink.spill(&self.limits.temp_dir)?;
+            }
+            if found {
+                self.buf.drain(..self.delimiter.len());
+                return sink.finish(len);
+            }
+            if !self.fill()? {
+                return Err(Self::unexpected_eof());
+            }
+        }
+    }
+
+    fn next_part(&mut self) -> Result<Option<Part>, FormError> {
+        if self.state == State::Done {
+            return Ok(None);
+        }
+        if self.state == State::Preamble {
+            self.skip_preamble()?;
+            self.state = State::Headers;
+        }
+        if !self.after_boundary()? {
+            return Ok(None);
+        }
+        let headers = self.read_headers()?;
+        let disposition = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("Content-Disposition"))
+            .map(|(_, v)| v.clone())
+            .unwrap_or_default();
+        let name = disposition_param(&disposition, "name");
+        let filename = disposition_param(&disposition, "filename");
+        let content_type = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, v)| v.clone());
+        let data = self.read_body(filename.is_some())?;
+        Ok(Some(Part {
+            headers,
+            name,
+            filename,
+            content_type,
+            data,
+        }))
+    }
+}
+impl<R: Read> Iterator for MultipartDecoder<R> {
+    type Item = Result<Part, FormError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let part = self.next_part();
+        if part.is_err() {
+            self.state = State::Done;
+        }
+        part.transpose()
+    }
+}
+
+enum Sink {
+    Memory(Vec<u8>),
+    File(TempFile, File),
+}
+impl Sink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Memory(v) => {
+                v.extend_from_slice(bytes);
+                Ok(())
+            }
+            Sink::File(_, f) => f.write_all(bytes),
+        }
+    }
+    fn spill(self, dir: &Path) -> io::Result<Sink> {
+        match self {
+            Sink::Memory(v) => {
+                let (temp, mut file) = TempFile::create(dir)?;
+                file.write_all(&v)?;
+                Ok(Sink::File(temp, file))
+            }
+            file => Ok(file),
+        }
+    }
+    fn finish(self, len: usize) -> Result<PartData, FormError> {
+        match self {
+            Sink::Memory(v) => Ok(PartData::Memory(v)),
+            Sink::File(mut temp, mut file) => {
+                file.flush()?;
+                temp.len = len;
+                Ok(PartData::File(temp))
+            }
+        }
+    }
+}
+
+fn disposition_param(disposition: &str, key: &str) -> Option<String> {
+    disposition
+        .split(';')
+        .skip(1)
+        .filter_map(|p| p.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case(key))
+        .map(|(_, v)| v.trim().trim_matches('"').to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return None;
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// A reader that hands out a few bytes per call, to exercise boundaries split across reads.
+    struct Trickle<'a>(&'a [u8], usize);
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.1.min(buf.len()).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn sample_multipart() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(b"preamble\r\n--XyZ\r\n");
+        body.extend_from_slice(b"Content-Disposition: form-data; name=\"title\"\r\n\r\n");
+        body.extend_from_slice(b"Order list\r\n--XyZ\r\n");
+        body.extend_from_slice(
+            b"Content-Disposition: form-data; name=\"upload\"; filename=\"orders.json\"\r\n",
+        );
+        body.extend_from_slice(b"Content-Type: application/json\r\n\r\n");
+        body.extend_from_slice(b"[{\"order_id\":1}]\r\n--XyZ--\r\n");
+        body
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a+b%20c%2Fd"), "a b c/d");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn test_parse_urlencoded() {
+        let fields = parse_urlencoded("name=Jo+Doe&empty=&flag&city=S%C3%A3o");
+        assert_eq!(
+            fields,
+            vec![
+                ("name".to_string(), "Jo Doe".to_string()),
+                ("empty".to_string(), "".to_string()),
+                ("flag".to_string(), "".to_string()),
+                ("city".to_string(), "São".to_string()),
+            ]
+        );
+        assert_eq!(encode_urlencoded(&fields), "name=Jo+Doe&empty=&flag=&city=S%C3%A3o");
+        assert_eq!(parse_urlencoded(&encode_urlencoded(&fields)), fields);
+    }
+
+    #[test]
+    fn test_urlencoded_decoder_streams_fields() {
+        let body = b"a=1&b=two+words&c=%26";
+        let fields: Vec<_> = UrlEncodedDecoder::new(Trickle(body, 3), Limits::default())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(fields[1], ("b".to_string(), "two words".to_string()));
+        assert_eq!(fields[2], ("c".to_string(), "&".to_string()));
+    }
+
+    #[test]
+    fn test_urlencoded_decoder_limits() {
+        let limits = Limits {
+            max_total_size: 8,
+            ..Limits::default()
+        };
+        let mut decoder = UrlEncodedDecoder::new(&b"a=1&b=123456789"[..], limits);
+        assert!(matches!(decoder.next(), Some(Err(FormError::BodyTooLarge))));
+    }
+
+    #[test]
+    fn test_multipart_boundary() {
+        assert_eq!(
+            multipart_boundary("multipart/form-data; boundary=\"XyZ\""),
+            Some("XyZ".to_string())
+        );
+        assert_eq!(multipart_boundary("text/plain; boundary=XyZ"), None);
+    }
+
+    #[test]
+    fn test_multipart_fields_and_files() {
+        let body = sample_multipart();
+        let parts: Vec<Part> = MultipartDecoder::new(Trickle(&body, 5), "XyZ", Limits::default())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].text().as_deref(), Some("Order list"));
+        assert!(!parts[0].is_file());
+        assert_eq!(parts[1].filename.as_deref(), Some("orders.json"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("application/json"));
+        assert_eq!(parts[1].header("content-type"), Some("application/json"));
+        assert_eq!(parts[1].text().as_deref(), Some("[{\"order_id\":1}]"));
+    }
+
+    #[test]
+    fn test_multipart_spills_large_files() {
+        let body = sample_multipart();
+        let limits = Limits {
+            spill_threshold: 4,
+            ..Limits::default()
+        };
+        let mut parts = MultipartDecoder::new(&body[..], "XyZ", limits);
+        let field = parts.next().unwrap().unwrap();
+        assert!(matches!(field.data, PartData::Memory(_)));
+        let file = parts.next().unwrap().unwrap();
+        let path = match &file.data {
+            PartData::File(temp) => {
+                let mut contents = String::new();
+                temp.open().unwrap().read_to_string(&mut contents).unwrap();
+                assert_eq!(contents, "[{\"order_id\":1}]");
+                temp.path().to_path_buf()
+            }
+            PartData::Memory(_) => panic!("file part was not spilled"),
+        };
+        assert_eq!(file.len(), 16);
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_multipart_part_limit() {
+        let body = sample_multipart();
+        let limits = Limits {
+            max_part_size: 12,
+            ..Limits::default()
+        };
+        let results: Vec<_> = MultipartDecoder::new(&body[..], "XyZ", limits).collect();
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(FormError::PartTooLarge)));
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn test_multipart_truncated_body() {
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue";
+        let mut parts = MultipartDecoder::new(&body[..], "XyZ", Limits::default());
+        assert!(matches!(parts.next(), Some(Err(FormError::Malformed(_)))));
+        assert!(parts.next().is_none());
+    }
+
+    /// Text that is mostly the characters urlencoding treats specially.
+    fn text() -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop_oneof![any::<char>(), prop::sample::select(&['%', '+', '&', '=', ' '][..]), prop::char::range('a', 'z')],
+            0..8,
+        )
+        .prop_map(|chars| chars.into_iter().collect())
+    }
+
+    proptest! {
+        #[test]
+        fn test_urlencoded_roundtrip_invariants(pairs in prop::collection::vec((text(), text()), 0..4), raw in text()) {
+            prop_assert_eq!(parse_urlencoded(&encode_urlencoded(&pairs)), pairs);
+            // Decoding anything and encoding it again gives the same fields back.
+            let fields = parse_urlencoded(&raw);
+            prop_assert_eq!(parse_urlencoded(&encode_urlencoded(&fields)), fields);
+            prop_assert_eq!(percent_decode(&percent_encode(&raw)), raw);
+        }
+    }
+}
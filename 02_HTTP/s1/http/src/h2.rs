@@ -0,0 +1,1248 @@
+//! HTTP/2 server connections (RFC 9113): the frame codec, SETTINGS, flow control
+//! and streams multiplexed onto a handler that answers each request as HTTP/1.1.
+//!
+//! There is no TLS, so no ALPN: clients reach HTTP/2 only over cleartext (h2c), either
+//! by prior knowledge (sending `PREFACE` first) or with an `Upgrade: h2c` request.
+
+use crate::hpack::{self, Decoder, HpackError};
+use crate::httprequest::{Extensions, HttpRequest, Method, Resource, Version};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Cursor, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+/// What a client sends first on an HTTP/2 connection (RFC 9113, 3.4).
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DEFAULT_WINDOW: u32 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+const MAX_FRAME_SIZE_LIMIT: u32 = (1 << 24) - 1;
+const FRAME_HEADER_LEN: usize = 9;
+const MAX_RESPONSE_HEAD: usize = 64 * 1024;
+
+pub const END_STREAM: u8 = 0x1;
+pub const ACK: u8 = 0x1;
+pub const END_HEADERS: u8 = 0x4;
+pub const PADDED: u8 = 0x8;
+pub const PRIORITY: u8 = 0x20;
+
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Whether `req` asks to switch to HTTP/2 over cleartext (`Upgrade: h2c`).
+pub fn is_h2c_upgrade(req: &HttpRequest) -> bool {
+    let upgrade = req.header("Upgrade").unwrap_or("");
+    upgrade.split(',').any(|t| t.trim().eq_ignore_ascii_case("h2c")) && req.header("HTTP2-Settings").is_some()
+}
+
+/// Accepts an `Upgrade: h2c` request; the connection speaks HTTP/2 afterwards.
+pub fn write_upgrade_response(stream: &mut impl Write) -> io::Result<()> {
+    stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n")?;
+    stream.flush()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameType {
+    Data,
+    Headers,
+    Priority,
+    RstStream,
+    Settings,
+    PushPromise,
+    Ping,
+    GoAway,
+    WindowUpdate,
+    Continuation,
+    /// Frames of unknown types are ignored (RFC 9113, 4.1).
+    Unknown(u8),
+}
+impl FrameType {
+    fn from_u8(b: u8) -> FrameType {
+        match b {
+            0x0 => FrameType::Data,
+            0x1 => FrameType::Headers,
+            0x2 => FrameType::Priority,
+            0x3 => FrameType::RstStream,
+            0x4 => FrameType::Settings,
+            0x5 => FrameType::PushPromise,
+            0x6 => FrameType::Ping,
+            0x7 => FrameType::GoAway,
+            0x8 => FrameType::WindowUpdate,
+            0x9 => FrameType::Continuation,
+            b => FrameType::Unknown(b),
+        }
+    }
+    fn as_u8(self) -> u8 {
+        match self {
+            FrameType::Data => 0x0,
+            FrameType::Headers => 0x1,
+            FrameType::Priority => 0x2,
+            FrameType::RstStream => 0x3,
+            FrameType::Settings => 0x4,
+            FrameType::PushPromise => 0x5,
+            FrameType::Ping => 0x6,
+            FrameType::GoAway => 0x7,
+            FrameType::WindowUpdate => 0x8,
+            FrameType::Continuation => 0x9,
+            FrameType::Unknown(b) => b,
+        }
+    }
+}
+
+/// An error code of RST_STREAM and GOAWAY frames (RFC 9113, 7).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorCode(pub u32);
+impl ErrorCode {
+    pub const NO_ERROR: ErrorCode = ErrorCode(0x0);
+    pub const PROTOCOL_ERROR: ErrorCode = ErrorCode(0x1);
+    pub const INTERNAL_ERROR: ErrorCode = ErrorCode(0x2);
+    pub const FLOW_CONTROL_ERROR: ErrorCode = ErrorCode(0x3);
+    pub const STREAM_CLOSED: ErrorCode = ErrorCode(0x5);
+    pub const FRAME_SIZE_ERROR: ErrorCode = ErrorCode(0x6);
+    pub const REFUSED_STREAM: ErrorCode = ErrorCode(0x7);
+    pub const CANCEL: ErrorCode = ErrorCode(0x8);
+    pub const COMPRESSION_ERROR: ErrorCode = ErrorCode(0x9);
+}
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.0 {
+            0x0 => "NO_ERROR",
+            0x1 => "PROTOCOL_ERROR",
+            0x2 => "INTERNAL_ERROR",
+            0x3 => "FLOW_CONTROL_ERROR",
+            0x4 => "SETTINGS_TIMEOUT",
+            0x5 => "STREAM_CLOSED",
+            0x6 => "FRAME_SIZE_ERROR",
+            0x7 => "REFUSED_STREAM",
+            0x8 => "CANCEL",
+            0x9 => "COMPRESSION_ERROR",
+            0xa => "CONNECT_ERROR",
+            0xb => "ENHANCE_YOUR_CALM",
+            0xc => "INADEQUATE_SECURITY",
+            0xd => "HTTP_1_1_REQUIRED",
+            code => return write!(f, "error code {:#x}", code),
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug)]
+pub enum H2Error {
+    Io(io::Error),
+    /// A connection error: the connection is closed with a GOAWAY carrying the code.
+    Connection(ErrorCode, &'static str),
+}
+impl fmt::Display for H2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            H2Error::Io(e) => write!(f, "{}", e),
+            H2Error::Connection(code, reason) => write!(f, "{} ({})", reason, code),
+        }
+    }
+}
+impl std::error::Error for H2Error {}
+impl From<io::Error> for H2Error {
+    fn from(e: io::Error) -> Self {
+        H2Error::Io(e)
+    }
+}
+impl From<HpackError> for H2Error {
+    fn from(e: HpackError) -> Self {
+        H2Error::Connection(ErrorCode::COMPRESSION_ERROR, e.0)
+    }
+}
+
+fn protocol_error(reason: &'static str) -> H2Error {
+    H2Error::Connection(ErrorCode::PROTOCOL_ERROR, reason)
+}
+fn frame_size_error(reason: &'static str) -> H2Error {
+    H2Error::Connection(ErrorCode::FRAME_SIZE_ERROR, reason)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub kind: FrameType,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+fn write_frame(w: &mut (impl Write + ?Sized), kind: FrameType, flags: u8, stream_id: u32, payload: &[u8]) -> io::Result<()> {
+    let len = (payload.len() as u32).to_be_bytes();
+    let mut head = [0u8; FRAME_HEADER_LEN];
+    head[..3].copy_from_slice(&len[1..]);
+    head[3] = kind.as_u8();
+    head[4] = flags;
+    head[5..].copy_from_slice(&(stream_id & 0x7fff_ffff).to_be_bytes());
+    w.write_all(&head)?;
+    w.write_all(payload)
+}
+
+impl Frame {
+    pub fn new(kind: FrameType, flags: u8, stream_id: u32, payload: Vec<u8>) -> Frame {
+        Frame {
+            kind,
+            flags,
+            stream_id,
+            payload,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(FRAME_HEADER_LEN + self.payload.len());
+        let _ = write_frame(&mut out, self.kind, self.flags, self.stream_id, &self.payload);
+        out
+    }
+
+    /// Reads the next frame, or `None` if the peer closed the connection between frames.
+    pub fn read(reader: &mut impl Read, max_size: u32) -> Result<Option<Frame>, H2Error> {
+        let mut head = [0u8; FRAME_HEADER_LEN];
+        match reader.read(&mut head[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut head[1..])?,
+        }
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]);
+        if len > max_size {
+            return Err(frame_size_error("frame larger than SETTINGS_MAX_FRAME_SIZE"));
+        }
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload)?;
+        Ok(Some(Frame {
+            kind: FrameType::from_u8(head[3]),
+            flags: head[4],
+            stream_id: u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff,
+            payload,
+        }))
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// The payload of a DATA or HEADERS frame without its padding and, for HEADERS, priority fields.
+    pub fn content(&self) -> Result<&[u8], H2Error> {
+        let mut payload = &self.payload[..];
+        let mut pad = 0;
+        if self.has(PADDED) {
+            pad = *payload.first().ok_or(frame_size_error("missing pad length"))? as usize;
+            payload = &payload[1..];
+        }
+        if self.kind == FrameType::Headers && self.has(PRIORITY) {
+            payload = payload.get(5..).ok_or(frame_size_error("missing priority fields"))?;
+        }
+        if pad > payload.len() {
+            return Err(protocol_error("padding longer than the payload"));
+        }
+        Ok(&payload[..payload.len() - pad])
+    }
+}
+
+/// The settings of one end of a connection (RFC 9113, 6.5.2).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub header_table_size: u32,
+    pub enable_push: bool,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+    pub max_header_list_size: Option<u32>,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            header_table_size: hpack::DEFAULT_TABLE_SIZE as u32,
+            enable_push: true,
+            max_concurrent_streams: None,
+            initial_window_size: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_header_list_size: None,
+        }
+    }
+}
+impl Settings {
+    /// Applies the parameters of a SETTINGS payload; unknown ones are ignored.
+    pub fn apply(&mut self, payload: &[u8]) -> Result<(), H2Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(frame_size_error("SETTINGS length not a multiple of 6"));
+        }
+        for param in payload.chunks(6) {
+            let id = u16::from_be_bytes([param[0], param[1]]);
+            let value = u32::from_be_bytes([param[2], param[3], param[4], param[5]]);
+            match id {
+                SETTINGS_HEADER_TABLE_SIZE => self.header_table_size = value,
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(protocol_error("invalid SETTINGS_ENABLE_PUSH")),
+                SETTINGS_ENABLE_PUSH => self.enable_push = value == 1,
+                SETTINGS_MAX_CONCURRENT_STREAMS => self.max_concurrent_streams = Some(value),
+                SETTINGS_INITIAL_WINDOW_SIZE if value as i64 > MAX_WINDOW => {
+                    return Err(H2Error::Connection(ErrorCode::FLOW_CONTROL_ERROR, "initial window too large"))
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => self.initial_window_size = value,
+                SETTINGS_MAX_FRAME_SIZE if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&value) => {
+                    return Err(protocol_error("invalid SETTINGS_MAX_FRAME_SIZE"))
+                }
+                SETTINGS_MAX_FRAME_SIZE => self.max_frame_size = value,
+                SETTINGS_MAX_HEADER_LIST_SIZE => self.max_header_list_size = Some(value),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The parameters that differ from the defaults, as a SETTINGS payload.
+    pub fn encode(&self) -> Vec<u8> {
+        let defaults = Settings::default();
+        let mut params = Vec::new();
+        if self.header_table_size != defaults.header_table_size {
+            params.push((SETTINGS_HEADER_TABLE_SIZE, self.header_table_size));
+        }
+        if !self.enable_push {
+            params.push((SETTINGS_ENABLE_PUSH, 0));
+        }
+        if let Some(max) = self.max_concurrent_streams {
+            params.push((SETTINGS_MAX_CONCURRENT_STREAMS, max));
+        }
+        if self.initial_window_size != defaults.initial_window_size {
+            params.push((SETTINGS_INITIAL_WINDOW_SIZE, self.initial_window_size));
+        }
+        if self.max_frame_size != defaults.max_frame_size {
+            params.push((SETTINGS_MAX_FRAME_SIZE, self.max_frame_size));
+        }
+        if let Some(max) = self.max_header_list_size {
+            params.push((SETTINGS_MAX_HEADER_LIST_SIZE, max));
+        }
+        params
+            .into_iter()
+            .flat_map(|(id, value)| id.to_be_bytes().into_iter().chain(value.to_be_bytes()))
+            .collect()
+    }
+
+    /// Reads the base64url `HTTP2-Settings` header of an `Upgrade: h2c` request.
+    pub fn from_header(value: &str) -> Result<Settings, H2Error> {
+        let payload = URL_SAFE_NO_PAD
+            .decode(value.trim().trim_end_matches('='))
+            .map_err(|_| protocol_error("invalid HTTP2-Settings header"))?;
+        let mut settings = Settings::default();
+        settings.apply(&payload)?;
+        Ok(settings)
+    }
+}
+
+/// The send window of a stream whose response is not finished.
+struct SendWindow {
+    window: i64,
+    reset: bool,
+}
+
+struct State {
+    writer: Box<dyn Write + Send>,
+    /// The connection-level send window.
+    window: i64,
+    streams: HashMap<u32, SendWindow>,
+    peer: Settings,
+    /// No more frames will be read, so windows can't grow any more.
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn send(&self, kind: FrameType, flags: u8, stream_id: u32, payload: &[u8]) -> io::Result<()> {
+        let mut state = self.lock();
+        write_frame(&mut state.writer, kind, flags, stream_id, payload)?;
+        state.writer.flush()
+    }
+    fn reset(&self, stream_id: u32, code: ErrorCode) -> io::Result<()> {
+        self.lock().streams.remove(&stream_id);
+        self.send(FrameType::RstStream, 0, stream_id, &code.0.to_be_bytes())
+    }
+}
+
+/// Parses the response a handler writes as HTTP/1.1 into HTTP/2 frames.
+enum Response {
+    /// Collecting the status line and headers.
+    Head(Vec<u8>),
+    Body(Framing),
+    Done,
+}
+enum Framing {
+    Length(u64),
+    Chunked(ChunkState, Vec<u8>),
+    /// No length given: the body ends when the handler is done.
+    UntilEnd,
+}
+#[derive(Clone, Copy)]
+enum ChunkState {
+    Size,
+    Data(u64),
+    Crlf,
+}
+
+/// Header fields that only mean something to a single HTTP/1.1 connection (RFC 9113, 8.2.2).
+fn is_connection_specific(name: &str) -> bool {
+    matches!(name, "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade")
+}
+
+/// One request of a connection, handed to the handler: reads give the request
+/// body, and the HTTP/1.1 response written to it goes out as HEADERS and DATA frames.
+pub struct Stream {
+    id: u32,
+    head_request: bool,
+    body: Cursor<Vec<u8>>,
+    shared: Arc<Shared>,
+    response: Response,
+}
+impl Stream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn send_head(&mut self, head: &[u8]) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .filter(|s| s.len() == 3 && s.bytes().all(|b| b.is_ascii_digit()))
+            .ok_or_else(|| invalid("malformed status line"))?;
+        let mut fields = vec![(":status".to_string(), status.to_string())];
+        let (mut chunked, mut length) = (false, None);
+        for line in lines.filter(|l| !l.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim().to_string());
+            match name.as_str() {
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                "content-length" => length = value.parse::<u64>().ok(),
+                _ => {}
+            }
+            if !is_connection_specific(&name) {
+                fields.push((name, value));
+            }
+        }
+        let no_body = self.head_request || matches!(status, "204" | "304") || status.starts_with('1') || length == Some(0);
+        let mut block = Vec::new();
+        hpack::encode(&fields, &mut block);
+        self.send_headers(&block, no_body)?;
+        self.response = match (no_body, chunked, length) {
+            (true, _, _) => Response::Done,
+            (_, true, _) => Response::Body(Framing::Chunked(ChunkState::Size, Vec::new())),
+            (_, _, Some(n)) => Response::Body(Framing::Length(n)),
+            _ => Response::Body(Framing::UntilEnd),
+        };
+        Ok(())
+    }
+
+    fn send_headers(&self, block: &[u8], end_stream: bool) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        if state.streams.get(&self.id).is_none_or(|s| s.reset) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream reset"));
+        }
+        let max = state.peer.max_frame_size as usize;
+        let mut chunks = block.chunks(max).peekable();
+        let first = chunks.next().unwrap_or(&[]);
+        let end = if end_stream { END_STREAM } else { 0 };
+        let flags = end | if chunks.peek().is_none() { END_HEADERS } else { 0 };
+        write_frame(&mut state.writer, FrameType::Headers, flags, self.id, first)?;
+        // CONTINUATION frames must follow HEADERS directly, so the lock is held throughout.
+        while let Some(chunk) = chunks.next() {
+            let flags = if chunks.peek().is_none() { END_HEADERS } else { 0 };
+            write_frame(&mut state.writer, FrameType::Continuation, flags, self.id, chunk)?;
+        }
+        if end_stream {
+            state.streams.remove(&self.id);
+        }
+        state.writer.flush()
+    }
+
+    /// Sends `data` in DATA frames as the send windows allow, waiting for WINDOW_UPDATEs.
+    fn send_data(&self, mut data: &[u8], end_stream: bool) -> io::Result<()> {
+        let flags = if end_stream { END_STREAM } else { 0 };
+        if data.is_empty() && end_stream {
+            let mut state = self.shared.lock();
+            state.streams.remove(&self.id);
+            write_frame(&mut state.writer, FrameType::Data, flags, self.id, &[])?;
+            return state.writer.flush();
+        }
+        while !data.is_empty() {
+            let mut state = self.shared.lock();
+            let available = loop {
+                let stream = match state.streams.get(&self.id) {
+                    Some(stream) if !stream.reset => stream,
+                    _ => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream reset")),
+                };
+                let available = state.window.min(stream.window);
+                if available > 0 {
+                    break available as usize;
+                }
+                if state.closed {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"));
+                }
+                state = self.shared.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+            };
+            let n = data.len().min(available).min(state.peer.max_frame_size as usize);
+            let last = n == data.len();
+            let flags = if last { flags } else { 0 };
+            write_frame(&mut state.writer, FrameType::Data, flags, self.id, &data[..n])?;
+            state.writer.flush()?;
+            state.window -= n as i64;
+            if let Some(stream) = state.streams.get_mut(&self.id) {
+                stream.window -= n as i64;
+            }
+            if last && end_stream {
+                state.streams.remove(&self.id);
+            }
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    fn send_body(&mut self, data: &[u8]) -> io::Result<()> {
+        let framing = match &mut self.response {
+            Response::Body(framing) => framing,
+            _ => return Ok(()),
+        };
+        match framing {
+            Framing::UntilEnd => self.send_data(data, false),
+            Framing::Length(remaining) => {
+                let n = data.len().min(*remaining as usize);
+                let left = *remaining - n as u64;
+                // Left as it was on failure, so that dropping the stream resets it.
+                self.send_data(&data[..n], left == 0)?;
+                self.response = match left {
+                    0 => Response::Done,
+                    left => Response::Body(Framing::Length(left)),
+                };
+                Ok(())
+            }
+            Framing::Chunked(..) => self.send_chunked(data),
+        }
+    }
+
+    /// Decodes a chunked body as it arrives, sending each chunk's data.
+    fn send_chunked(&mut self, data: &[u8]) -> io::Result<()> {
+        let (mut state, mut pending) = match &mut self.response {
+            Response::Body(Framing::Chunked(state, pending)) => (*state, std::mem::take(pending)),
+            _ => return Ok(()),
+        };
+        pending.extend_from_slice(data);
+        loop {
+            match state {
+                ChunkState::Size => {
+                    let end = match pending.windows(2).position(|w| w == b"\r\n") {
+                        Some(end) => end,
+                        None => break,
+                    };
+                    let line = String::from_utf8_lossy(&pending[..end]).to_string();
+                    pending.drain(..end + 2);
+                    let size = u64::from_str_radix(line.split(';').next().unwrap_or("").trim(), 16)
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad chunk size"))?;
+                    if size == 0 {
+                        // Trailers aren't forwarded.
+                        self.response = Response::Done;
+                        return self.send_data(&[], true);
+                    }
+                    state = ChunkState::Data(size);
+                }
+                ChunkState::Data(remaining) => {
+                    if pending.is_empty() {
+                        break;
+                    }
+                    let n = pending.len().min(remaining as usize);
+                    self.send_data(&pending[..n], false)?;
+                    pending.drain(..n);
+                    state = if remaining == n as u64 { ChunkState::Crlf } else { ChunkState::Data(remaining - n as u64) };
+                }
+                ChunkState::Crlf => {
+                    if pending.len() < 2 {
+                        break;
+                    }
+                    pending.drain(..2);
+                    state = ChunkState::Size;
+                }
+            }
+        }
+        self.response = Response::Body(Framing::Chunked(state, pending));
+        Ok(())
+    }
+}
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+impl BufRead for Stream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.body.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.body.consume(amt)
+    }
+}
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.response {
+            Response::Head(head) => {
+                head.extend_from_slice(buf);
+                if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+                    let rest = head.split_off(end + 4);
+                    let head = std::mem::take(head);
+                    self.send_head(&head)?;
+                    self.send_body(&rest)?;
+                } else if head.len() > MAX_RESPONSE_HEAD {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "response head too large"));
+                }
+            }
+            Response::Body(_) => self.send_body(buf)?,
+            Response::Done => {}
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let _ = match self.response {
+            Response::Done => Ok(()),
+            Response::Body(Framing::UntilEnd | Framing::Chunked(..)) => self.send_data(&[], true),
+            // Nothing or only part of the response was written.
+            Response::Head(_) | Response::Body(Framing::Length(_)) => {
+                self.shared.reset(self.id, ErrorCode::INTERNAL_ERROR)
+            }
+        };
+        self.shared.lock().streams.remove(&self.id);
+    }
+}
+
+/// Builds a request from a decoded header block, or says why it is malformed (RFC 9113, 8.1.1).
+fn build_request(fields: Vec<(String, String)>) -> Result<HttpRequest, &'static str> {
+    let (mut method, mut path, mut scheme, mut authority) = (None, None, None, None);
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in fields {
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if !headers.is_empty() {
+                return Err("pseudo-header after a regular header");
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                _ => return Err("unknown pseudo-header"),
+            };
+            if slot.replace(value).is_some() {
+                return Err("duplicate pseudo-header");
+            }
+            continue;
+        }
+        if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err("upper-case header name");
+        }
+        if is_connection_specific(&name) || (name == "te" && value != "trailers") {
+            return Err("connection-specific header");
+        }
+        let separator = if name == "cookie" { "; " } else { ", " };
+        headers
+            .entry(name)
+            .and_modify(|v| {
+                v.push_str(separator);
+                v.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    let method = method.ok_or("missing :method")?;
+    if method == "CONNECT" {
+        return Err("CONNECT is not supported");
+    }
+    let path = path.filter(|p| !p.is_empty()).ok_or("missing :path")?;
+    scheme.ok_or("missing :scheme")?;
+    if let Some(authority) = authority {
+        headers.entry("host".to_string()).or_insert(authority);
+    }
+    Ok(HttpRequest {
+        method: Method::from(method.as_str()),
+        version: Version::V2_0,
+        resource: Resource::Path(path),
+        headers,
+        msg_body: Vec::new(),
+        extensions: Extensions::default(),
+    })
+}
+
+/// A request whose body is still arriving.
+struct Pending {
+    req: HttpRequest,
+    body: Vec<u8>,
+}
+
+type Handler<'h> = &'h (dyn Fn(HttpRequest, Stream) + Sync);
+
+/// Serves one HTTP/2 connection. Every request runs on its own thread and the
+/// connection ends when the client closes it, sends GOAWAY or breaks the protocol.
+pub struct ServerConnection {
+    settings: Settings,
+    max_body: usize,
+}
+impl Default for ServerConnection {
+    fn default() -> Self {
+        ServerConnection {
+            settings: Settings {
+                max_concurrent_streams: Some(100),
+                max_header_list_size: Some(64 * 1024),
+                ..Settings::default()
+            },
+            max_body: 20 * 1024 * 1024,
+        }
+    }
+}
+impl ServerConnection {
+    pub fn max_concurrent_streams(mut self, max: u32) -> Self {
+        self.settings.max_concurrent_streams = Some(max);
+        self
+    }
+    /// Requests with larger bodies get `413 Payload Too Large`.
+    pub fn max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    /// Speaks HTTP/2 on a connection whose client preface is still unread in `reader`.
+    /// For `Upgrade: h2c`, `upgraded` is the request that asked for it, body included:
+    /// it becomes stream 1.
+    pub fn serve<R: BufRead, W: Write + Send + 'static>(
+        &self,
+        reader: &mut R,
+        writer: W,
+        upgraded: Option<HttpRequest>,
+        handler: Handler,
+    ) -> Result<(), H2Error> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                writer: Box::new(writer),
+                window: DEFAULT_WINDOW as i64,
+                streams: HashMap::new(),
+                peer: Settings::default(),
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let mut conn = ConnectionReader {
+            config: self,
+            shared: Arc::clone(&shared),
+            decoder: Decoder::default()
+                .max_header_list_size(self.settings.max_header_list_size.unwrap_or(u32::MAX) as usize),
+            pending: HashMap::new(),
+            last_stream_id: 0,
+        };
+        thread::scope(|scope| {
+            let result = conn.run(reader, upgraded, handler, scope);
+            if let Err(H2Error::Connection(code, reason)) = &result {
+                let mut payload = conn.last_stream_id.to_be_bytes().to_vec();
+                payload.extend_from_slice(&code.0.to_be_bytes());
+                payload.extend_from_slice(reason.as_bytes());
+                let _ = shared.send(FrameType::GoAway, 0, 0, &payload);
+            }
+            shared.lock().closed = true;
+            shared.changed.notify_all();
+            result
+        })
+    }
+}
+
+struct ConnectionReader<'c> {
+    config: &'c ServerConnection,
+    shared: Arc<Shared>,
+    decoder: Decoder,
+    pending: HashMap<u32, Pending>,
+    last_stream_id: u32,
+}
+impl<'c> ConnectionReader<'c> {
+    fn run<'scope, 'env>(
+        &mut self,
+        reader: &mut impl BufRead,
+        upgraded: Option<HttpRequest>,
+        handler: Handler<'env>,
+        scope: &'scope thread::Scope<'scope, 'env>,
+    ) -> Result<(), H2Error> {
+        self.shared.send(FrameType::Settings, 0, 0, &self.config.settings.encode())?;
+        if let Some(mut req) = upgraded {
+            let settings = Settings::from_header(req.header("HTTP2-Settings").unwrap_or(""))?;
+            self.shared.lock().peer = settings;
+            for name in ["Connection", "Upgrade", "HTTP2-Settings", "Transfer-Encoding"] {
+                req.headers.retain(|k, _| !k.eq_ignore_ascii_case(name));
+            }
+            req.version = Version::V2_0;
+            let body = std::mem::take(&mut req.msg_body);
+            self.open(1);
+            self.last_stream_id = 1;
+            self.dispatch(1, req, body, handler, scope);
+        }
+        let mut preface = [0u8; 24];
+        reader.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            return Err(protocol_error("invalid connection preface"));
+        }
+        match Frame::read(reader, DEFAULT_MAX_FRAME_SIZE)? {
+            Some(frame) if frame.kind == FrameType::Settings && !frame.has(ACK) => self.on_settings(&frame)?,
+            Some(_) => return Err(protocol_error("the preface must be followed by SETTINGS")),
+            None => return Ok(()),
+        }
+        while let Some(frame) = Frame::read(reader, DEFAULT_MAX_FRAME_SIZE)? {
+            match frame.kind {
+                FrameType::Settings if frame.has(ACK) => {
+                    if !frame.payload.is_empty() {
+                        return Err(frame_size_error("SETTINGS ACK with a payload"));
+                    }
+                }
+                FrameType::Settings => self.on_settings(&frame)?,
+                FrameType::Ping => {
+                    if frame.stream_id != 0 {
+                        return Err(protocol_error("PING on a stream"));
+                    }
+                    if frame.payload.len() != 8 {
+                        return Err(frame_size_error("PING payload is not 8 bytes"));
+                    }
+                    if !frame.has(ACK) {
+                        self.shared.send(FrameType::Ping, ACK, 0, &frame.payload)?;
+                    }
+                }
+                FrameType::WindowUpdate => self.on_window_update(&frame)?,
+                FrameType::Headers => {
+                    let frame = self.read_header_block(reader, frame)?;
+                    self.on_headers(frame, handler, scope)?;
+                }
+                FrameType::Data => self.on_data(&frame, handler, scope)?,
+                FrameType::RstStream => {
+                    if frame.stream_id == 0 {
+                        return Err(protocol_error("RST_STREAM on stream 0"));
+                    }
+                    if frame.payload.len() != 4 {
+                        return Err(frame_size_error("RST_STREAM payload is not 4 bytes"));
+                    }
+                    self.pending.remove(&frame.stream_id);
+                    if let Some(stream) = self.shared.lock().streams.get_mut(&frame.stream_id) {
+                        stream.reset = true;
+                    }
+                    self.shared.changed.notify_all();
+                }
+                FrameType::Priority => {
+                    if frame.payload.len() != 5 {
+                        return Err(frame_size_error("PRIORITY payload is not 5 bytes"));
+                    }
+                }
+                FrameType::PushPromise => return Err(protocol_error("clients can't push")),
+                FrameType::Continuation => return Err(protocol_error("CONTINUATION without HEADERS")),
+                FrameType::GoAway => return Ok(()),
+                FrameType::Unknown(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn on_settings(&mut self, frame: &Frame) -> Result<(), H2Error> {
+        if frame.stream_id != 0 {
+            return Err(protocol_error("SETTINGS on a stream"));
+        }
+        let mut state = self.shared.lock();
+        let old_window = state.peer.initial_window_size as i64;
+        state.peer.apply(&frame.payload)?;
+        // A new initial window size changes the windows of all open streams (RFC 9113, 6.9.2).
+        let delta = state.peer.initial_window_size as i64 - old_window;
+        for stream in state.streams.values_mut() {
+            stream.window += delta;
+            if stream.window > MAX_WINDOW {
+                return Err(H2Error::Connection(ErrorCode::FLOW_CONTROL_ERROR, "window overflow"));
+            }
+        }
+        write_frame(&mut state.writer, FrameType::Settings, ACK, 0, &[])?;
+        state.writer.flush()?;
+        drop(state);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: &Frame) -> Result<(), H2Error> {
+        if frame.payload.len() != 4 {
+            return Err(frame_size_error("WINDOW_UPDATE payload is not 4 bytes"));
+        }
+        let p = &frame.payload;
+        let increment = (u32::from_be_bytes([p[0], p[1], p[2], p[3]]) & 0x7fff_ffff) as i64;
+        let mut state = self.shared.lock();
+        if frame.stream_id == 0 {
+            if increment == 0 {
+                return Err(protocol_error("WINDOW_UPDATE of 0"));
+            }
+            state.window += increment;
+            if state.window > MAX_WINDOW {
+                return Err(H2Error::Connection(ErrorCode::FLOW_CONTROL_ERROR, "window overflow"));
+            }
+        } else if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+            stream.window += increment;
+            if increment == 0 || stream.window > MAX_WINDOW {
+                let code = if increment == 0 { ErrorCode::PROTOCOL_ERROR } else { ErrorCode::FLOW_CONTROL_ERROR };
+                drop(state);
+                self.shared.reset(frame.stream_id, code)?;
+            }
+        }
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Joins a HEADERS frame with the CONTINUATION frames that finish its header block.
+    fn read_header_block(&mut self, reader: &mut impl BufRead, frame: Frame) -> Result<Frame, H2Error> {
+        let mut block = frame.content()?.to_vec();
+        let mut end_headers = frame.has(END_HEADERS);
+        while !end_headers {
+            let next = Frame::read(reader, DEFAULT_MAX_FRAME_SIZE)?.ok_or(protocol_error("unfinished header block"))?;
+            if next.kind != FrameType::Continuation || next.stream_id != frame.stream_id {
+                return Err(protocol_error("expected CONTINUATION"));
+            }
+            block.extend_from_slice(&next.payload);
+            if block.len() > 4 * self.decoder_limit() {
+                return Err(protocol_error("header block too large"));
+            }
+            end_headers = next.has(END_HEADERS);
+        }
+        Ok(Frame::new(FrameType::Headers, frame.flags & END_STREAM, frame.stream_id, block))
+    }
+
+    fn decoder_limit(&self) -> usize {
+        self.config.settings.max_header_list_size.unwrap_or(64 * 1024) as usize
+    }
+
+    fn on_headers<'scope, 'env>(
+        &mut self,
+        frame: Frame,
+        handler: Handler<'env>,
+        scope: &'scope thread::Scope<'scope, 'env>,
+    ) -> Result<(), H2Error> {
+        let id = frame.stream_id;
+        if id == 0 {
+            return Err(protocol_error("HEADERS on stream 0"));
+        }
+        // Decoded even if the stream is refused, to keep the dynamic table in step.
+        let fields = self.decoder.decode(&frame.payload)?;
+        if self.pending.contains_key(&id) {
+            // Trailers, which end the request; they aren't passed on.
+            if !frame.has(END_STREAM) {
+                return Err(protocol_error("trailers without END_STREAM"));
+            }
+            let Pending { req, body } = self.pending.remove(&id).unwrap_or_else(|| unreachable!());
+            self.dispatch(id, req, body, handler, scope);
+            return Ok(());
+        }
+        if id.is_multiple_of(2) || id <= self.last_stream_id {
+            return Err(protocol_error("HEADERS on a closed or server stream"));
+        }
+        self.last_stream_id = id;
+        let active = self.shared.lock().streams.len();
+        let max = self.config.settings.max_concurrent_streams.unwrap_or(u32::MAX) as usize;
+        if active >= max {
+            self.shared.reset(id, ErrorCode::REFUSED_STREAM)?;
+            return Ok(());
+        }
+        let req = match build_request(fields) {
+            Ok(req) => req,
+            Err(_) => {
+                self.shared.reset(id, ErrorCode::PROTOCOL_ERROR)?;
+                return Ok(());
+            }
+        };
+        self.open(id);
+        if frame.has(END_STREAM) {
+            self.dispatch(id, req, Vec::new(), handler, scope);
+        } else {
+            self.pending.insert(id, Pending { req, body: Vec::new() });
+        }
+        Ok(())
+    }
+
+    fn on_data<'scope, 'env>(
+        &mut self,
+        frame: &Frame,
+        handler: Handler<'env>,
+        scope: &'scope thread::Scope<'scope, 'env>,
+    ) -> Result<(), H2Error> {
+        let id = frame.stream_id;
+        if id == 0 {
+            return Err(protocol_error("DATA on stream 0"));
+        }
+        let data = frame.content()?;
+        // Received data is handed on at once, so the windows are replenished right away.
+        let consumed = (frame.payload.len() as u32).to_be_bytes();
+        if !frame.payload.is_empty() {
+            self.shared.send(FrameType::WindowUpdate, 0, 0, &consumed)?;
+        }
+        let pending = match self.pending.get_mut(&id) {
+            Some(pending) => pending,
+            None if id > self.last_stream_id => return Err(protocol_error("DATA on an idle stream")),
+            None => {
+                self.shared.reset(id, ErrorCode::STREAM_CLOSED)?;
+                return Ok(());
+            }
+        };
+        if pending.body.len() + data.len() > self.config.max_body {
+            self.pending.remove(&id);
+            let mut stream = self.stream(id, false, Vec::new());
+            let _ = stream.write_all(b"HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n");
+            drop(stream);
+            // The response is complete, so the rest of the request isn't needed (RFC 9113, 8.1).
+            return Ok(self.shared.send(FrameType::RstStream, 0, id, &ErrorCode::NO_ERROR.0.to_be_bytes())?);
+        }
+        pending.body.extend_from_slice(data);
+        if frame.has(END_STREAM) {
+            let Pending { req, body } = self.pending.remove(&id).unwrap_or_else(|| unreachable!());
+            self.dispatch(id, req, body, handler, scope);
+        } else if !frame.payload.is_empty() {
+            self.shared.send(FrameType::WindowUpdate, 0, id, &consumed)?;
+        }
+        Ok(())
+    }
+
+    fn open(&self, id: u32) {
+        let mut state = self.shared.lock();
+        let window = state.peer.initial_window_size as i64;
+        state.streams.insert(id, SendWindow { window, reset: false });
+    }
+
+    fn stream(&self, id: u32, head_request: bool, body: Vec<u8>) -> Stream {
+        Stream {
+            id,
+            head_request,
+            body: Cursor::new(body),
+            shared: Arc::clone(&self.shared),
+            response: Response::Head(Vec::new()),
+        }
+    }
+
+    fn dispatch<'scope, 'env>(
+        &self,
+        id: u32,
+        mut req: HttpRequest,
+        body: Vec<u8>,
+        handler: Handler<'env>,
+        scope: &'scope thread::Scope<'scope, 'env>,
+    ) {
+        // Handlers read the body by its length, as they would over HTTP/1.1.
+        req.headers.retain(|k, _| !k.eq_ignore_ascii_case("content-length"));
+        if !body.is_empty() {
+            req.headers.insert("content-length".to_string(), body.len().to_string());
+        }
+        let stream = self.stream(id, req.method == Method::HEAD, body);
+        scope.spawn(move || handler(req, stream));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer whose output the test can read after the connection is done.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request_headers(method: &str, path: &str) -> Vec<u8> {
+        let fields: Vec<(String, String)> = [(":method", method), (":scheme", "http"), (":path", path), (":authority", "example.com")]
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect();
+        let mut block = Vec::new();
+        hpack::encode(&fields, &mut block);
+        block
+    }
+
+    /// A stream's decoded response headers, its body and whether it ended.
+    type Response = (Vec<(String, String)>, Vec<u8>, bool);
+
+    /// The frames the server sent, and the response of each stream.
+    fn run(client: Vec<u8>, handler: Handler) -> (Vec<Frame>, HashMap<u32, Response>) {
+        let output = Output::default();
+        let mut reader = Cursor::new(client);
+        ServerConnection::default().serve(&mut reader, output.clone(), None, handler).unwrap();
+        let bytes = output.0.lock().unwrap().clone();
+        let mut reader = &bytes[..];
+        let mut frames = Vec::new();
+        let mut streams: HashMap<u32, Response> = HashMap::new();
+        let mut decoder = Decoder::default();
+        while let Some(frame) = Frame::read(&mut reader, MAX_FRAME_SIZE_LIMIT).unwrap() {
+            let stream = streams.entry(frame.stream_id).or_default();
+            match frame.kind {
+                FrameType::Headers => stream.0 = decoder.decode(frame.content().unwrap()).unwrap(),
+                FrameType::Data => stream.1.extend_from_slice(frame.content().unwrap()),
+                _ => {}
+            }
+            stream.2 |= matches!(frame.kind, FrameType::Headers | FrameType::Data) && frame.has(END_STREAM);
+            frames.push(frame);
+        }
+        (frames, streams)
+    }
+
+    #[test]
+    fn test_frame_codec() {
+        let frame = Frame::new(FrameType::Headers, END_HEADERS | PADDED, 3, vec![2, b'h', b'i', 0, 0]);
+        let encoded = frame.encode();
+        assert_eq!(&encoded[..9], [0, 0, 5, 1, 0x0c, 0, 0, 0, 3]);
+        let decoded = Frame::read(&mut &encoded[..], DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(decoded, frame);
+        assert_eq!(decoded.content().unwrap(), b"hi");
+        assert!(Frame::read(&mut &[][..], DEFAULT_MAX_FRAME_SIZE).unwrap().is_none());
+        let big = Frame::new(FrameType::Data, 0, 1, vec![0; 20_000]).encode();
+        assert!(matches!(
+            Frame::read(&mut &big[..], DEFAULT_MAX_FRAME_SIZE),
+            Err(H2Error::Connection(ErrorCode::FRAME_SIZE_ERROR, _))
+        ));
+        let overpadded = Frame::new(FrameType::Data, PADDED, 1, vec![9, 1, 2]);
+        assert!(overpadded.content().is_err());
+    }
+
+    #[test]
+    fn test_settings() {
+        let settings = Settings {
+            enable_push: false,
+            max_concurrent_streams: Some(100),
+            initial_window_size: 10 << 20,
+            ..Settings::default()
+        };
+        let header = URL_SAFE_NO_PAD.encode(settings.encode());
+        assert_eq!(header, "AAIAAAAAAAMAAABkAAQAoAAA");
+        assert_eq!(Settings::from_header(&header).unwrap(), settings);
+        assert!(Settings::default().apply(&[0, 5, 0, 0, 0, 1]).is_err());
+        assert!(Settings::default().apply(&[0, 4, 0x80, 0, 0, 0]).is_err());
+        assert!(Settings::default().apply(&[0, 4, 0]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        let fields = |list: &[(&str, &str)]| list.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect();
+        let req = build_request(fields(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/orders?page=2"),
+            (":authority", "example.com"),
+            ("cookie", "a=1"),
+            ("cookie", "b=2"),
+        ]))
+        .unwrap();
+        assert_eq!(req.version, Version::V2_0);
+        assert_eq!(req.path(), "/orders");
+        assert_eq!(req.header("Host"), Some("example.com"));
+        assert_eq!(req.header("Cookie"), Some("a=1; b=2"));
+        for bad in [
+            vec![(":method", "GET"), (":scheme", "http")],
+            vec![(":method", "GET"), (":scheme", "http"), (":path", "/"), ("Accept", "*/*")],
+            vec![(":method", "GET"), (":scheme", "http"), (":path", "/"), ("connection", "close")],
+            vec![(":method", "GET"), ("accept", "*/*"), (":scheme", "http"), (":path", "/")],
+            vec![(":method", "GET"), (":scheme", "http"), (":path", "/"), (":status", "200")],
+        ] {
+            assert!(build_request(fields(&bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_multiplexed_requests() {
+        let mut client = PREFACE.to_vec();
+        client.extend(Frame::new(FrameType::Settings, 0, 0, Vec::new()).encode());
+        client.extend(Frame::new(FrameType::Headers, END_HEADERS | END_STREAM, 1, request_headers("GET", "/hello")).encode());
+        let post = request_headers("POST", "/echo");
+        let (first, rest) = post.split_at(4);
+        client.extend(Frame::new(FrameType::Headers, 0, 3, first.to_vec()).encode());
+        client.extend(Frame::new(FrameType::Continuation, END_HEADERS, 3, rest.to_vec()).encode());
+        client.extend(Frame::new(FrameType::Data, 0, 3, b"ping".to_vec()).encode());
+        client.extend(Frame::new(FrameType::Data, END_STREAM | PADDED, 3, vec![1, b'!', 0]).encode());
+        client.extend(Frame::new(FrameType::Ping, 0, 0, b"12345678".to_vec()).encode());
+        let (frames, streams) = run(client, &|req, mut stream| {
+            assert_eq!(req.header("Host"), Some("example.com"));
+            if req.method == Method::GET {
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n")
+                    .unwrap();
+                stream.write_all(b"6\r\n world\r\n0\r\n\r\n").unwrap();
+            } else {
+                let mut body = String::new();
+                stream.read_to_string(&mut body).unwrap();
+                assert_eq!(req.header("Content-Length"), Some("5"));
+                let resp = format!("HTTP/1.1 201 Created\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                stream.write_all(resp.as_bytes()).unwrap();
+            }
+        });
+        assert_eq!(frames[0].kind, FrameType::Settings);
+        assert!(frames.iter().any(|f| f.kind == FrameType::Settings && f.has(ACK)));
+        assert!(frames.iter().any(|f| f.kind == FrameType::Ping && f.has(ACK) && f.payload == b"12345678"));
+        let field = |fields: &Vec<(String, String)>, name: &str| {
+            fields.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone())
+        };
+        let (headers, body, ended) = &streams[&1];
+        assert_eq!(field(headers, ":status").as_deref(), Some("200"));
+        assert_eq!(field(headers, "content-type").as_deref(), Some("text/plain"));
+        assert_eq!(field(headers, "transfer-encoding"), None);
+        assert_eq!((body.as_slice(), *ended), (&b"hello world"[..], true));
+        let (headers, body, ended) = &streams[&3];
+        assert_eq!(field(headers, ":status").as_deref(), Some("201"));
+        assert_eq!(field(headers, "connection"), None);
+        assert_eq!((body.as_slice(), *ended), (&b"ping!"[..], true));
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let small_window = Frame::new(FrameType::Settings, 0, 0, vec![0, 4, 0, 0, 0, 3]).encode();
+        let respond: Handler = &|_req, mut stream| {
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789");
+        };
+        let mut client = PREFACE.to_vec();
+        client.extend(&small_window);
+        client.extend(Frame::new(FrameType::Headers, END_HEADERS | END_STREAM, 1, request_headers("GET", "/")).encode());
+        let (frames, streams) = run(client.clone(), respond);
+        // Three bytes fit the window; with no WINDOW_UPDATE coming, the stream is reset.
+        assert_eq!((streams[&1].1.as_slice(), streams[&1].2), (&b"012"[..], false));
+        assert!(frames.iter().any(|f| f.kind == FrameType::RstStream && f.stream_id == 1));
+
+        client.extend(Frame::new(FrameType::WindowUpdate, 0, 1, 7u32.to_be_bytes().to_vec()).encode());
+        let (_, streams) = run(client, respond);
+        assert_eq!((streams[&1].1.as_slice(), streams[&1].2), (&b"0123456789"[..], true));
+    }
+
+    #[test]
+    fn test_protocol_errors_end_with_goaway() {
+        let mut client = PREFACE.to_vec();
+        client.extend(Frame::new(FrameType::Settings, 0, 0, Vec::new()).encode());
+        client.extend(Frame::new(FrameType::Headers, END_HEADERS | END_STREAM, 2, request_headers("GET", "/")).encode());
+        let output = Output::default();
+        let result = ServerConnection::default().serve(&mut Cursor::new(client), output.clone(), None, &|_, _| {});
+        assert!(matches!(result, Err(H2Error::Connection(ErrorCode::PROTOCOL_ERROR, _))));
+        let bytes = output.0.lock().unwrap().clone();
+        let mut reader = &bytes[..];
+        let mut last = None;
+        while let Some(frame) = Frame::read(&mut reader, MAX_FRAME_SIZE_LIMIT).unwrap() {
+            last = Some(frame);
+        }
+        let goaway = last.unwrap();
+        assert_eq!(goaway.kind, FrameType::GoAway);
+        assert_eq!(&goaway.payload[4..8], &ErrorCode::PROTOCOL_ERROR.0.to_be_bytes());
+
+        let bad_preface = ServerConnection::default().serve(&mut Cursor::new(b"GET / HTTP/1.1\r\n\r\nxxxxxxx".to_vec()), Output::default(), None, &|_, _| {});
+        assert!(bad_preface.is_err());
+    }
+}
@@ -0,0 +1,524 @@
+//! HPACK header compression for HTTP/2 (RFC 7541).
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// The dynamic table size both ends start with (`SETTINGS_HEADER_TABLE_SIZE`).
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+const DEFAULT_MAX_HEADER_LIST_SIZE: usize = 64 * 1024;
+
+/// RFC 7541, Appendix A.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Bit lengths of the Huffman codes of the 256 octets and EOS (RFC 7541, Appendix B).
+/// The code is canonical, so the codes themselves follow from the lengths.
+const CODE_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28,
+    28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15,
+    6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20,
+    20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23,
+    24, 22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22,
+    22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19, 21, 26, 27, 27, 26, 27,
+    24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23, 26, 27, 26,
+    26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, 30,
+];
+const EOS: usize = 256;
+
+/// Why a header block could not be decoded; a connection error of type `COMPRESSION_ERROR`.
+#[derive(Debug, PartialEq)]
+pub struct HpackError(pub &'static str);
+impl fmt::Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HPACK: {}", self.0)
+    }
+}
+impl std::error::Error for HpackError {}
+
+fn huffman_codes() -> &'static [u32; 257] {
+    static CODES: OnceLock<[u32; 257]> = OnceLock::new();
+    CODES.get_or_init(|| {
+        let mut symbols: Vec<usize> = (0..=EOS).collect();
+        symbols.sort_by_key(|&s| (CODE_LENGTHS[s], s));
+        let mut codes = [0u32; 257];
+        let (mut code, mut len) = (0u32, 0u8);
+        for s in symbols {
+            code <<= CODE_LENGTHS[s] - len;
+            len = CODE_LENGTHS[s];
+            codes[s] = code;
+            code += 1;
+        }
+        codes
+    })
+}
+
+/// A leaf in the decoding tree; other child values are node indexes, and 0 (the root) means none.
+const LEAF: u16 = 0x8000;
+
+fn huffman_tree() -> &'static Vec<[u16; 2]> {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut nodes = vec![[0u16; 2]];
+        for (symbol, &code) in huffman_codes().iter().enumerate() {
+            let len = CODE_LENGTHS[symbol];
+            let mut node = 0;
+            for i in (0..len).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                if i == 0 {
+                    nodes[node][bit] = LEAF | symbol as u16;
+                } else {
+                    if nodes[node][bit] == 0 {
+                        nodes.push([0; 2]);
+                        nodes[node][bit] = (nodes.len() - 1) as u16;
+                    }
+                    node = nodes[node][bit] as usize;
+                }
+            }
+        }
+        nodes
+    })
+}
+
+pub fn huffman_encoded_len(data: &[u8]) -> usize {
+    let bits: usize = data.iter().map(|&b| CODE_LENGTHS[b as usize] as usize).sum();
+    bits.div_ceil(8)
+}
+
+pub fn huffman_encode(data: &[u8], out: &mut Vec<u8>) {
+    let codes = huffman_codes();
+    let (mut acc, mut bits) = (0u64, 0u32);
+    for &b in data {
+        acc = (acc << CODE_LENGTHS[b as usize]) | codes[b as usize] as u64;
+        bits += CODE_LENGTHS[b as usize] as u32;
+        while bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    if bits > 0 {
+        // Padded with the most significant bits of EOS, which are all ones.
+        let pad = 8 - bits;
+        out.push(((acc << pad) | ((1 << pad) - 1)) as u8);
+    }
+}
+
+pub fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let tree = huffman_tree();
+    let mut out = Vec::with_capacity(data.len() * 8 / 5);
+    let (mut node, mut depth, mut all_ones) = (0usize, 0u32, true);
+    for byte in data {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1;
+            let child = tree[node][bit as usize];
+            if child & LEAF != 0 {
+                let symbol = (child & !LEAF) as usize;
+                if symbol == EOS {
+                    return Err(HpackError("EOS in Huffman string"));
+                }
+                out.push(symbol as u8);
+                (node, depth, all_ones) = (0, 0, true);
+            } else {
+                node = child as usize;
+                depth += 1;
+                all_ones &= bit == 1;
+            }
+        }
+    }
+    // Whatever is left must be padding: fewer than 8 bits of EOS.
+    if depth > 7 || !all_ones {
+        return Err(HpackError("invalid Huffman padding"));
+    }
+    Ok(out)
+}
+
+fn decode_int(buf: &[u8], pos: &mut usize, prefix_bits: u8) -> Result<usize, HpackError> {
+    let truncated = HpackError("truncated integer");
+    let max_prefix = (1usize << prefix_bits) - 1;
+    let first = *buf.get(*pos).ok_or(truncated)? as usize & max_prefix;
+    *pos += 1;
+    if first < max_prefix {
+        return Ok(first);
+    }
+    let mut value = max_prefix;
+    let mut shift = 0;
+    loop {
+        let b = *buf.get(*pos).ok_or(HpackError("truncated integer"))?;
+        *pos += 1;
+        if shift > 28 {
+            return Err(HpackError("integer overflow"));
+        }
+        value += ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn encode_int(out: &mut Vec<u8>, flags: u8, prefix_bits: u8, value: usize) {
+    let max_prefix = (1usize << prefix_bits) - 1;
+    if value < max_prefix {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max_prefix as u8);
+    let mut rest = value - max_prefix;
+    while rest >= 0x80 {
+        out.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+fn decode_string(buf: &[u8], pos: &mut usize) -> Result<String, HpackError> {
+    let huffman = buf.get(*pos).ok_or(HpackError("truncated string"))? & 0x80 != 0;
+    let len = decode_int(buf, pos, 7)?;
+    let end = pos.checked_add(len).filter(|&end| end <= buf.len()).ok_or(HpackError("truncated string"))?;
+    let raw = &buf[*pos..end];
+    *pos = end;
+    let bytes = if huffman { huffman_decode(raw)? } else { raw.to_vec() };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn encode_string(out: &mut Vec<u8>, s: &str) {
+    let huffman_len = huffman_encoded_len(s.as_bytes());
+    if huffman_len < s.len() {
+        encode_int(out, 0x80, 7, huffman_len);
+        huffman_encode(s.as_bytes(), out);
+    } else {
+        encode_int(out, 0, 7, s.len());
+        out.extend_from_slice(s.as_bytes());
+    }
+}
+
+/// Headers are accounted with 32 bytes of overhead each (RFC 7541, 4.1).
+fn entry_size(name: &str, value: &str) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Decodes the header blocks of one connection, keeping its dynamic table.
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    /// The most `max_size` may be set to: the `SETTINGS_HEADER_TABLE_SIZE` we advertised.
+    size_limit: usize,
+    max_header_list_size: usize,
+}
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new(DEFAULT_TABLE_SIZE)
+    }
+}
+impl Decoder {
+    pub fn new(size_limit: usize) -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: size_limit,
+            size_limit,
+            max_header_list_size: DEFAULT_MAX_HEADER_LIST_SIZE,
+        }
+    }
+    /// Rejects header blocks that decode to more than `size` bytes, counted as in the dynamic table.
+    pub fn max_header_list_size(mut self, size: usize) -> Self {
+        self.max_header_list_size = size;
+        self
+    }
+    pub fn table_size(&self) -> usize {
+        self.size
+    }
+
+    fn entry(&self, index: usize) -> Result<(String, String), HpackError> {
+        match index {
+            0 => Err(HpackError("index 0")),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            _ => self.table.get(index - 62).cloned().ok_or(HpackError("index out of range")),
+        }
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let size = entry_size(&name, &value);
+        self.evict(self.max_size.saturating_sub(size));
+        // An entry larger than the whole table just empties it.
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front((name, value));
+        }
+    }
+
+    fn evict(&mut self, target: usize) {
+        while self.size > target {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= entry_size(&name, &value),
+                None => break,
+            }
+        }
+    }
+
+    /// Decodes one complete header block into name and value pairs, in order.
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, HpackError> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        let mut pos = 0;
+        while pos < block.len() {
+            let b = block[pos];
+            let (name, value) = if b & 0x80 != 0 {
+                self.entry(decode_int(block, &mut pos, 7)?)?
+            } else if b & 0xe0 == 0x20 {
+                if !headers.is_empty() {
+                    return Err(HpackError("table size update after a header field"));
+                }
+                let size = decode_int(block, &mut pos, 5)?;
+                if size > self.size_limit {
+                    return Err(HpackError("table size update above the limit"));
+                }
+                self.max_size = size;
+                self.evict(size);
+                continue;
+            } else {
+                // Literals: with incremental indexing (01), without (0000) or never indexed (0001).
+                let indexed = b & 0xc0 == 0x40;
+                let index = decode_int(block, &mut pos, if indexed { 6 } else { 4 })?;
+                let name = match index {
+                    0 => decode_string(block, &mut pos)?,
+                    _ => self.entry(index)?.0,
+                };
+                let value = decode_string(block, &mut pos)?;
+                if indexed {
+                    self.insert(name.clone(), value.clone());
+                }
+                (name, value)
+            };
+            list_size += entry_size(&name, &value);
+            if list_size > self.max_header_list_size {
+                return Err(HpackError("header list too large"));
+            }
+            headers.push((name, value));
+        }
+        Ok(headers)
+    }
+}
+
+/// Headers whose values must not be remembered by intermediaries (RFC 7541, 7.1.3).
+fn is_sensitive(name: &str) -> bool {
+    matches!(name, "authorization" | "cookie" | "set-cookie" | "proxy-authorization")
+}
+
+/// Encodes a header block from lower-case names. Fields are never added to the
+/// dynamic table, so the encoder keeps no state and any table size the peer sets will do.
+pub fn encode(headers: &[(String, String)], out: &mut Vec<u8>) {
+    for (name, value) in headers {
+        let exact = STATIC_TABLE.iter().position(|&(n, v)| n == name && v == value);
+        if let Some(i) = exact {
+            encode_int(out, 0x80, 7, i + 1);
+            continue;
+        }
+        let flags = if is_sensitive(name) { 0x10 } else { 0x00 };
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(i) => encode_int(out, flags, 4, i + 1),
+            None => {
+                out.push(flags);
+                encode_string(out, name);
+            }
+        }
+        encode_string(out, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn pairs(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_integers() {
+        // RFC 7541, C.1.
+        let mut out = Vec::new();
+        encode_int(&mut out, 0, 5, 10);
+        encode_int(&mut out, 0, 5, 1337);
+        encode_int(&mut out, 0, 8, 42);
+        assert_eq!(out, [0x0a, 0x1f, 0x9a, 0x0a, 0x2a]);
+        let mut pos = 0;
+        assert_eq!(decode_int(&out, &mut pos, 5), Ok(10));
+        assert_eq!(decode_int(&out, &mut pos, 5), Ok(1337));
+        assert_eq!(decode_int(&out, &mut pos, 8), Ok(42));
+        assert!(decode_int(&[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f], &mut 0, 5).is_err());
+    }
+
+    #[test]
+    fn test_huffman() {
+        // RFC 7541, C.4 and C.6.
+        for (text, encoded) in [
+            ("www.example.com", "f1e3 c2e5 f23a 6ba0 ab90 f4ff"),
+            ("no-cache", "a8eb 1064 9cbf"),
+            ("custom-value", "25a8 49e9 5bb8 e8b4 bf"),
+            ("Mon, 21 Oct 2013 20:13:21 GMT", "d07a be94 1054 d444 a820 0595 040b 8166 e082 a62d 1bff"),
+        ] {
+            let mut out = Vec::new();
+            huffman_encode(text.as_bytes(), &mut out);
+            assert_eq!(out, hex(encoded), "{}", text);
+            assert_eq!(huffman_decode(&out).unwrap(), text.as_bytes());
+        }
+        let every_octet: Vec<u8> = (0..=255).collect();
+        let mut out = Vec::new();
+        huffman_encode(&every_octet, &mut out);
+        assert_eq!(huffman_decode(&out).unwrap(), every_octet);
+        // EOS itself, and padding that isn't all ones or is a whole byte long.
+        assert!(huffman_decode(&[0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(huffman_decode(&[0xf1, 0xe0]).is_err());
+        assert!(huffman_decode(&[0xf1, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_decode_requests_with_dynamic_table() {
+        // RFC 7541, C.3 and C.4: the same three requests, without and with Huffman coding.
+        for blocks in [
+            [
+                "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                "8286 84be 5808 6e6f 2d63 6163 6865",
+                "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+            ],
+            [
+                "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+                "8286 84be 5886 a8eb 1064 9cbf",
+                "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+            ],
+        ] {
+            let mut decoder = Decoder::default();
+            let first = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")];
+            assert_eq!(decoder.decode(&hex(blocks[0])).unwrap(), pairs(&first));
+            assert_eq!(decoder.table_size(), 57);
+            let mut second = first.to_vec();
+            second.push(("cache-control", "no-cache"));
+            assert_eq!(decoder.decode(&hex(blocks[1])).unwrap(), pairs(&second));
+            assert_eq!(decoder.table_size(), 110);
+            let third = [
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ];
+            assert_eq!(decoder.decode(&hex(blocks[2])).unwrap(), pairs(&third));
+            assert_eq!(decoder.table_size(), 164);
+        }
+    }
+
+    #[test]
+    fn test_decode_evicts_entries() {
+        // RFC 7541, C.5.1 and C.5.2, with a 256-byte table.
+        let mut decoder = Decoder::new(256);
+        let first = decoder
+            .decode(&hex(
+                "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 \
+                 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 7861 6d70 \
+                 6c65 2e63 6f6d",
+            ))
+            .unwrap();
+        assert_eq!(first[0], (":status".to_string(), "302".to_string()));
+        assert_eq!(decoder.table_size(), 222);
+        let second = decoder.decode(&hex("4803 3330 37c1 c0bf")).unwrap();
+        assert_eq!(
+            second,
+            pairs(&[
+                (":status", "307"),
+                ("cache-control", "private"),
+                ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+                ("location", "https://www.example.com"),
+            ])
+        );
+        assert_eq!(decoder.table_size(), 222);
+        assert!(decoder.decode(&hex("3fe1 1f")).is_err());
+        assert!(decoder.decode(&hex("c2")).is_err());
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let headers = pairs(&[
+            (":status", "200"),
+            ("content-type", "application/json"),
+            ("x-total-count", "2"),
+            ("set-cookie", "session=abc"),
+            ("content-length", ""),
+        ]);
+        let mut block = Vec::new();
+        encode(&headers, &mut block);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::default().decode(&block).unwrap(), headers);
+    }
+}
@@ -1,9 +1,21 @@
+use crate::chunked::{self, ChunkedReader};
+use crate::form::parse_urlencoded;
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Read};
 
-#[derive(Debug, PartialEq)]
+pub(crate) const MAX_HEAD_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Method {
     GET,
     POST,
+    PUT,
+    PATCH,
+    DELETE,
+    HEAD,
+    OPTIONS,
     UNINITIALIZED,
 }
 impl From<&str> for Method {
@@ -11,12 +23,31 @@ impl From<&str> for Method {
         match s {
             "GET" => Method::GET,
             "POST" => Method::POST,
+            "PUT" => Method::PUT,
+            "PATCH" => Method::PATCH,
+            "DELETE" => Method::DELETE,
+            "HEAD" => Method::HEAD,
+            "OPTIONS" => Method::OPTIONS,
             _ => Method::UNINITIALIZED,
         }
     }
 }
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::GET => "GET",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::PATCH => "PATCH",
+            Method::DELETE => "DELETE",
+            Method::HEAD => "HEAD",
+            Method::OPTIONS => "OPTIONS",
+            Method::UNINITIALIZED => "",
+        }
+    }
+}
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Version {
     V1_1,
     V2_0,
@@ -31,17 +62,46 @@ impl From<&str> for Version {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Resource {
     Path(String),
 }
+/// Values attached to a request while it is handled, such as the authenticated
+/// user, at most one per type.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+impl Extensions {
+    /// Stores `value`, returning the previous value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+}
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpRequest {
     pub method: Method,
     pub version: Version,
     pub resource: Resource,
     pub headers: HashMap<String, String>,
-    pub msg_body: String,
+    pub msg_body: Vec<u8>,
+    pub extensions: Extensions,
 }
 
 impl From<String> for HttpRequest {
@@ -51,9 +111,12 @@ impl From<String> for HttpRequest {
         let mut parsed_resource = Resource::Path("".to_string());
         let mut parsed_headers = HashMap::new();
         let mut parsed_msg_body = "";
+        let mut seen_req_line = false;
 
         for line in req.lines() {
-            if line.contains("HTTP") {
+            // Only once: header names such as `HTTP2-Settings` contain "HTTP" too.
+            if !seen_req_line && line.contains("HTTP") {
+                seen_req_line = true;
                 let (method, resource, version) = process_req_line(line);
                 parsed_method = method;
                 parsed_resource = resource;
@@ -61,7 +124,7 @@ impl From<String> for HttpRequest {
             } else if line.contains(":") {
                 let (key, value) = process_header_line(line);
                 parsed_headers.insert(key, value);
-            } else if line.len() == 0 {
+            } else if line.is_empty() {
             } else {
                 parsed_msg_body = line;
             }
@@ -71,16 +134,129 @@ impl From<String> for HttpRequest {
             version: parsed_version,
             resource: parsed_resource,
             headers: parsed_headers,
-            msg_body: parsed_msg_body.to_string(),
+            msg_body: parsed_msg_body.as_bytes().to_vec(),
+            extensions: Extensions::default(),
+        }
+    }
+}
+
+impl HttpRequest {
+    /// Reads a request head from `reader`, followed by its body; see `read_body`.
+    pub fn read_from(reader: &mut impl BufRead, max_body: usize) -> io::Result<HttpRequest> {
+        let mut req = HttpRequest::read_head(reader)?;
+        req.read_body(reader, max_body)?;
+        Ok(req)
+    }
+
+    /// Reads the request line and headers, leaving the body unread in `reader`.
+    /// Heads over 64 KiB and request lines that aren't `METHOD TARGET HTTP/x.y` are rejected.
+    pub fn read_head(reader: &mut impl BufRead) -> io::Result<HttpRequest> {
+        let mut limited = reader.take(MAX_HEAD_SIZE);
+        let mut head = String::new();
+        loop {
+            let n = limited.read_line(&mut head)?;
+            if n == 0 || head.ends_with("\r\n\r\n") || head.ends_with("\n\n") {
+                break;
+            }
+        }
+        if head.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before a request"));
+        }
+        if !head.ends_with("\r\n\r\n") && !head.ends_with("\n\n") {
+            return Err(match limited.limit() {
+                0 => invalid_data("request head too large"),
+                _ => io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed within the request head"),
+            });
         }
+        if !is_request_line(head.lines().next().unwrap_or("")) {
+            return Err(invalid_data("malformed request line"));
+        }
+        Ok(head.into())
     }
+
+    /// Reads a body of `Content-Length` bytes or a `Transfer-Encoding: chunked` body into
+    /// `msg_body`. Bodies larger than `max_body` are rejected with `FileTooLarge`; malformed
+    /// framing with `InvalidData`, as are transfer codings that don't end in `chunked`, since
+    /// the body's length is then unknown.
+    pub fn read_body(&mut self, reader: &mut impl BufRead, max_body: usize) -> io::Result<()> {
+        let too_large = || io::Error::new(io::ErrorKind::FileTooLarge, "body too large");
+        let mut body = Vec::new();
+        if self.header("Transfer-Encoding").is_some() && !self.is_chunked() {
+            return Err(invalid_data("unsupported transfer coding"));
+        }
+        if self.is_chunked() {
+            ChunkedReader::new(&mut *reader)
+                .take(max_body as u64 + 1)
+                .read_to_end(&mut body)?;
+            if body.len() > max_body {
+                return Err(too_large());
+            }
+        } else {
+            let len = self.content_length()?.unwrap_or(0);
+            if len > max_body {
+                return Err(too_large());
+            }
+            body.reserve(len);
+            reader.take(len as u64).read_to_end(&mut body)?;
+        }
+        self.msg_body = body;
+        Ok(())
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.header("Transfer-Encoding").is_some_and(chunked::is_chunked)
+    }
+
+    pub fn content_length(&self) -> io::Result<Option<usize>> {
+        match self.header("Content-Length") {
+            Some(v) => v
+                .parse::<usize>()
+                .map(Some)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad Content-Length")),
+            None => Ok(None),
+        }
+    }
+
+    /// The request path without its query string.
+    pub fn path(&self) -> &str {
+        let Resource::Path(s) = &self.resource;
+        s.split('?').next().unwrap_or("")
+    }
+
+    /// The decoded query string parameters, in order.
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        let Resource::Path(s) = &self.resource;
+        match s.split_once('?') {
+            Some((_, query)) => parse_urlencoded(query),
+            None => Vec::new(),
+        }
+    }
+
+    /// Looks up a header by case-insensitive name, with surrounding whitespace trimmed.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
 }
 
+fn is_request_line(line: &str) -> bool {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    matches!(words[..], [method, _, version]
+        if method.bytes().all(|b| b.is_ascii_alphabetic()) && version.starts_with("HTTP/"))
+}
+
+/// Missing parts are left empty; `read_head` has rejected such lines already.
 fn process_req_line(s: &str) -> (Method, Resource, Version) {
     let mut words = s.split_whitespace();
-    let method = words.next().unwrap();
-    let resource = words.next().unwrap();
-    let version = words.next().unwrap();
+    let method = words.next().unwrap_or("");
+    let resource = words.next().unwrap_or("");
+    let version = words.next().unwrap_or("");
     (
         method.into(),
         Resource::Path(resource.to_string()),
@@ -89,7 +265,7 @@ fn process_req_line(s: &str) -> (Method, Resource, Version) {
 }
 
 fn process_header_line(s: &str) -> (String, String){
-    let mut header_items = s.split(":");
+    let mut header_items = s.splitn(2, ':');
     let mut key = String::from("");
     let mut value = String::from("");
     if let Some(k) = header_items.next(){
@@ -104,6 +280,8 @@ fn process_header_line(s: &str) -> (String, String){
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
     #[test]
     fn test_method_into() {
         let m: Method = "GET".into();
@@ -130,4 +308,174 @@ mod tests {
         assert_eq!(header_expected, req.headers);
 
     }
+    #[test]
+    fn test_header_containing_http(){
+        let req: HttpRequest = "GET / HTTP/1.1\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQCAAAAAAIAAAAA\r\n\r\n".to_string().into();
+        assert_eq!(Resource::Path("/".to_string()), req.resource);
+        assert_eq!(Some("AAMAAABkAAQCAAAAAAIAAAAA"), req.header("HTTP2-Settings"));
+    }
+    #[test]
+    fn test_read_from_with_body(){
+        let raw = "POST /api/form HTTP/1.1\r\nHost: localhost:3000\r\nContent-Length: 7\r\n\r\na=1&b=2trailing";
+        let mut reader = io::BufReader::new(raw.as_bytes());
+        let req = HttpRequest::read_from(&mut reader, 1024).unwrap();
+
+        assert_eq!(Method::POST, req.method);
+        assert_eq!(Some("localhost:3000"), req.header("host"));
+        assert_eq!(b"a=1&b=2".to_vec(), req.msg_body);
+    }
+    #[test]
+    fn test_read_from_chunked_body(){
+        let raw = "POST /api/form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\na=1\r\n4\r\n&b=2\r\n0\r\n\r\n";
+        let mut reader = io::BufReader::new(raw.as_bytes());
+        let req = HttpRequest::read_from(&mut reader, 1024).unwrap();
+        assert_eq!(b"a=1&b=2".to_vec(), req.msg_body);
+    }
+    #[test]
+    fn test_transfer_coding_ends_in_chunked(){
+        for te in ["chunked", "Chunked", "gzip, chunked", "gzip,CHUNKED "] {
+            let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\nContent-Length: 1\r\n\r\n3\r\nabc\r\n0\r\n\r\n", te);
+            let req = HttpRequest::read_from(&mut raw.as_bytes(), 1024).unwrap_or_else(|e| panic!("{}: {:?}", e, te));
+            assert_eq!(req.msg_body, b"abc", "{:?}", te);
+        }
+        for te in ["chunked, gzip", "gzip", "chunkedx"] {
+            let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\nContent-Length: 1\r\n\r\na", te);
+            let err = HttpRequest::read_from(&mut raw.as_bytes(), 1024).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", te);
+        }
+    }
+    #[test]
+    fn test_read_head_limit(){
+        let head = |size: usize| {
+            let line = "GET / HTTP/1.1\r\nX: ";
+            format!("{}{}\r\n\r\n", line, "a".repeat(size - line.len() - 4))
+        };
+        let exact = head(MAX_HEAD_SIZE as usize);
+        assert_eq!(exact.len() as u64, MAX_HEAD_SIZE);
+        assert!(HttpRequest::read_head(&mut exact.as_bytes()).is_ok());
+        let over = head(MAX_HEAD_SIZE as usize + 1);
+        assert_eq!(HttpRequest::read_head(&mut over.as_bytes()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let truncated = "GET / HTTP/1.1\r\nHost: a\r\n";
+        assert_eq!(HttpRequest::read_head(&mut truncated.as_bytes()).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+    #[test]
+    fn test_read_from_rejects_large_body(){
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\n";
+        let mut reader = io::BufReader::new(raw.as_bytes());
+        assert_eq!(HttpRequest::read_from(&mut reader, 10).unwrap_err().kind(), io::ErrorKind::FileTooLarge);
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nb\r\nhello world\r\n0\r\n\r\n";
+        assert_eq!(HttpRequest::read_from(&mut raw.as_bytes(), 10).unwrap_err().kind(), io::ErrorKind::FileTooLarge);
+    }
+    #[test]
+    fn test_extensions(){
+        let mut req: HttpRequest = "GET / HTTP/1.1\r\n\r\n".to_string().into();
+        assert_eq!(req.extensions.get::<String>(), None);
+        assert_eq!(req.extensions.insert("alice".to_string()), None);
+        req.extensions.insert(42u32);
+        assert_eq!(req.extensions.insert("bob".to_string()), Some("alice".to_string()));
+        assert_eq!(req.extensions.get::<String>().map(String::as_str), Some("bob"));
+        assert_eq!(req.extensions.remove::<u32>(), Some(42));
+        assert_eq!(req.extensions.get::<u32>(), None);
+    }
+    #[test]
+    fn test_path_and_query_params(){
+        let req: HttpRequest = "GET /api/shipping/orders?order_status=In%20Transit&from=2022-01-01 HTTP/1.1\r\n\r\n".to_string().into();
+        assert_eq!(req.path(), "/api/shipping/orders");
+        assert_eq!(
+            req.query_params(),
+            vec![
+                ("order_status".to_string(), "In Transit".to_string()),
+                ("from".to_string(), "2022-01-01".to_string()),
+            ]
+        );
+    }
+
+    const METHODS: [&str; 7] = ["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"];
+
+    /// Request text biased towards the tokens the parser looks for.
+    fn fragments() -> impl Strategy<Value = Vec<u8>> {
+        const PIECES: [&str; 12] = [
+            "GET ", "POST ", "/", " HTTP/1.1", "\r\n", "\n", ":", " ", "Content-Length: ",
+            "Transfer-Encoding: chunked", "?a=%", "HTTP",
+        ];
+        let piece = prop_oneof![
+            1 => any::<u8>().prop_map(|b| vec![b]),
+            1 => (0..1000usize).prop_map(|n| n.to_string().into_bytes()),
+            2 => prop::sample::select(&PIECES[..]).prop_map(|p| p.as_bytes().to_vec()),
+        ];
+        prop::collection::vec(piece, 0..40).prop_map(|pieces| pieces.concat())
+    }
+
+    #[test]
+    fn test_malformed_heads_are_rejected(){
+        for line in ["GET", "GET /", "\r\n", "GET / HTTP/1.1 extra", "G3T / HTTP/1.1", "GET / FTP/1"] {
+            let raw = format!("{}\r\n\r\n", line);
+            assert!(HttpRequest::read_head(&mut raw.as_bytes()).is_err(), "{:?}", line);
+        }
+        assert!(HttpRequest::read_head(&mut &b""[..]).is_err());
+        let huge = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(70_000));
+        assert!(HttpRequest::read_head(&mut huge.as_bytes()).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_arbitrary_input_never_panics(input in prop_oneof![fragments(), any::<Vec<u8>>()]) {
+            let _: HttpRequest = String::from_utf8_lossy(&input).into_owned().into();
+            if let Ok(mut req) = HttpRequest::read_head(&mut &input[..]) {
+                let _ = (req.path(), req.query_params());
+                let _ = req.read_body(&mut &input[..], 1024);
+            }
+        }
+
+        #[test]
+        fn test_bad_request_lines_are_rejected(
+            line in prop_oneof![
+                // A method that isn't all letters.
+                "[A-Z]{0,3}[0-9_.-][A-Z]{0,3} /[a-z]{0,5} HTTP/1\\.1",
+                // A version that isn't HTTP.
+                "GET /[a-z]{0,5} (FTP|HTP|http)/1\\.1",
+                // Too few or too many words.
+                "GET( /[a-z]{0,5})?",
+                "GET /[a-z]{0,5} HTTP/1\\.1 [a-z]{1,5}",
+            ]
+        ) {
+            let raw = format!("{}\r\nHost: a\r\n\r\n", line);
+            prop_assert!(HttpRequest::read_head(&mut raw.as_bytes()).is_err(), "{:?}", line);
+        }
+
+        #[test]
+        fn test_generated_requests_roundtrip(
+            method in prop::sample::select(&METHODS[..]),
+            segments in prop::collection::vec("[a-zA-Z0-9._-]{1,8}", 0..4),
+            pairs in prop::collection::vec((".{1,5}", ".{0,5}"), 0..3),
+            headers in prop::collection::vec(("[a-zA-Z0-9._-]{1,4}", "[ -~]{0,20}"), 0..5),
+            body in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let mut target = format!("/{}", segments.join("/"));
+            if !pairs.is_empty() {
+                target = format!("{}?{}", target, crate::form::encode_urlencoded(&pairs));
+            }
+            let headers: Vec<(String, String)> = headers
+                .into_iter()
+                .enumerate()
+                .map(|(i, (name, value))| (format!("X-{}-{}", i, name), value.trim().to_string()))
+                .collect();
+            let mut raw = format!("{} {} HTTP/1.1\r\nContent-Length: {}\r\n", method, target, body.len());
+            for (name, value) in &headers {
+                raw.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            raw.push_str("\r\n");
+            let mut input = raw.clone().into_bytes();
+            input.extend_from_slice(&body);
+
+            let req = HttpRequest::read_from(&mut &input[..], 1024).unwrap_or_else(|e| panic!("{}: {:?}", e, raw));
+            prop_assert_eq!(req.method.as_str(), method);
+            prop_assert_eq!(&req.resource, &Resource::Path(target.clone()));
+            prop_assert_eq!(req.query_params(), pairs);
+            for (name, value) in &headers {
+                prop_assert_eq!(req.header(name), Some(value.as_str()));
+            }
+            prop_assert_eq!(req.msg_body, body);
+        }
+    }
 }
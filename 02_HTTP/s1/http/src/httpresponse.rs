@@ -1,20 +1,24 @@
+use crate::chunked::{self, ChunkedWriter};
 use std::collections::HashMap;
-use std::io::{Result, Write};
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+
+/// Upper bound on a response head read by `ResponseHead::read_from`.
+const MAX_HEAD_SIZE: u64 = 64 * 1024;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct HttpResponse<'a> {
     version: &'a str,
     status_code: &'a str,
     status_text: &'a str,
-    headers: Option<HashMap<&'a str, &'a str>>,
+    headers: Option<HashMap<String, String>>,
     body: Option<String>,
 }
 impl<'a> Default for HttpResponse<'a> {
     fn default() -> Self {
         Self {
-            version: "HTTP/1.1".into(),
-            status_code: "200".into(),
-            status_text: "OK".into(),
+            version: "HTTP/1.1",
+            status_code: "200",
+            status_text: "OK",
             headers: None,
             body: None,
         }
@@ -29,7 +33,7 @@ impl<'a> From<HttpResponse<'a>> for String{
             &res1.status_code(),
             &res1.status_text(),
             &res1.headers(),
-            &res.body.unwrap().len(),
+            &res1.body().len(),
             &res1.body()
         )
     }
@@ -43,61 +47,181 @@ impl<'a> HttpResponse<'a> {
 
         let mut response: HttpResponse<'a> = HttpResponse::default();
         if status_code != "200"{
-            response.status_code = status_code.into();
+            response.status_code = status_code;
         };
         response.headers = match &headers {
-            Some(_h) => headers,
+            Some(h) => Some(h.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()),
             None => {
                 let mut h = HashMap::new();
-                h.insert("Content-Type", "text/html");
+                h.insert("Content-Type".to_string(), "text/html".to_string());
                 Some(h)
             }
         };
         response.status_text = match response.status_code {
-            "200" => "OK".into(),
-            "400" => "Bad Request".into(),
-            "404" => "Not Found".into(),
-            "500" => "Internal Server Error".into(),
-            _ => "Not Found".into(),
+            "200" => "OK",
+            "201" => "Created",
+            "204" => "No Content",
+            "301" => "Moved Permanently",
+            "400" => "Bad Request",
+            "401" => "Unauthorized",
+            "403" => "Forbidden",
+            "404" => "Not Found",
+            "405" => "Method Not Allowed",
+            "406" => "Not Acceptable",
+            "409" => "Conflict",
+            "413" => "Payload Too Large",
+            "415" => "Unsupported Media Type",
+            "422" => "Unprocessable Entity",
+            "426" => "Upgrade Required",
+            "429" => "Too Many Requests",
+            "500" => "Internal Server Error",
+            "502" => "Bad Gateway",
+            "504" => "Gateway Timeout",
+            _ => "Not Found",
         };
         response.body = body;
         response
     }
-    pub fn send_response(&self, write_stream:&mut impl Write) -> Result<()>{
+    pub fn send_response(&self, write_stream:&mut (impl Write + ?Sized)) -> Result<()>{
         let res = self.clone();
         let response_string : String  = String::from(res);
         let _ = write!(write_stream, "{}", response_string);
         Ok(())
     }
+    /// Writes the status line and headers only, and returns a writer that streams the
+    /// body with chunked transfer encoding. Any body set on the response is ignored.
+    pub fn send_chunked<'w, W: Write + ?Sized>(&self, write_stream: &'w mut W) -> Result<ChunkedWriter<&'w mut W>>{
+        write!(
+            write_stream,
+            "{} {} {}\r\n{}Transfer-Encoding: chunked\r\n\r\n",
+            self.version(),
+            self.status_code(),
+            self.status_text(),
+            self.headers()
+        )?;
+        write_stream.flush()?;
+        Ok(ChunkedWriter::new(write_stream))
+    }
+    /// Sets a header, replacing any existing value under the same case-insensitive name.
+    pub fn set_header(&mut self, key: &str, value: &str){
+        let map = self.headers.get_or_insert_with(HashMap::new);
+        map.retain(|k, _| !k.eq_ignore_ascii_case(key));
+        map.insert(key.to_string(), value.to_string());
+    }
+    pub fn with_header(mut self, key: &str, value: &str) -> Self{
+        self.set_header(key, value);
+        self
+    }
+    pub fn header(&self, key: &str) -> Option<&str>{
+        self.headers
+            .as_ref()?
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+    /// Every header, in no particular order.
+    pub fn header_pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().flatten().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
     fn version(&self) -> &str{
         self.version
     }
-    fn status_code(&self) -> &str{
+    pub fn status_code(&self) -> &str{
         self.status_code
     }
     fn status_text(&self) -> &str{
         self.status_text
     }
     fn headers(&self) -> String{
-        let map: HashMap<&str, &str> = self.headers.clone().unwrap();
+        let mut map: Vec<(&String, &String)> = match &self.headers {
+            Some(h) => h.iter().collect(),
+            None => Vec::new(),
+        };
+        // Sorted so that the same response always serializes the same way.
+        map.sort();
         let mut header_string : String = "".into();
         for(k , v) in map.iter(){
             header_string = format!("{}{}:{}\r\n", header_string, k , v);
         }
         header_string
     }
-    fn body(&self) -> &str {
+    pub fn body(&self) -> &str {
         match &self.body {
             Some(b) => b.as_str(),
-            None => "".into(),
+            None => "",
         }
     }
 
 }
 
+/// The status line and headers of a response read back from another server.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status_code: u16,
+    pub status_text: String,
+    pub headers: Vec<(String, String)>,
+}
+impl ResponseHead {
+    /// Reads a status line and headers, leaving the body unread in `reader`.
+    pub fn read_from(reader: &mut impl BufRead) -> Result<ResponseHead> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+        let mut limited = reader.take(MAX_HEAD_SIZE);
+        let mut line = String::new();
+        if limited.read_line(&mut line)? == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "empty response"));
+        }
+        let mut parts = line.trim_end().splitn(3, ' ');
+        let version = parts.next().unwrap_or("").to_string();
+        if !version.starts_with("HTTP/") {
+            return Err(invalid("bad status line"));
+        }
+        let status_code = parts
+            .next()
+            .and_then(|c| c.parse::<u16>().ok())
+            .filter(|c| (100..1000).contains(c))
+            .ok_or_else(|| invalid("bad status code"))?;
+        let status_text = parts.next().unwrap_or("").to_string();
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if limited.read_line(&mut line)? == 0 {
+                return Err(invalid("truncated response head"));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            match header.split_once(':') {
+                Some((k, v)) => headers.push((k.trim().to_string(), v.trim().to_string())),
+                None => return Err(invalid("bad header line")),
+            }
+        }
+        Ok(ResponseHead {
+            version,
+            status_code,
+            status_text,
+            headers,
+        })
+    }
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn is_chunked(&self) -> bool {
+        self.header("Transfer-Encoding").is_some_and(chunked::is_chunked)
+    }
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("Content-Length").and_then(|v| v.parse().ok())
+    }
+}
+
 #[cfg(test)]
 mod tests{
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_response_struct_creation_200(){
@@ -112,7 +236,7 @@ mod tests{
             status_text: "OK",
             headers:{
                 let mut h = HashMap::new();
-                h.insert("Content-Type", "text/html");
+                h.insert("Content-Type".to_string(), "text/html".to_string());
                 Some(h)
             },
             body: Some("xxx".into()),
@@ -133,7 +257,7 @@ mod tests{
             status_text: "Not Found",
             headers:{
                 let mut h = HashMap::new();
-                h.insert("Content-Type", "text/html");
+                h.insert("Content-Type".to_string(), "text/html".to_string());
                 Some(h)
             },
             body: Some("xxx".into()),
@@ -141,6 +265,43 @@ mod tests{
         assert_eq!(response_actual, response_expected);
     }
     #[test]
+    fn test_response_head_read_from(){
+        let raw = "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+        let mut reader = raw.as_bytes();
+        let head = ResponseHead::read_from(&mut reader).unwrap();
+        assert_eq!(head.status_code, 201);
+        assert_eq!(head.status_text, "Created");
+        assert_eq!(head.header("content-type"), Some("application/json"));
+        assert_eq!(head.content_length(), Some(2));
+        assert_eq!(reader, b"{}");
+
+        let mut bad = "HTTP/1.1 abc Nope\r\n\r\n".as_bytes();
+        assert!(ResponseHead::read_from(&mut bad).is_err());
+    }
+    #[test]
+    fn test_set_header(){
+        let response = HttpResponse::new("201", None, None)
+            .with_header("Location", "/api/shipping/orders/3")
+            .with_header("content-type", "application/json");
+        assert_eq!(response.header("location"), Some("/api/shipping/orders/3"));
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        let http_string: String = response.into();
+        assert_eq!(http_string, "HTTP/1.1 201 Created\r\nLocation:/api/shipping/orders/3\r\ncontent-type:application/json\r\nContent-Length: 0\r\n\r\n");
+    }
+    #[test]
+    fn test_send_chunked(){
+        let mut h = HashMap::new();
+        h.insert("Content-Type", "text/event-stream");
+        let response = HttpResponse::new("200", Some(h), None);
+        let mut out = Vec::new();
+        {
+            let mut body = response.send_chunked(&mut out).unwrap();
+            body.write_all(b"data: x\n\n").unwrap();
+        }
+        let expected = "HTTP/1.1 200 OK\r\nContent-Type:text/event-stream\r\nTransfer-Encoding: chunked\r\n\r\n9\r\ndata: x\n\n\r\n0\r\n\r\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+    #[test]
     fn test_http_response_creation(){
         let response_expected = HttpResponse{
             version: "HTTP/1.1",
@@ -148,7 +309,7 @@ mod tests{
             status_text:"Not Found",
             headers:{
                 let mut h = HashMap::new();
-                h.insert("Content-Type", "text/html");
+                h.insert("Content-Type".to_string(), "text/html".to_string());
                 Some(h)
             },
             body:Some("xxx".into()),
@@ -157,4 +318,52 @@ mod tests{
         let actual_string = "HTTP/1.1 404 Not Found\r\nContent-Type:text/html\r\nContent-Length: 3\r\n\r\nxxx";
         assert_eq!(http_string, actual_string);
     }
-}
\ No newline at end of file
+
+    proptest! {
+        #[test]
+        fn test_responses_roundtrip(
+            status in prop::sample::select(&["200", "201", "204", "404", "429", "500"][..]),
+            values in prop::collection::vec("[a-zA-Z0-9]{1,11}", 0..4),
+            body in ".{0,40}",
+        ) {
+            let names: Vec<String> = (0..values.len()).map(|i| format!("X-Field-{}", i)).collect();
+            let mut headers: HashMap<&str, &str> = HashMap::new();
+            for (name, value) in names.iter().zip(&values) {
+                headers.insert(name, value);
+            }
+            let raw = String::from(HttpResponse::new(status, Some(headers), Some(body.clone())));
+            let mut reader = raw.as_bytes();
+            let head = ResponseHead::read_from(&mut reader).unwrap_or_else(|e| panic!("{}: {:?}", e, raw));
+            prop_assert_eq!(head.status_code.to_string(), status);
+            for (name, value) in names.iter().zip(&values) {
+                prop_assert_eq!(head.header(name), Some(value.as_str()));
+            }
+            prop_assert_eq!(head.content_length(), Some(body.len() as u64));
+            prop_assert_eq!(reader, body.as_bytes());
+        }
+
+        #[test]
+        fn test_garbage_is_rejected(
+            garbage in prop::collection::vec(
+                prop_oneof![prop::sample::select(&b"HTTP/1.1 200:\r\n "[..]), any::<u8>()],
+                0..60,
+            ),
+        ) {
+            if let Ok(head) = ResponseHead::read_from(&mut &garbage[..]) {
+                prop_assert!((100..1000).contains(&head.status_code));
+            }
+        }
+
+        #[test]
+        fn test_bad_status_lines_are_rejected(
+            line in prop_oneof![
+                // Not HTTP, or a status code that isn't three digits.
+                "(FTP|http|HTP)/1\\.1 200 OK",
+                "HTTP/1\\.1 ([0-9]{1,2}|[1-9][0-9]{3,5}|0[0-9]{2}|[a-z]{3})( OK)?",
+            ]
+        ) {
+            let raw = format!("{}\r\nContent-Length: 0\r\n\r\n", line);
+            prop_assert!(ResponseHead::read_from(&mut raw.as_bytes()).is_err(), "{:?}", line);
+        }
+    }
+}
@@ -1,2 +1,10 @@
+pub mod chunked;
+pub mod form;
+pub mod h2;
+pub mod hpack;
 pub mod httprequest;
-pub mod httpresponse;
\ No newline at end of file
+pub mod httpresponse;
+pub mod negotiation;
+pub mod proxy_protocol;
+pub mod sse;
+pub mod websocket;
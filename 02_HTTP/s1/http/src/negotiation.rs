@@ -0,0 +1,241 @@
+//! Content negotiation: picks, of the representations a handler can produce, the one a
+//! client prefers according to its `Accept`, `Accept-Language` and `Accept-Charset` headers.
+
+use crate::httprequest::HttpRequest;
+
+/// One item of an `Accept`-style header, such as `text/html;level=1;q=0.8`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Preference {
+    /// Lowercased, e.g. `text/html`, `en-gb` or `*`.
+    pub value: String,
+    /// Parameters before `q`, lowercased names with their values.
+    pub params: Vec<(String, String)>,
+    /// From 0 (not acceptable) to 1000, the q-value in thousandths.
+    pub q: u16,
+}
+
+/// Parses a q-value: `0`, `1`, or up to three decimals such as `0.125`.
+fn parse_q(s: &str) -> Option<u16> {
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Parses a header such as `Accept`. Items with a malformed q-value are left out.
+pub fn parse(header: &str) -> Vec<Preference> {
+    let mut preferences = Vec::new();
+    'items: for item in header.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let value = match parts.next() {
+            Some(value) if !value.is_empty() => value.to_ascii_lowercase(),
+            _ => continue,
+        };
+        let mut preference = Preference { value, params: Vec::new(), q: 1000 };
+        for param in parts {
+            let (name, val) = param.split_once('=').unwrap_or((param, ""));
+            let name = name.trim().to_ascii_lowercase();
+            let val = val.trim().trim_matches('"');
+            if name == "q" {
+                match parse_q(val) {
+                    Some(q) => preference.q = q,
+                    None => continue 'items,
+                }
+                // Whatever follows `q` are extensions of the item, not parameters of the type.
+                break;
+            }
+            preference.params.push((name, val.to_ascii_lowercase()));
+        }
+        preferences.push(preference);
+    }
+    preferences
+}
+
+/// How closely a media range matches a media type offered, or `None` if it doesn't:
+/// `*/*`, then `type/*`, then the type itself, then the type with parameters.
+fn media_specificity(range: &Preference, offered: &str) -> Option<usize> {
+    let mut parts = offered.split(';').map(str::trim);
+    let offered_type = parts.next().unwrap_or("").to_ascii_lowercase();
+    let offered_params: Vec<(String, String)> = parts
+        .filter_map(|p| p.split_once('='))
+        .map(|(n, v)| (n.trim().to_ascii_lowercase(), v.trim().trim_matches('"').to_ascii_lowercase()))
+        .collect();
+    let (kind, subtype) = offered_type.split_once('/')?;
+    let specificity = match range.value.split_once('/')? {
+        ("*", "*") => 0,
+        (k, "*") if k == kind => 1,
+        (k, s) if k == kind && s == subtype => 2,
+        _ => return None,
+    };
+    if !range.params.iter().all(|param| offered_params.contains(param)) {
+        return None;
+    }
+    Some(specificity + range.params.len())
+}
+
+/// Language ranges match a tag that equals them or starts with them and a `-`
+/// (basic filtering, RFC 4647); longer ranges are more specific.
+fn language_specificity(range: &Preference, offered: &str) -> Option<usize> {
+    let offered = offered.to_ascii_lowercase();
+    if range.value == "*" {
+        return Some(0);
+    }
+    let matches = offered == range.value
+        || offered.strip_prefix(&range.value).is_some_and(|rest| rest.starts_with('-'));
+    matches.then_some(range.value.len())
+}
+
+fn charset_specificity(range: &Preference, offered: &str) -> Option<usize> {
+    match range.value.as_str() {
+        "*" => Some(0),
+        value if value.eq_ignore_ascii_case(offered) => Some(1),
+        _ => None,
+    }
+}
+
+/// The offer the client likes best: the one whose most specific matching preference has
+/// the highest q-value above 0. Ties go to the earlier offer, as does everything if the
+/// client sent no header. Offers no preference matches aren't acceptable.
+fn negotiate<'a>(
+    header: Option<&str>,
+    offered: &[&'a str],
+    specificity: fn(&Preference, &str) -> Option<usize>,
+) -> Option<&'a str> {
+    let header = match header {
+        Some(header) => header,
+        None => return offered.first().copied(),
+    };
+    let preferences = parse(header);
+    let mut best: Option<(&str, u16)> = None;
+    for offer in offered {
+        let q = preferences
+            .iter()
+            .filter_map(|p| specificity(p, offer).map(|s| (s, p.q)))
+            .max_by_key(|(s, _)| *s)
+            .map(|(_, q)| q)
+            .unwrap_or(0);
+        if q > 0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((offer, q));
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
+
+/// The media type of `offered`, such as `application/json`, that best matches `Accept`.
+pub fn media_type<'a>(accept: Option<&str>, offered: &[&'a str]) -> Option<&'a str> {
+    negotiate(accept, offered, media_specificity)
+}
+
+/// The language tag of `offered`, such as `en-US`, that best matches `Accept-Language`.
+pub fn language<'a>(accept_language: Option<&str>, offered: &[&'a str]) -> Option<&'a str> {
+    negotiate(accept_language, offered, language_specificity)
+}
+
+/// The charset of `offered`, such as `utf-8`, that best matches `Accept-Charset`.
+pub fn charset<'a>(accept_charset: Option<&str>, offered: &[&'a str]) -> Option<&'a str> {
+    negotiate(accept_charset, offered, charset_specificity)
+}
+
+/// Negotiates the representation of a response to a request, noting which headers the
+/// choice depended on for the response's `Vary` header.
+pub struct Negotiation<'r> {
+    req: &'r HttpRequest,
+    vary: Vec<&'static str>,
+}
+impl<'r> Negotiation<'r> {
+    pub fn new(req: &'r HttpRequest) -> Self {
+        Negotiation { req, vary: Vec::new() }
+    }
+    fn consult(&mut self, header: &'static str) -> Option<&'r str> {
+        if !self.vary.contains(&header) {
+            self.vary.push(header);
+        }
+        self.req.header(header)
+    }
+    pub fn media_type<'a>(&mut self, offered: &[&'a str]) -> Option<&'a str> {
+        media_type(self.consult("Accept"), offered)
+    }
+    pub fn language<'a>(&mut self, offered: &[&'a str]) -> Option<&'a str> {
+        language(self.consult("Accept-Language"), offered)
+    }
+    pub fn charset<'a>(&mut self, offered: &[&'a str]) -> Option<&'a str> {
+        charset(self.consult("Accept-Charset"), offered)
+    }
+    /// The value of `Vary`: the headers consulted so far, e.g. `Accept, Accept-Language`.
+    pub fn vary(&self) -> String {
+        self.vary.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFERED: [&str; 3] = ["application/json", "text/csv", "text/html"];
+
+    #[test]
+    fn test_parse() {
+        let preferences = parse("text/html;level=1, TEXT/*;q=0.5, */*;q=0.1;ext=1, bad;q=2, ;q=1");
+        let summary: Vec<(&str, u16)> = preferences.iter().map(|p| (p.value.as_str(), p.q)).collect();
+        assert_eq!(summary, [("text/html", 1000), ("text/*", 500), ("*/*", 100)]);
+        assert_eq!(preferences[0].params, [("level".to_string(), "1".to_string())]);
+        assert!(preferences[2].params.is_empty());
+        for (q, expected) in [("1.000", Some(1000)), ("0.125", Some(125)), ("0.5", Some(500)), ("0", Some(0))] {
+            assert_eq!(parse_q(q), expected);
+        }
+        for q in ["1.5", "0.1234", "-0", "", "0.x", ".5"] {
+            assert_eq!(parse_q(q), None, "{:?}", q);
+        }
+    }
+
+    #[test]
+    fn test_media_type() {
+        assert_eq!(media_type(None, &OFFERED), Some("application/json"));
+        assert_eq!(media_type(Some("*/*"), &OFFERED), Some("application/json"));
+        assert_eq!(media_type(Some("text/csv"), &OFFERED), Some("text/csv"));
+        assert_eq!(media_type(Some("text/*, application/json;q=0.9"), &OFFERED), Some("text/csv"));
+        // A browser: HTML beats the `*/*` fallback.
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(media_type(Some(browser), &OFFERED), Some("text/html"));
+        // The most specific range decides, even when a broader one has a higher q.
+        assert_eq!(media_type(Some("text/*;q=0.9, text/html;q=0, */*;q=0.1"), &OFFERED[1..]), Some("text/csv"));
+        assert_eq!(media_type(Some("*/*;q=0.5, application/json;q=0"), &OFFERED), Some("text/csv"));
+        assert_eq!(media_type(Some("Application/JSON"), &OFFERED), Some("application/json"));
+        assert_eq!(media_type(Some("text/html;level=1"), &["text/html"]), None);
+        assert_eq!(media_type(Some("text/html;charset=utf-8"), &["text/html; charset=UTF-8"]), Some("text/html; charset=UTF-8"));
+        assert_eq!(media_type(Some("image/png"), &OFFERED), None);
+        assert_eq!(media_type(Some("application/json;q=0"), &OFFERED[..1]), None);
+    }
+
+    #[test]
+    fn test_language_and_charset() {
+        let offered = ["en-US", "de", "fr-CA"];
+        assert_eq!(language(Some("de-DE, fr;q=0.8"), &offered), Some("fr-CA"));
+        assert_eq!(language(Some("fr-ca;q=0.5, en;q=0.7"), &offered), Some("en-US"));
+        assert_eq!(language(Some("en-GB"), &offered), None);
+        assert_eq!(language(Some("e"), &offered), None);
+        assert_eq!(language(Some("*;q=0.1, de;q=0"), &offered), Some("en-US"));
+        assert_eq!(charset(Some("iso-8859-1, UTF-8;q=0.5"), &["utf-8"]), Some("utf-8"));
+        assert_eq!(charset(Some("iso-8859-1"), &["utf-8"]), None);
+        assert_eq!(charset(Some("*;q=0.1"), &["utf-8"]), Some("utf-8"));
+    }
+
+    #[test]
+    fn test_negotiation_vary() {
+        let req: HttpRequest =
+            String::from("GET /api HTTP/1.1\r\nAccept: text/csv\r\nAccept-Language: de\r\n\r\n").into();
+        let mut negotiation = Negotiation::new(&req);
+        assert_eq!(negotiation.vary(), "");
+        assert_eq!(negotiation.media_type(&OFFERED), Some("text/csv"));
+        assert_eq!(negotiation.media_type(&OFFERED), Some("text/csv"));
+        assert_eq!(negotiation.charset(&["utf-8"]), Some("utf-8"));
+        assert_eq!(negotiation.language(&["en", "de"]), Some("de"));
+        assert_eq!(negotiation.vary(), "Accept, Accept-Charset, Accept-Language");
+    }
+}
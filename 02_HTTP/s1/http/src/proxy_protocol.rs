@@ -0,0 +1,266 @@
+//! The HAProxy PROXY protocol, versions 1 (text) and 2 (binary), with which a
+//! load balancer tells the server whom a connection is really from.
+
+use std::io::{self, BufRead, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The start of a version 2 header.
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest version 1 header, `\r\n` included.
+const V1_MAX_LEN: u64 = 107;
+
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_INET: u8 = 0x1;
+const V2_INET6: u8 = 0x2;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ProxyHeader {
+    /// The proxy's own connection, such as a health check, or one whose addresses
+    /// it didn't pass on (`UNKNOWN`, or a family other than IPv4 and IPv6).
+    Local,
+    Proxied { source: SocketAddr, destination: SocketAddr },
+}
+impl ProxyHeader {
+    /// The client's address, if the proxy passed it on.
+    pub fn source(&self) -> Option<SocketAddr> {
+        match self {
+            ProxyHeader::Local => None,
+            ProxyHeader::Proxied { source, .. } => Some(*source),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("PROXY protocol: {}", msg))
+}
+
+/// Reads the header a connection starts with. Returns `Ok(None)`, consuming nothing,
+/// if the connection doesn't start with one, and an error if it starts with a malformed one.
+pub fn read_header(reader: &mut impl BufRead) -> io::Result<Option<ProxyHeader>> {
+    let buf = reader.fill_buf()?;
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if V2_SIGNATURE.starts_with(&buf[..buf.len().min(V2_SIGNATURE.len())]) {
+        read_v2(reader).map(Some)
+    } else if V1_PREFIX.starts_with(&buf[..buf.len().min(V1_PREFIX.len())]) {
+        read_v1(reader).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`, or `PROXY UNKNOWN ...\r\n`.
+fn read_v1(reader: &mut impl BufRead) -> io::Result<ProxyHeader> {
+    let mut line = Vec::new();
+    reader.take(V1_MAX_LEN).read_until(b'\n', &mut line)?;
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or_else(|| invalid("header line too long or not ended by CRLF"))?;
+    let line = std::str::from_utf8(line).map_err(|_| invalid("header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::Local),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let ip = |s: &str| -> io::Result<IpAddr> {
+                let ip: IpAddr = s.parse().map_err(|_| invalid("bad address"))?;
+                match (family, ip) {
+                    ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(ip),
+                    _ => Err(invalid("address doesn't match the protocol family")),
+                }
+            };
+            let port = |s: &str| -> io::Result<u16> {
+                // No leading zeros, as the spec requires.
+                match s.parse() {
+                    Ok(port) if !(s.starts_with('0') && s.len() > 1) => Ok(port),
+                    _ => Err(invalid("bad port")),
+                }
+            };
+            Ok(ProxyHeader::Proxied {
+                source: SocketAddr::new(ip(source)?, port(source_port)?),
+                destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+            })
+        }
+        _ => Err(invalid("malformed version 1 header")),
+    }
+}
+
+/// The 12-byte signature, version and command, family and protocol, a 16-bit length,
+/// then the addresses and any TLVs, which are skipped.
+fn read_v2(reader: &mut impl BufRead) -> io::Result<ProxyHeader> {
+    let mut head = [0; 16];
+    reader.read_exact(&mut head)?;
+    if head[..12] != V2_SIGNATURE {
+        return Err(invalid("bad version 2 signature"));
+    }
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    let family = head[13] >> 4;
+    match head[12] {
+        V2_LOCAL => Ok(ProxyHeader::Local),
+        V2_PROXY => {
+            let (source, destination) = match family {
+                V2_INET if len >= 12 => {
+                    let ip = |at: usize| IpAddr::V4(Ipv4Addr::new(body[at], body[at + 1], body[at + 2], body[at + 3]));
+                    ((ip(0), 8), (ip(4), 10))
+                }
+                V2_INET6 if len >= 36 => {
+                    let ip = |at: usize| {
+                        let octets: [u8; 16] = body[at..at + 16].try_into().unwrap();
+                        IpAddr::V6(Ipv6Addr::from(octets))
+                    };
+                    ((ip(0), 32), (ip(16), 34))
+                }
+                V2_INET | V2_INET6 => return Err(invalid("address block too short")),
+                _ => return Ok(ProxyHeader::Local),
+            };
+            let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+            Ok(ProxyHeader::Proxied {
+                source: SocketAddr::new(source.0, port(source.1)),
+                destination: SocketAddr::new(destination.0, port(destination.1)),
+            })
+        }
+        _ => Err(invalid("unsupported version or command")),
+    }
+}
+
+/// Writes a version 1 header, as a proxy would.
+pub fn write_v1(header: &ProxyHeader) -> String {
+    match header {
+        ProxyHeader::Local => "PROXY UNKNOWN\r\n".to_string(),
+        ProxyHeader::Proxied { source, destination } => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+        }
+    }
+}
+
+/// Writes a version 2 header, as a proxy would.
+pub fn write_v2(header: &ProxyHeader) -> Vec<u8> {
+    let mut out = V2_SIGNATURE.to_vec();
+    match header {
+        ProxyHeader::Local => out.extend_from_slice(&[V2_LOCAL, 0, 0, 0]),
+        ProxyHeader::Proxied { source, destination } => {
+            let mut body = Vec::new();
+            let family = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    body.extend_from_slice(&s.octets());
+                    body.extend_from_slice(&d.octets());
+                    V2_INET
+                }
+                (s, d) => {
+                    let v6 = |ip: IpAddr| match ip {
+                        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                        IpAddr::V6(ip) => ip,
+                    };
+                    body.extend_from_slice(&v6(s).octets());
+                    body.extend_from_slice(&v6(d).octets());
+                    V2_INET6
+                }
+            };
+            body.extend_from_slice(&source.port().to_be_bytes());
+            body.extend_from_slice(&destination.port().to_be_bytes());
+            // Stream protocol over the family.
+            out.extend_from_slice(&[V2_PROXY, family << 4 | 0x1]);
+            out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            out.extend_from_slice(&body);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &[u8]) -> (io::Result<Option<ProxyHeader>>, Vec<u8>) {
+        let mut reader = input;
+        let header = read_header(&mut reader);
+        (header, reader.to_vec())
+    }
+
+    fn proxied(source: &str, destination: &str) -> ProxyHeader {
+        ProxyHeader::Proxied {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_v1() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n\r\n");
+        let expected = proxied("192.0.2.1:56324", "198.51.100.1:443");
+        assert_eq!(header.unwrap(), Some(expected));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(write_v1(&expected), "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n");
+        let v6 = proxied("[2001:db8::1]:1234", "[2001:db8::2]:80");
+        assert_eq!(read(write_v1(&v6).as_bytes()).0.unwrap(), Some(v6));
+        assert_eq!(read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").0.unwrap(), Some(ProxyHeader::Local));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").0.unwrap().and_then(|h| h.source()), None);
+        for bad in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 70000 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 1 2\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n",
+        ] {
+            assert!(read(bad).0.is_err(), "{:?}", String::from_utf8_lossy(bad));
+        }
+        let long = format!("PROXY UNKNOWN {}\r\n", "x".repeat(100));
+        assert!(read(long.as_bytes()).0.is_err());
+    }
+
+    #[test]
+    fn test_v2() {
+        for header in [
+            proxied("192.0.2.1:56324", "198.51.100.1:443"),
+            proxied("[2001:db8::1]:1234", "[2001:db8::2]:80"),
+            ProxyHeader::Local,
+        ] {
+            let mut input = write_v2(&header);
+            input.extend_from_slice(b"PRI * HTTP/2.0");
+            let (read_back, rest) = read(&input);
+            assert_eq!(read_back.unwrap(), Some(header));
+            assert_eq!(rest, b"PRI * HTTP/2.0");
+        }
+        // TLVs after the addresses are skipped.
+        let mut input = write_v2(&proxied("192.0.2.1:1", "192.0.2.2:2"));
+        input[15] += 3;
+        input.extend_from_slice(&[0x04, 0, 0, b'G']);
+        let (header, rest) = read(&input);
+        assert_eq!(header.unwrap().and_then(|h| h.source()), Some("192.0.2.1:1".parse().unwrap()));
+        assert_eq!(rest, b"G");
+        // AF_UNIX addresses aren't passed on.
+        let mut unix = V2_SIGNATURE.to_vec();
+        unix.extend_from_slice(&[V2_PROXY, 0x31, 0, 216]);
+        unix.extend_from_slice(&[0; 216]);
+        assert_eq!(read(&unix).0.unwrap(), Some(ProxyHeader::Local));
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend_from_slice(&[V2_PROXY, 0x11, 0, 4, 1, 2, 3, 4]);
+        assert!(read(&short).0.is_err());
+        let mut version3 = write_v2(&ProxyHeader::Local);
+        version3[12] = 0x30;
+        assert!(read(&version3).0.is_err());
+        assert!(read(&V2_SIGNATURE[..8]).0.is_err());
+    }
+
+    #[test]
+    fn test_no_header() {
+        for input in [&b"GET / HTTP/1.1\r\n\r\n"[..], b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n", b""] {
+            let (header, rest) = read(input);
+            assert_eq!(header.unwrap(), None);
+            assert_eq!(rest, input);
+        }
+    }
+}
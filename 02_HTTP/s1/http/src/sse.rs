@@ -0,0 +1,157 @@
+use crate::chunked::ChunkedWriter;
+use crate::httprequest::HttpRequest;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// One Server-Sent Event, serialized in the `text/event-stream` format.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    pub retry: Option<Duration>,
+}
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Event {
+            data: data.to_string(),
+            ..Event::default()
+        }
+    }
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+impl From<&Event> for String {
+    fn from(e: &Event) -> String {
+        let mut out = String::new();
+        // Field values can't contain newlines; strip them rather than corrupt the stream.
+        let clean = |s: &str| s.replace(['\r', '\n'], "");
+        if let Some(event) = &e.event {
+            out.push_str(&format!("event: {}\n", clean(event)));
+        }
+        if let Some(id) = &e.id {
+            out.push_str(&format!("id: {}\n", clean(id)));
+        }
+        if let Some(retry) = e.retry {
+            out.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in e.data.split('\n') {
+            out.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+/// The `Last-Event-ID` a reconnecting client sent, if any.
+pub fn last_event_id(req: &HttpRequest) -> Option<&str> {
+    req.header("Last-Event-ID").filter(|id| !id.is_empty())
+}
+
+/// A `text/event-stream` response body. Writes go out immediately as chunks, and
+/// `keep_alive` sends a comment line when the stream has been idle for the heartbeat interval.
+pub struct EventStream<W: Write> {
+    writer: ChunkedWriter<W>,
+    heartbeat: Duration,
+    last_write: Instant,
+    last_event_id: Option<String>,
+}
+impl<W: Write> EventStream<W> {
+    pub fn new(writer: ChunkedWriter<W>, last_event_id: Option<&str>) -> Self {
+        EventStream {
+            writer,
+            heartbeat: Duration::from_secs(15),
+            last_write: Instant::now(),
+            last_event_id: last_event_id.map(str::to_string),
+        }
+    }
+    pub fn with_heartbeat(mut self, heartbeat: Duration) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+    /// The ID of the last event sent, initially the client's `Last-Event-ID`.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+    pub fn send(&mut self, event: &Event) -> io::Result<()> {
+        let text: String = event.into();
+        self.write_raw(&text)?;
+        if let Some(id) = &event.id {
+            self.last_event_id = Some(id.clone());
+        }
+        Ok(())
+    }
+    /// Tells the client how long to wait before reconnecting.
+    pub fn retry(&mut self, retry: Duration) -> io::Result<()> {
+        self.write_raw(&format!("retry: {}\n\n", retry.as_millis()))
+    }
+    /// Sends a heartbeat comment if nothing was written for the heartbeat interval.
+    /// Fails once the client has gone away, which lets handlers end their loop.
+    pub fn keep_alive(&mut self) -> io::Result<()> {
+        if self.last_write.elapsed() >= self.heartbeat {
+            self.write_raw(": keep-alive\n\n")?;
+        }
+        Ok(())
+    }
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.finish()
+    }
+    fn write_raw(&mut self, text: &str) -> io::Result<()> {
+        self.writer.write_all(text.as_bytes())?;
+        self.writer.flush()?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_format() {
+        let event = Event::new("line one\nline two")
+            .id("42")
+            .event("order-status")
+            .retry(Duration::from_millis(1500));
+        let text: String = (&event).into();
+        assert_eq!(
+            text,
+            "event: order-status\nid: 42\nretry: 1500\ndata: line one\ndata: line two\n\n"
+        );
+    }
+
+    #[test]
+    fn test_event_stream_tracks_ids_and_heartbeats() {
+        let mut out = Vec::new();
+        {
+            let mut stream = EventStream::new(ChunkedWriter::new(&mut out), Some("7"))
+                .with_heartbeat(Duration::from_secs(0));
+            assert_eq!(stream.last_event_id(), Some("7"));
+            stream.send(&Event::new("hi").id("8")).unwrap();
+            assert_eq!(stream.last_event_id(), Some("8"));
+            stream.keep_alive().unwrap();
+        }
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text, "10\r\nid: 8\ndata: hi\n\n\r\nE\r\n: keep-alive\n\n\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_last_event_id_header() {
+        let req: HttpRequest = "GET /events HTTP/1.1\r\nLast-Event-ID: 12\r\n\r\n".to_string().into();
+        assert_eq!(last_event_id(&req), Some("12"));
+    }
+}
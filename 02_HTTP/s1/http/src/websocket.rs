@@ -0,0 +1,603 @@
+use crate::httprequest::{HttpRequest, Method};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// GUID appended to the client key when computing `Sec-WebSocket-Accept` (RFC 6455, 1.3).
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_CONTROL_PAYLOAD: usize = 125;
+const DEFAULT_MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    NotGet,
+    MissingUpgrade,
+    MissingConnectionUpgrade,
+    UnsupportedVersion,
+    InvalidKey,
+}
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            HandshakeError::NotGet => "websocket handshake must use GET",
+            HandshakeError::MissingUpgrade => "missing `Upgrade: websocket` header",
+            HandshakeError::MissingConnectionUpgrade => "missing `Connection: Upgrade` header",
+            HandshakeError::UnsupportedVersion => "unsupported Sec-WebSocket-Version",
+            HandshakeError::InvalidKey => "invalid Sec-WebSocket-Key",
+        };
+        write!(f, "{}", msg)
+    }
+}
+impl std::error::Error for HandshakeError {}
+
+/// Computes the `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.trim().as_bytes());
+    sha.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(sha.finalize())
+}
+
+/// Validates an opening handshake and returns the `Sec-WebSocket-Accept` value to send back.
+pub fn validate_handshake(req: &HttpRequest) -> Result<String, HandshakeError> {
+    if req.method != Method::GET {
+        return Err(HandshakeError::NotGet);
+    }
+    let has_token = |header: &str, token: &str| {
+        req.header(header)
+            .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    };
+    if !has_token("Upgrade", "websocket") {
+        return Err(HandshakeError::MissingUpgrade);
+    }
+    if !has_token("Connection", "upgrade") {
+        return Err(HandshakeError::MissingConnectionUpgrade);
+    }
+    if req.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(HandshakeError::UnsupportedVersion);
+    }
+    let key = req.header("Sec-WebSocket-Key").ok_or(HandshakeError::InvalidKey)?;
+    match STANDARD.decode(key) {
+        Ok(nonce) if nonce.len() == 16 => Ok(accept_key(key)),
+        _ => Err(HandshakeError::InvalidKey),
+    }
+}
+
+/// Writes the `101 Switching Protocols` response that completes the handshake.
+pub fn write_handshake_response(stream: &mut impl Write, accept: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    )?;
+    stream.flush()
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+impl Opcode {
+    fn from_u8(b: u8) -> Option<Opcode> {
+        match b {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+/// Why a frame could not be decoded; carries the close code to report to the peer.
+#[derive(Debug, PartialEq)]
+pub struct FrameError {
+    pub code: u16,
+    pub reason: &'static str,
+}
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.reason, self.code)
+    }
+}
+impl std::error::Error for FrameError {}
+
+fn protocol_error(reason: &'static str) -> FrameError {
+    FrameError {
+        code: CLOSE_PROTOCOL_ERROR,
+        reason,
+    }
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            opcode,
+            payload,
+        }
+    }
+
+    /// Encodes the frame, masking the payload when `mask` is given (client-to-server frames).
+    pub fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let len = self.payload.len();
+        let mut out = Vec::with_capacity(len + 14);
+        out.push(if self.fin { 0x80 } else { 0 } | self.opcode.as_u8());
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        if len < 126 {
+            out.push(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        match mask {
+            Some(key) => {
+                out.extend_from_slice(&key);
+                out.extend(self.payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+            }
+            None => out.extend_from_slice(&self.payload),
+        }
+        out
+    }
+
+    /// Decodes one frame from the front of `buf`, returning it with the number of bytes used,
+    /// or `None` when `buf` does not yet hold a whole frame.
+    pub fn decode(
+        buf: &[u8],
+        require_mask: bool,
+        max_payload: usize,
+    ) -> Result<Option<(Frame, usize)>, FrameError> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        if buf[0] & 0x70 != 0 {
+            return Err(protocol_error("reserved bits set"));
+        }
+        let opcode = Opcode::from_u8(buf[0] & 0x0F).ok_or(protocol_error("unknown opcode"))?;
+        let masked = buf[1] & 0x80 != 0;
+        if require_mask && !masked {
+            return Err(protocol_error("client frames must be masked"));
+        }
+        let (len, mut pos) = match buf[1] & 0x7F {
+            126 => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+                (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+            }
+            127 => {
+                if buf.len() < 10 {
+                    return Ok(None);
+                }
+                let mut b = [0; 8];
+                b.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(b), 10)
+            }
+            n => (n as u64, 2),
+        };
+        if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(protocol_error("invalid control frame"));
+        }
+        if len > max_payload as u64 {
+            return Err(FrameError {
+                code: CLOSE_TOO_BIG,
+                reason: "frame too large",
+            });
+        }
+        let len = len as usize;
+        let mut key = None;
+        if masked {
+            if buf.len() < pos + 4 {
+                return Ok(None);
+            }
+            key = Some([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+            pos += 4;
+        }
+        if buf.len() < pos + len {
+            return Ok(None);
+        }
+        let mut payload = buf[pos..pos + len].to_vec();
+        if let Some(key) = key {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= key[i % 4];
+            }
+        }
+        Ok(Some((
+            Frame {
+                fin,
+                opcode,
+                payload,
+            },
+            pos + len,
+        )))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>),
+}
+
+/// The server side of an established WebSocket connection.
+///
+/// Incoming bytes are buffered, so a read timeout on the underlying stream surfaces as an
+/// `io::Error` from `recv` without losing a partially received frame; callers can use that
+/// to interleave sending with waiting for client messages.
+pub struct WebSocket<S: Read + Write> {
+    stream: S,
+    buf: Vec<u8>,
+    fragments: Option<(Opcode, Vec<u8>)>,
+    max_message: usize,
+    close_sent: bool,
+    closed: bool,
+}
+impl<S: Read + Write> WebSocket<S> {
+    pub fn new(stream: S) -> Self {
+        WebSocket {
+            stream,
+            buf: Vec::new(),
+            fragments: None,
+            max_message: DEFAULT_MAX_MESSAGE,
+            close_sent: false,
+            closed: false,
+        }
+    }
+    pub fn with_max_message(mut self, max_message: usize) -> Self {
+        self.max_message = max_message;
+        self
+    }
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn send(&mut self, msg: Message) -> io::Result<()> {
+        let frame = match msg {
+            Message::Text(t) => Frame::new(Opcode::Text, t.into_bytes()),
+            Message::Binary(b) => Frame::new(Opcode::Binary, b),
+            Message::Ping(p) => Frame::new(Opcode::Ping, p),
+            Message::Pong(p) => Frame::new(Opcode::Pong, p),
+            Message::Close(c) => return self.close(c),
+        };
+        self.write_frame(&frame)
+    }
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send(Message::Text(text.to_string()))
+    }
+    /// Sends a message split into continuation frames of at most `chunk` bytes each.
+    /// An empty message is a single final frame.
+    pub fn send_fragmented(&mut self, opcode: Opcode, data: &[u8], chunk: usize) -> io::Result<()> {
+        let mut chunks: Vec<&[u8]> = data.chunks(chunk.max(1)).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        let last = chunks.len().saturating_sub(1);
+        for (i, part) in chunks.iter().enumerate() {
+            let frame = Frame {
+                fin: i == last,
+                opcode: if i == 0 { opcode } else { Opcode::Continuation },
+                payload: part.to_vec(),
+            };
+            self.write_frame(&frame)?;
+        }
+        Ok(())
+    }
+    /// Starts the closing handshake; `recv` keeps returning messages until the peer's Close.
+    pub fn close(&mut self, status: Option<(u16, String)>) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        let mut payload = Vec::new();
+        if let Some((code, reason)) = status {
+            payload.extend_from_slice(&code.to_be_bytes());
+            payload.extend_from_slice(reason.as_bytes());
+            payload.truncate(MAX_CONTROL_PAYLOAD);
+        }
+        self.close_sent = true;
+        self.write_frame(&Frame::new(Opcode::Close, payload))
+    }
+
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if self.close_sent && frame.opcode != Opcode::Close {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "websocket is closing"));
+        }
+        self.stream.write_all(&frame.encode(None))?;
+        self.stream.flush()
+    }
+
+    fn fail(&mut self, err: FrameError) -> io::Error {
+        let _ = self.close(Some((err.code, err.reason.to_string())));
+        self.closed = true;
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+
+    /// Receives the next message, answering pings and the closing handshake automatically.
+    /// Pings and pongs are still returned so handlers can observe them.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        loop {
+            if self.closed {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "websocket closed"));
+            }
+            let decoded = Frame::decode(&self.buf, true, self.max_message);
+            let frame = match decoded {
+                Ok(Some((frame, used))) => {
+                    self.buf.drain(..used);
+                    frame
+                }
+                Ok(None) => {
+                    let mut chunk = [0; 4096];
+                    let n = self.stream.read(&mut chunk)?;
+                    if n == 0 {
+                        self.closed = true;
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "peer went away"));
+                    }
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    continue;
+                }
+                Err(e) => return Err(self.fail(e)),
+            };
+            if let Some(msg) = self.handle_frame(frame)? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> io::Result<Option<Message>> {
+        match frame.opcode {
+            Opcode::Ping => {
+                if !self.close_sent {
+                    self.write_frame(&Frame::new(Opcode::Pong, frame.payload.clone()))?;
+                }
+                Ok(Some(Message::Ping(frame.payload)))
+            }
+            Opcode::Pong => Ok(Some(Message::Pong(frame.payload))),
+            Opcode::Close => {
+                let status = match frame.payload.len() {
+                    0 => None,
+                    1 => return Err(self.fail(protocol_error("invalid close payload"))),
+                    _ => {
+                        let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                        let reason = String::from_utf8_lossy(&frame.payload[2..]).into_owned();
+                        Some((code, reason))
+                    }
+                };
+                let echo = status.as_ref().map(|(code, _)| (*code, String::new()));
+                self.close(echo)?;
+                self.closed = true;
+                Ok(Some(Message::Close(status)))
+            }
+            Opcode::Text | Opcode::Binary => {
+                if self.fragments.is_some() {
+                    return Err(self.fail(protocol_error("expected continuation frame")));
+                }
+                if frame.fin {
+                    return self.finish_message(frame.opcode, frame.payload).map(Some);
+                }
+                self.fragments = Some((frame.opcode, frame.payload));
+                Ok(None)
+            }
+            Opcode::Continuation => {
+                let (opcode, mut data) = match self.fragments.take() {
+                    Some(f) => f,
+                    None => return Err(self.fail(protocol_error("unexpected continuation frame"))),
+                };
+                if data.len() + frame.payload.len() > self.max_message {
+                    return Err(self.fail(FrameError {
+                        code: CLOSE_TOO_BIG,
+                        reason: "message too large",
+                    }));
+                }
+                data.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    return self.finish_message(opcode, data).map(Some);
+                }
+                self.fragments = Some((opcode, data));
+                Ok(None)
+            }
+        }
+    }
+
+    fn finish_message(&mut self, opcode: Opcode, data: Vec<u8>) -> io::Result<Message> {
+        if opcode == Opcode::Binary {
+            return Ok(Message::Binary(data));
+        }
+        match String::from_utf8(data) {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => Err(self.fail(FrameError {
+                code: CLOSE_INVALID_DATA,
+                reason: "text message is not valid UTF-8",
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// In-memory duplex stream: reads from `input`, collects writes in `output`.
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    fn socket(frames: &[Frame]) -> WebSocket<Duplex> {
+        let mut input = Vec::new();
+        for f in frames {
+            input.extend(f.encode(Some([1, 2, 3, 4])));
+        }
+        WebSocket::new(Duplex {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        })
+    }
+    fn written(ws: &WebSocket<Duplex>) -> Vec<Frame> {
+        let mut out = &ws.get_ref().output[..];
+        let mut frames = Vec::new();
+        while let Some((f, used)) = Frame::decode(out, false, usize::MAX).unwrap() {
+            frames.push(f);
+            out = &out[used..];
+        }
+        frames
+    }
+
+    #[test]
+    fn test_accept_key_rfc_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_validate_handshake() {
+        let raw = "GET /ws/orders HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let req: HttpRequest = raw.to_string().into();
+        assert_eq!(validate_handshake(&req).unwrap(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let bad_key = raw.replace("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=");
+        let req: HttpRequest = bad_key.into();
+        assert_eq!(validate_handshake(&req), Err(HandshakeError::InvalidKey));
+
+        let no_upgrade = raw.replace("Upgrade: websocket\r\n", "");
+        let req: HttpRequest = no_upgrade.into();
+        assert_eq!(validate_handshake(&req), Err(HandshakeError::MissingUpgrade));
+    }
+
+    #[test]
+    fn test_frame_roundtrip_lengths() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let frame = Frame::new(Opcode::Binary, vec![7; len]);
+            let bytes = frame.encode(Some([9, 8, 7, 6]));
+            let (decoded, used) = Frame::decode(&bytes, true, usize::MAX).unwrap().unwrap();
+            assert_eq!(decoded, frame);
+            assert_eq!(used, bytes.len());
+            assert_eq!(Frame::decode(&bytes[..bytes.len() - 1], true, usize::MAX), Ok(None));
+        }
+    }
+
+    #[test]
+    fn test_unmasked_client_frame_rejected() {
+        let bytes = Frame::new(Opcode::Text, b"hi".to_vec()).encode(None);
+        let err = Frame::decode(&bytes, true, 1024).unwrap_err();
+        assert_eq!(err.code, CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn test_fragmented_message_with_interleaved_ping() {
+        let mut ws = socket(&[
+            Frame {
+                fin: false,
+                opcode: Opcode::Text,
+                payload: b"Hel".to_vec(),
+            },
+            Frame::new(Opcode::Ping, b"p".to_vec()),
+            Frame {
+                fin: true,
+                opcode: Opcode::Continuation,
+                payload: b"lo".to_vec(),
+            },
+        ]);
+        assert_eq!(ws.recv().unwrap(), Message::Ping(b"p".to_vec()));
+        assert_eq!(ws.recv().unwrap(), Message::Text("Hello".into()));
+        assert_eq!(written(&ws), vec![Frame::new(Opcode::Pong, b"p".to_vec())]);
+    }
+
+    #[test]
+    fn test_close_handshake_echoes_code() {
+        let mut payload = CLOSE_GOING_AWAY.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        let mut ws = socket(&[Frame::new(Opcode::Close, payload)]);
+        assert_eq!(
+            ws.recv().unwrap(),
+            Message::Close(Some((CLOSE_GOING_AWAY, "bye".into())))
+        );
+        assert!(ws.is_closed());
+        assert_eq!(
+            written(&ws),
+            vec![Frame::new(Opcode::Close, CLOSE_GOING_AWAY.to_be_bytes().to_vec())]
+        );
+        assert!(ws.send_text("late").is_err());
+    }
+
+    #[test]
+    fn test_invalid_utf8_closes_with_1007() {
+        let mut ws = socket(&[Frame::new(Opcode::Text, vec![0xff, 0xfe])]);
+        assert!(ws.recv().is_err());
+        let frames = written(&ws);
+        assert_eq!(frames[0].opcode, Opcode::Close);
+        assert_eq!(frames[0].payload[..2], CLOSE_INVALID_DATA.to_be_bytes());
+    }
+
+    #[test]
+    fn test_send_fragmented() {
+        let mut ws = socket(&[]);
+        ws.send_fragmented(Opcode::Text, b"abcde", 2).unwrap();
+        let frames = written(&ws);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].opcode, Opcode::Text);
+        assert!(!frames[0].fin);
+        assert_eq!(frames[2].opcode, Opcode::Continuation);
+        assert!(frames[2].fin);
+
+        let mut ws = socket(&[]);
+        ws.send_fragmented(Opcode::Binary, b"", 2).unwrap();
+        assert_eq!(written(&ws), vec![Frame::new(Opcode::Binary, Vec::new())]);
+    }
+}
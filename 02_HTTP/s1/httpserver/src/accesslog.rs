@@ -0,0 +1,441 @@
+//! The access log: one line per request in a file, in Common or Combined Log Format
+//! or as JSON, rotated by size or time and reopened on request for logrotate.
+
+use crate::config::{AccessLogFormat, RotateEvery};
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::warn;
+
+/// What is logged of one request.
+#[derive(Debug, Clone)]
+pub struct Record<'a> {
+    pub time: DateTime<FixedOffset>,
+    pub client: Option<IpAddr>,
+    /// The authenticated user.
+    pub user: Option<&'a str>,
+    pub method: &'a str,
+    /// The path and query as requested.
+    pub target: &'a str,
+    /// `None` for versions other than HTTP/1.1 and HTTP/2.
+    pub version: Option<&'a str>,
+    /// `None` if no response was sent.
+    pub status: Option<u16>,
+    /// Bytes sent, headers included.
+    pub bytes: u64,
+    pub host: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub duration: Duration,
+}
+impl Record<'_> {
+    /// The line for this request, without the newline.
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.common(),
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                self.common(),
+                self.referer.map(escape).unwrap_or_else(|| "-".into()),
+                self.user_agent.map(escape).unwrap_or_else(|| "-".into())
+            ),
+            AccessLogFormat::Json => json!({
+                "time": self.time.to_rfc3339_opts(SecondsFormat::Millis, false),
+                "client": self.client.map(|ip| ip.to_string()),
+                "user": self.user,
+                "method": self.method,
+                "target": self.target,
+                "version": self.version,
+                "status": self.status,
+                "bytes": self.bytes,
+                "host": self.host,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "duration_ms": self.duration.as_secs_f64() * 1000.0,
+            })
+            .to_string(),
+        }
+    }
+    /// `client - user [time] "request line" status bytes`
+    fn common(&self) -> String {
+        let dash = |s: Option<String>| s.unwrap_or_else(|| "-".into());
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {}",
+            dash(self.client.map(|ip| ip.to_string())),
+            dash(self.user.map(escape).filter(|user| !user.is_empty())),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(self.method),
+            escape(self.target),
+            self.version.unwrap_or("-"),
+            dash(self.status.map(|status| status.to_string())),
+            if self.bytes == 0 { "-".to_string() } else { self.bytes.to_string() }
+        )
+    }
+}
+
+/// Escapes quotes, backslashes and control characters so that a field can't end
+/// its quotes early or start a line of its own, as Apache does.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Asks the access log to reopen its file, e.g. after logrotate moved it away.
+/// The file is reopened before the next line is written.
+#[derive(Clone, Default)]
+pub struct ReopenHandle {
+    requested: Arc<AtomicBool>,
+}
+impl ReopenHandle {
+    pub fn reopen(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+    fn take_request(&self) -> bool {
+        self.requested.swap(false, Ordering::SeqCst)
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    /// The hour or day the lines in the file are from, when rotating by time.
+    period: String,
+    /// When the last line was written, which the file is named after once rotated.
+    last_written: Option<DateTime<FixedOffset>>,
+    /// Compressing the file rotated last.
+    compressing: Option<JoinHandle<()>>,
+}
+
+pub struct AccessLog {
+    path: PathBuf,
+    format: AccessLogFormat,
+    max_size: Option<u64>,
+    rotate: Option<RotateEvery>,
+    compress: bool,
+    /// How many rotated files are kept.
+    keep: usize,
+    reopen: ReopenHandle,
+    file: Mutex<LogFile>,
+}
+impl AccessLog {
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn open(path: &Path, format: AccessLogFormat) -> io::Result<AccessLog> {
+        let file = Self::open_file(path, None)?;
+        Ok(AccessLog {
+            path: path.to_path_buf(),
+            format,
+            max_size: None,
+            rotate: None,
+            compress: false,
+            keep: 0,
+            reopen: ReopenHandle::default(),
+            file: Mutex::new(file),
+        })
+    }
+    /// Rotates the file before it grows past `max_size` bytes.
+    pub fn max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+    /// Rotates the file when the first line of a new hour or day is written.
+    pub fn rotate(mut self, every: Option<RotateEvery>) -> Self {
+        self.file.get_mut().unwrap().period = Self::period_of_file(&self.path, every);
+        self.rotate = every;
+        self
+    }
+    /// Gzips rotated files.
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+    /// How many rotated files to keep; older ones are deleted.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+    pub fn reopen_handle(&self) -> ReopenHandle {
+        self.reopen.clone()
+    }
+
+    fn open_file(path: &Path, rotate: Option<RotateEvery>) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            size: file.metadata()?.len(),
+            file,
+            period: Self::period_of_file(path, rotate),
+            last_written: Self::last_written(path),
+            compressing: None,
+        })
+    }
+    /// When the file at `path` was last written, if it has lines in it.
+    fn last_written(path: &Path) -> Option<DateTime<FixedOffset>> {
+        let modified = fs::metadata(path).ok().filter(|m| m.len() > 0).and_then(|m| m.modified().ok())?;
+        Some(DateTime::<Local>::from(modified).fixed_offset())
+    }
+    /// The period of the last line in the file at `path`, taken from when it was last written,
+    /// so that a file left from yesterday is rotated after a restart.
+    fn period_of_file(path: &Path, rotate: Option<RotateEvery>) -> String {
+        let time = Self::last_written(path).unwrap_or_else(|| Local::now().fixed_offset());
+        period(time, rotate)
+    }
+
+    /// Writes the line for `record`, rotating the file first if it is due.
+    pub fn log(&self, record: &Record) {
+        let mut line = record.format(self.format);
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        if self.reopen.take_request() {
+            match Self::open_file(&self.path, self.rotate) {
+                Ok(reopened) => *file = reopened,
+                Err(e) => warn!(error = %e, path = %self.path.display(), "failed to reopen the access log"),
+            }
+        }
+        let period = period(record.time, self.rotate);
+        let too_big = self.max_size.is_some_and(|max| file.size > 0 && file.size + line.len() as u64 > max);
+        // An empty file is kept, whatever period it was started in.
+        if too_big || (period != file.period && file.size > 0) {
+            if let Err(e) = self.rotate_file(&mut file, record.time) {
+                warn!(error = %e, path = %self.path.display(), "failed to rotate the access log");
+            }
+        }
+        file.period = period;
+        match file.file.write_all(line.as_bytes()) {
+            Ok(()) => {
+                file.size += line.len() as u64;
+                file.last_written = Some(record.time);
+            }
+            Err(e) => warn!(error = %e, path = %self.path.display(), "failed to write the access log"),
+        }
+    }
+
+    /// Moves the file aside under the time of its last line, so that `access.log.<stamp>`
+    /// holds the lines up to `<stamp>`, starts a new one, and compresses the old one and
+    /// deletes those past the retention count in the background.
+    fn rotate_file(&self, file: &mut LogFile, time: DateTime<FixedOffset>) -> io::Result<()> {
+        if let Some(previous) = file.compressing.take() {
+            let _ = previous.join();
+        }
+        let stamp = file.last_written.unwrap_or(time).format("%Y%m%d-%H%M%S").to_string();
+        let mut rotated = self.rotated_path(&stamp);
+        // Several rotations within a second, when the size limit is small.
+        let mut n = 1;
+        while rotated.exists() || gz_path(&rotated).exists() {
+            rotated = self.rotated_path(&format!("{}-{}", stamp, n));
+            n += 1;
+        }
+        fs::rename(&self.path, &rotated)?;
+        *file = Self::open_file(&self.path, self.rotate)?;
+        let (path, compress, keep) = (self.path.clone(), self.compress, self.keep);
+        file.compressing = Some(thread::spawn(move || {
+            if compress {
+                if let Err(e) = gzip(&rotated) {
+                    warn!(error = %e, path = %rotated.display(), "failed to compress the access log");
+                }
+            }
+            if let Err(e) = prune(&path, keep) {
+                warn!(error = %e, path = %path.display(), "failed to delete old access logs");
+            }
+        }));
+        Ok(())
+    }
+    fn rotated_path(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", suffix));
+        self.path.with_file_name(name)
+    }
+    /// Waits for the compression of the file rotated last.
+    #[cfg(test)]
+    fn finish_rotation(&self) {
+        if let Some(compressing) = self.file.lock().unwrap().compressing.take() {
+            compressing.join().unwrap();
+        }
+    }
+}
+
+/// The hour or day `time` falls in, or nothing when not rotating by time.
+fn period(time: DateTime<FixedOffset>, rotate: Option<RotateEvery>) -> String {
+    match rotate {
+        Some(RotateEvery::Hourly) => time.format("%Y%m%d%H").to_string(),
+        Some(RotateEvery::Daily) => time.format("%Y%m%d").to_string(),
+        None => String::new(),
+    }
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+/// Replaces the file at `path` with a gzipped copy. The copy is written under a temporary
+/// name first, so that an interrupted compression doesn't leave a truncated `.gz` behind.
+fn gzip(path: &Path) -> io::Result<()> {
+    let gz = gz_path(path);
+    let partial = gz.with_extension("gz.partial");
+    let mut encoder = GzEncoder::new(File::create(&partial)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&partial, &gz)?;
+    fs::remove_file(path)
+}
+
+/// Deletes all but the newest `keep` rotated copies of the log at `path`.
+fn prune(path: &Path, keep: usize) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{}.", path.file_name().unwrap_or_default().to_string_lossy());
+    let mut rotated: Vec<(String, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let stamp = match name.strip_prefix(&prefix) {
+            Some(stamp) if !stamp.ends_with(".partial") => stamp.trim_end_matches(".gz").to_string(),
+            _ => continue,
+        };
+        // Only names this module gave, `YYYYmmdd-HHMMSS` with an optional `-n`.
+        if stamp.len() >= 15 && stamp.bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+            rotated.push((stamp, entry.path()));
+        }
+    }
+    rotated.sort_by(|a, b| {
+        let key = |stamp: &str| {
+            let (time, n) = stamp.split_at(15);
+            (time.to_string(), n.trim_start_matches('-').parse::<u32>().unwrap_or(0))
+        };
+        key(&b.0).cmp(&key(&a.0))
+    });
+    for (_, path) in rotated.into_iter().skip(keep) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::{env, process};
+
+    fn record(time: &str) -> Record<'static> {
+        Record {
+            time: DateTime::parse_from_rfc3339(time).unwrap(),
+            client: Some("192.0.2.1".parse().unwrap()),
+            user: Some("alice"),
+            method: "GET",
+            target: "/api/shipping/orders?status=\"shipped\"",
+            version: Some("HTTP/1.1"),
+            status: Some(200),
+            bytes: 1234,
+            host: Some("localhost"),
+            referer: None,
+            user_agent: Some("curl/8.5.0"),
+            duration: Duration::from_micros(2500),
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        let record = record("2026-10-19T13:55:36-07:00");
+        assert_eq!(
+            record.format(AccessLogFormat::Common),
+            "192.0.2.1 - alice [19/Oct/2026:13:55:36 -0700] \"GET /api/shipping/orders?status=\\\"shipped\\\" HTTP/1.1\" 200 1234"
+        );
+        assert!(record.format(AccessLogFormat::Combined).ends_with(" 200 1234 \"-\" \"curl/8.5.0\""));
+        let json: serde_json::Value = serde_json::from_str(&record.format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(json["time"], "2026-10-19T13:55:36.000-07:00");
+        assert_eq!((&json["status"], &json["referer"], &json["duration_ms"]), (&json!(200), &json!(null), &json!(2.5)));
+        let unanswered = Record {
+            client: None,
+            user: None,
+            status: None,
+            bytes: 0,
+            user_agent: Some("evil\n127.0.0.1 - admin"),
+            version: None,
+            ..record
+        };
+        assert_eq!(
+            unanswered.format(AccessLogFormat::Combined),
+            "- - - [19/Oct/2026:13:55:36 -0700] \"GET /api/shipping/orders?status=\\\"shipped\\\" -\" - - \"-\" \"evil\\x0a127.0.0.1 - admin\""
+        );
+        let json: serde_json::Value = serde_json::from_str(&unanswered.format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(json["version"], json!(null));
+    }
+
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("httpserver-accesslog-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_rotates_by_size_and_keeps_the_newest() {
+        let dir = log_dir("size");
+        let path = dir.join("access.log");
+        let log = AccessLog::open(&path, AccessLogFormat::Common).unwrap().max_size(Some(250)).compress(true).keep(2);
+        let line_len = record("2026-10-19T10:00:00Z").format(AccessLogFormat::Common).len() + 1;
+        for i in 0..8 {
+            log.log(&record(&format!("2026-10-19T10:00:0{}Z", i / 4)));
+            log.finish_rotation();
+        }
+        // Two lines fit in 250 bytes, so the eight lines were rotated three times, into
+        // files named after their last line, two of them ending within the same second.
+        assert_eq!(
+            files(&dir),
+            ["access.log", "access.log.20261019-100000-1.gz", "access.log.20261019-100001.gz"]
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap().len(), 2 * line_len);
+        let mut lines = String::new();
+        GzDecoder::new(File::open(dir.join("access.log.20261019-100001.gz")).unwrap()).read_to_string(&mut lines).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        assert!(lines.starts_with("192.0.2.1 - alice [19/Oct/2026:10:00:01 +0000]"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotates_by_time_and_reopens() {
+        let dir = log_dir("time");
+        let path = dir.join("access.log");
+        let log = AccessLog::open(&path, AccessLogFormat::Json).unwrap().rotate(Some(RotateEvery::Hourly)).keep(5);
+        log.log(&record("2026-10-19T10:59:59Z"));
+        log.log(&record("2026-10-19T11:00:00Z"));
+        log.log(&record("2026-10-19T11:30:00Z"));
+        log.finish_rotation();
+        // Named after the hour it covers, not the line that started the next one.
+        assert_eq!(files(&dir), ["access.log", "access.log.20261019-105959"]);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        // As logrotate would: move the file away, then ask for it to be reopened.
+        fs::rename(&path, dir.join("moved.log")).unwrap();
+        log.reopen_handle().reopen();
+        log.log(&record("2026-10-19T11:31:00Z"));
+        assert_eq!(fs::read_to_string(dir.join("moved.log")).unwrap().lines().count(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
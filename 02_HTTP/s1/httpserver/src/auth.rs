@@ -0,0 +1,338 @@
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crate::router::under_prefix;
+use http::httprequest::{HttpRequest, Method};
+use http::httpresponse::HttpResponse;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Who made a request, as established by an `Authenticator`. The router stores it
+/// in the request's extensions, where handlers find it with `req.extensions.get::<Identity>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    /// The user name, or the token's `sub` claim.
+    pub subject: String,
+    pub scheme: Scheme,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    Basic,
+    Bearer,
+}
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Basic => "Basic",
+            Scheme::Bearer => "Bearer",
+        }
+    }
+}
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Checks the credentials of one `Authorization` scheme.
+pub trait Authenticator: Send + Sync {
+    fn scheme(&self) -> Scheme;
+    /// Verifies the credentials following the scheme name, explaining any failure.
+    fn authenticate(&self, credentials: &str) -> Result<Identity, String>;
+    /// The `WWW-Authenticate` challenge, with the reason credentials were rejected if they were.
+    fn challenge(&self, realm: &str, error: Option<&str>) -> String;
+}
+
+/// HTTP Basic against an htpasswd-style file of `user:hash` lines, where hashes
+/// are bcrypt (`$2y$...`) or argon2 (`$argon2id$...`).
+pub struct BasicAuth {
+    users: HashMap<String, String>,
+}
+impl BasicAuth {
+    pub fn from_htpasswd(contents: &str) -> Result<BasicAuth, String> {
+        let mut users = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| format!("line {}: expected user:hash", i + 1))?;
+            if !(hash.starts_with("$2") || hash.starts_with("$argon2")) {
+                return Err(format!("line {}: only bcrypt and argon2 hashes are supported", i + 1));
+            }
+            users.insert(user.to_string(), hash.to_string());
+        }
+        Ok(BasicAuth { users })
+    }
+    pub fn load(path: &Path) -> Result<BasicAuth, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        BasicAuth::from_htpasswd(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+    fn verify(hash: &str, password: &str) -> bool {
+        if hash.starts_with("$argon2") {
+            PasswordHash::new(hash)
+                .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+                .unwrap_or(false)
+        } else {
+            bcrypt::verify(password, hash).unwrap_or(false)
+        }
+    }
+}
+impl Authenticator for BasicAuth {
+    fn scheme(&self) -> Scheme {
+        Scheme::Basic
+    }
+    fn authenticate(&self, credentials: &str) -> Result<Identity, String> {
+        let decoded = STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or("malformed credentials")?;
+        let (user, password) = decoded.split_once(':').ok_or("malformed credentials")?;
+        match self.users.get(user) {
+            Some(hash) if Self::verify(hash, password) => Ok(Identity {
+                subject: user.to_string(),
+                scheme: Scheme::Basic,
+            }),
+            _ => Err("invalid user name or password".into()),
+        }
+    }
+    fn challenge(&self, realm: &str, _error: Option<&str>) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm)
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+/// Bearer tokens that must be JWTs signed with HS256 (a shared secret) or RS256
+/// (an RSA public key), unexpired, already valid (`nbf`) and, if audiences are
+/// configured, meant for one of them.
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+}
+impl JwtAuth {
+    pub fn hs256(secret: &[u8]) -> Self {
+        JwtAuth::new(DecodingKey::from_secret(secret), Algorithm::HS256)
+    }
+    pub fn rs256(public_key_pem: &[u8]) -> Result<Self, String> {
+        let key = DecodingKey::from_rsa_pem(public_key_pem).map_err(|e| format!("RSA public key: {}", e))?;
+        Ok(JwtAuth::new(key, Algorithm::RS256))
+    }
+    fn new(key: DecodingKey, algorithm: Algorithm) -> Self {
+        let mut validation = Validation::new(algorithm);
+        validation.validate_nbf = true;
+        validation.validate_aud = false;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        JwtAuth { key, validation }
+    }
+    pub fn audience(mut self, audience: &[String]) -> Self {
+        if !audience.is_empty() {
+            self.validation.set_audience(audience);
+            self.validation.validate_aud = true;
+        }
+        self
+    }
+    /// Clock skew allowed when checking `exp` and `nbf`.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.validation.leeway = leeway.as_secs();
+        self
+    }
+}
+impl Authenticator for JwtAuth {
+    fn scheme(&self) -> Scheme {
+        Scheme::Bearer
+    }
+    fn authenticate(&self, credentials: &str) -> Result<Identity, String> {
+        let token = jsonwebtoken::decode::<Claims>(credentials.trim(), &self.key, &self.validation)
+            .map_err(|e| e.to_string())?;
+        Ok(Identity {
+            subject: token.claims.sub,
+            scheme: Scheme::Bearer,
+        })
+    }
+    fn challenge(&self, realm: &str, error: Option<&str>) -> String {
+        match error {
+            // RFC 6750, 3.1
+            Some(error) => format!(
+                "Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"",
+                realm,
+                error.replace(['"', '\\'], "'")
+            ),
+            None => format!("Bearer realm=\"{}\"", realm),
+        }
+    }
+}
+
+/// The authenticators of one host and the path prefixes (and methods) they protect.
+pub struct Auth {
+    realm: String,
+    prefixes: Vec<String>,
+    /// Methods that need credentials; all of them if empty.
+    methods: Vec<Method>,
+    authenticators: Vec<Box<dyn Authenticator>>,
+}
+impl Auth {
+    pub fn new(realm: &str, prefixes: &[String]) -> Self {
+        Auth {
+            realm: realm.to_string(),
+            prefixes: prefixes.iter().map(|p| p.trim_end_matches('/').to_string()).collect(),
+            methods: Vec::new(),
+            authenticators: Vec::new(),
+        }
+    }
+    /// Requires credentials only for these methods, e.g. to leave reads public.
+    pub fn methods(mut self, methods: &[Method]) -> Self {
+        self.methods = methods.to_vec();
+        self
+    }
+    pub fn with(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.authenticators.push(Box::new(authenticator));
+        self
+    }
+    pub fn protects(&self, method: Method, path: &str) -> bool {
+        (self.methods.is_empty() || self.methods.contains(&method))
+            && self.prefixes.iter().any(|prefix| under_prefix(path, prefix))
+    }
+
+    /// The identity behind the request's `Authorization` header, or the 401 to send instead.
+    pub fn authenticate<'a>(&self, req: &HttpRequest) -> Result<Identity, HttpResponse<'a>> {
+        let header = req.header("Authorization").unwrap_or("");
+        let (scheme, credentials) = header.split_once(' ').unwrap_or((header, ""));
+        let authenticator = self
+            .authenticators
+            .iter()
+            .find(|a| a.scheme().as_str().eq_ignore_ascii_case(scheme));
+        match authenticator {
+            Some(authenticator) => authenticator
+                .authenticate(credentials)
+                .map_err(|error| self.unauthorized(Some((authenticator.scheme(), &error)))),
+            None => Err(self.unauthorized(None)),
+        }
+    }
+
+    fn unauthorized<'a>(&self, failure: Option<(Scheme, &str)>) -> HttpResponse<'a> {
+        let challenges: Vec<String> = self
+            .authenticators
+            .iter()
+            .map(|a| {
+                let error = failure.filter(|(scheme, _)| *scheme == a.scheme()).map(|(_, e)| e);
+                a.challenge(&self.realm, error)
+            })
+            .collect();
+        let message = match failure {
+            Some((_, error)) => format!("Authentication failed: {}", error),
+            None => "Authentication required".to_string(),
+        };
+        let mut headers: HashMap<&str, &str> = HashMap::new();
+        headers.insert("Content-Type", "application/json");
+        HttpResponse::new("401", Some(headers), Some(json!({ "error_message": message }).to_string()))
+            .with_header("WWW-Authenticate", &challenges.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use jsonwebtoken::{EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn request(authorization: &str) -> HttpRequest {
+        format!("GET /api/shipping/orders HTTP/1.1\r\nAuthorization: {}\r\n\r\n", authorization).into()
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{}:{}", user, password)))
+    }
+
+    fn token(claims: serde_json::Value) -> String {
+        let token = jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        format!("Bearer {}", token)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn test_basic_with_bcrypt_and_argon2() {
+        let salt = SaltString::from_b64("c29tZXNhbHR2YWx1ZQ").unwrap();
+        let argon = Argon2::default().hash_password(b"hunter2", &salt).unwrap().to_string();
+        let htpasswd = format!("# users\nalice:{}\nbob:{}\n", bcrypt::hash("wonderland", 4).unwrap(), argon);
+        let auth = Auth::new("orders", &["/api".into()]).with(BasicAuth::from_htpasswd(&htpasswd).unwrap());
+
+        let alice = auth.authenticate(&request(&basic("alice", "wonderland"))).unwrap();
+        assert_eq!(alice, Identity { subject: "alice".into(), scheme: Scheme::Basic });
+        assert_eq!(auth.authenticate(&request(&basic("bob", "hunter2"))).unwrap().subject, "bob");
+
+        let denied = auth.authenticate(&request(&basic("alice", "nope"))).unwrap_err();
+        assert_eq!(denied.status_code(), "401");
+        assert_eq!(denied.header("WWW-Authenticate"), Some("Basic realm=\"orders\", charset=\"UTF-8\""));
+        assert!(BasicAuth::from_htpasswd("carol:{SHA}abc").is_err());
+    }
+
+    #[test]
+    fn test_bearer_jwt_checks() {
+        let auth = Auth::new("orders", &["/api".into()])
+            .with(JwtAuth::hs256(b"secret").audience(&["orders-api".into()]));
+        let valid = token(json!({ "sub": "dashboard", "aud": "orders-api", "exp": now() + 60 }));
+        assert_eq!(
+            auth.authenticate(&request(&valid)).unwrap(),
+            Identity { subject: "dashboard".into(), scheme: Scheme::Bearer }
+        );
+        for claims in [
+            json!({ "sub": "a", "aud": "orders-api", "exp": now() - 120 }),
+            json!({ "sub": "a", "aud": "orders-api", "exp": now() + 60, "nbf": now() + 120 }),
+            json!({ "sub": "a", "aud": "elsewhere", "exp": now() + 60 }),
+            json!({ "sub": "a", "aud": "orders-api" }),
+        ] {
+            let denied = auth.authenticate(&request(&token(claims))).unwrap_err();
+            assert!(denied.header("WWW-Authenticate").unwrap().contains("error=\"invalid_token\""));
+        }
+        let forged = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({ "sub": "a", "aud": "orders-api", "exp": now() + 60 }),
+            &EncodingKey::from_secret(b"guess"),
+        )
+        .unwrap();
+        assert!(auth.authenticate(&request(&format!("Bearer {}", forged))).is_err());
+    }
+
+    #[test]
+    fn test_missing_credentials_lists_every_scheme() {
+        let auth = Auth::new("orders", &["/api/".into()])
+            .with(BasicAuth::from_htpasswd("").unwrap())
+            .with(JwtAuth::hs256(b"secret"));
+        let req: HttpRequest = "GET /api HTTP/1.1\r\n\r\n".to_string().into();
+        let denied = auth.authenticate(&req).unwrap_err();
+        assert_eq!(
+            denied.header("WWW-Authenticate"),
+            Some("Basic realm=\"orders\", charset=\"UTF-8\", Bearer realm=\"orders\"")
+        );
+        assert!(auth.protects(Method::GET, "/api") && auth.protects(Method::GET, "/api/shipping/orders"));
+        assert!(!auth.protects(Method::GET, "/apis") && !auth.protects(Method::GET, "/health"));
+    }
+
+    #[test]
+    fn test_protected_methods() {
+        let auth = Auth::new("orders", &["/api".into()]).methods(&[Method::POST, Method::DELETE]);
+        assert!(auth.protects(Method::POST, "/api/shipping/orders"));
+        assert!(auth.protects(Method::DELETE, "/api/shipping/orders/1"));
+        assert!(!auth.protects(Method::GET, "/api/shipping/orders"));
+        assert!(!auth.protects(Method::POST, "/health"));
+    }
+}
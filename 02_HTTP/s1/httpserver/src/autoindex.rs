@@ -0,0 +1,295 @@
+//! Directory listings for hosts with `autoindex` on: an HTML page for browsers,
+//! or JSON for clients that ask for it.
+
+use crate::template::escape_html;
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::form::{percent_decode, percent_encode};
+use http::negotiation;
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+impl SortKey {
+    fn param(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "mtime",
+        }
+    }
+}
+
+/// The order of a listing: `?sort=name`, `size` or `mtime`, descending with a `-` prefix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sort {
+    pub key: SortKey,
+    pub descending: bool,
+}
+impl Default for Sort {
+    fn default() -> Self {
+        Sort {
+            key: SortKey::Name,
+            descending: false,
+        }
+    }
+}
+impl Sort {
+    /// Reads `sort` from the query parameters. Unknown values sort by name.
+    pub fn from_params(params: &[(String, String)]) -> Sort {
+        let value = match params.iter().rev().find(|(name, _)| name == "sort") {
+            Some((_, value)) => value.as_str(),
+            None => return Sort::default(),
+        };
+        let (name, descending) = match value.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (value, false),
+        };
+        let key = match name {
+            "size" => SortKey::Size,
+            "mtime" => SortKey::Modified,
+            _ => SortKey::Name,
+        };
+        Sort { key, descending }
+    }
+    fn param(self) -> String {
+        format!("{}{}", if self.descending { "-" } else { "" }, self.key.param())
+    }
+    /// What the header of column `key` links to: that column ascending, or reversed if already sorted by it.
+    fn toggled(self, key: SortKey) -> Sort {
+        Sort {
+            key,
+            descending: key == self.key && !self.descending,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    /// In bytes; 0 for directories.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// The path below the document root that the request path `url_path` names, decoded,
+/// or `None` if one of its segments is hidden (starts with `.`, which covers `..`) or
+/// contains an encoded separator.
+pub fn relative_path(url_path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    // `+` is a plain character in paths, unlike in query strings.
+    for segment in url_path.split('/').filter(|s| !s.is_empty()) {
+        let segment = percent_decode(&segment.replace('+', "%2B"));
+        if segment.starts_with('.') || segment.contains(['/', '\\']) {
+            return None;
+        }
+        segments.push(segment);
+    }
+    Some(segments.join("/"))
+}
+
+/// Percent-encodes a file name for a link; spaces become `%20` rather than `+`.
+fn encode_segment(name: &str) -> String {
+    percent_encode(name).replace('+', "%20")
+}
+
+/// The entries of `dir`, leaving out hidden ones, broken symlinks and symlinks
+/// leading out of `root`, which must be canonical.
+pub fn read_entries(dir: &Path, root: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        let target = match entry.path().canonicalize() {
+            Ok(target) if target.starts_with(root) => target,
+            _ => continue,
+        };
+        let metadata = match fs::metadata(target) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Sorts directories before files, each group by `sort`, with ties broken by name.
+pub fn sort_entries(entries: &mut [Entry], sort: Sort) {
+    entries.sort_by(|a, b| {
+        let order = match sort.key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let order = if sort.descending { order.reverse() } else { order };
+        b.is_dir.cmp(&a.is_dir).then(order)
+    });
+}
+
+/// Browsers get HTML; JSON is only sent to clients that prefer it.
+pub fn wants_json(accept: Option<&str>) -> bool {
+    negotiation::media_type(accept, &["text/html", "application/json"]) == Some("application/json")
+}
+
+fn format_time(time: Option<SystemTime>, format: &str) -> Option<String> {
+    let time = DateTime::<Utc>::from(time?);
+    Some(match format {
+        "rfc3339" => time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        format => time.format(format).to_string(),
+    })
+}
+
+/// A table of `entries` with column headers that change the order, for the directory at `url_path`.
+pub fn render_html(url_path: &str, entries: &[Entry], sort: Sort) -> String {
+    let title = format!("Index of {}", escape_html(url_path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n<table>\n<tr>",
+        title, title
+    );
+    for (key, label) in [(SortKey::Name, "Name"), (SortKey::Size, "Size"), (SortKey::Modified, "Last modified")] {
+        html.push_str(&format!("<th><a href=\"?sort={}\">{}</a></th>", sort.toggled(key).param(), label));
+    }
+    html.push_str("</tr>\n");
+    if url_path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir { "-".to_string() } else { entry.size.to_string() };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            encode_segment(&entry.name),
+            slash,
+            escape_html(&entry.name),
+            slash,
+            size,
+            format_time(entry.modified, "%Y-%m-%d %H:%M").unwrap_or_default()
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// `{"path": ..., "entries": [{"name", "type", "size", "modified"}]}`, with times in RFC 3339
+/// and a `null` size for directories.
+pub fn render_json(url_path: &str, entries: &[Entry]) -> Value {
+    let entries: Vec<Value> = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "name": entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "size": if entry.is_dir { None } else { Some(entry.size) },
+                "modified": format_time(entry.modified, "rfc3339"),
+            })
+        })
+        .collect();
+    json!({ "path": url_path, "entries": entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(name: &str, is_dir: bool, size: u64, secs: u64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    fn sorted(query: &str) -> Vec<String> {
+        let mut entries = vec![
+            entry("b.txt", false, 10, 300),
+            entry("docs", true, 0, 100),
+            entry("a.txt", false, 30, 200),
+            entry("c.txt", false, 20, 100),
+        ];
+        sort_entries(&mut entries, Sort::from_params(&http::form::parse_urlencoded(query)));
+        names(&entries).iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sort() {
+        assert_eq!(sorted(""), ["docs", "a.txt", "b.txt", "c.txt"]);
+        assert_eq!(sorted("sort=-name"), ["docs", "c.txt", "b.txt", "a.txt"]);
+        assert_eq!(sorted("sort=size"), ["docs", "b.txt", "c.txt", "a.txt"]);
+        assert_eq!(sorted("sort=-mtime"), ["docs", "b.txt", "a.txt", "c.txt"]);
+        assert_eq!(sorted("sort=owner"), sorted("sort=name"));
+        let by_size = Sort::from_params(&[("sort".into(), "size".into())]);
+        assert_eq!(by_size.toggled(SortKey::Size).param(), "-size");
+        assert_eq!(by_size.toggled(SortKey::Name).param(), "name");
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(relative_path("/").as_deref(), Some(""));
+        assert_eq!(relative_path("/docs/my%20notes+v2/").as_deref(), Some("docs/my notes+v2"));
+        assert_eq!(relative_path("/docs/../secret"), None);
+        assert_eq!(relative_path("/%2e%2e/secret"), None);
+        assert_eq!(relative_path("/.git/"), None);
+        assert_eq!(relative_path("/docs%2F..%2F..%2Fetc"), None);
+        assert_eq!(encode_segment("my notes+v2 <b>.txt"), "my%20notes%2Bv2%20%3Cb%3E.txt");
+    }
+
+    #[test]
+    fn test_read_entries_hides_dotfiles() {
+        let dir = std::env::temp_dir().join(format!("httpserver-autoindex-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        fs::write(dir.join(".env"), "SECRET=1").unwrap();
+        let mut entries = read_entries(&dir, &dir.canonicalize().unwrap()).unwrap();
+        sort_entries(&mut entries, Sort::default());
+        assert_eq!(names(&entries), ["sub", "a.txt"]);
+        assert_eq!((entries[0].is_dir, entries[1].size), (true, 5));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_render() {
+        let entries = [entry("docs", true, 0, 0), entry("<script>.txt", false, 7, 86_400)];
+        let html = render_html("/files/", &entries, Sort::default());
+        assert!(html.contains("<title>Index of /files/</title>"));
+        assert!(html.contains("<a href=\"?sort=-name\">Name</a>"));
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(html.contains("<a href=\"docs/\">docs/</a></td><td>-</td>"));
+        assert!(html.contains("<a href=\"%3Cscript%3E.txt\">&lt;script&gt;.txt</a></td><td>7</td><td>1970-01-02 00:00</td>"));
+        assert!(!render_html("/", &entries, Sort::default()).contains("../"));
+        assert_eq!(
+            render_json("/files/", &entries)["entries"],
+            json!([
+                { "name": "docs", "type": "directory", "size": null, "modified": "1970-01-01T00:00:00Z" },
+                { "name": "<script>.txt", "type": "file", "size": 7, "modified": "1970-01-02T00:00:00Z" },
+            ])
+        );
+        assert!(wants_json(Some("application/json")));
+        assert!(!wants_json(Some("text/html,application/json;q=0.9")));
+        assert!(!wants_json(None));
+    }
+}
@@ -0,0 +1,399 @@
+//! A shared cache of the responses handlers mark as cacheable, so that repeated
+//! requests for the same page or list of orders don't go to disk.
+
+use http::httprequest::{HttpRequest, Method, Resource};
+use http::httpresponse::HttpResponse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The `Cache-Control` directives the cache acts on.
+#[derive(Debug, Default, PartialEq)]
+pub struct CacheControl {
+    pub max_age: Option<Duration>,
+    /// `s-maxage`: how long shared caches such as this one may keep the response, instead of `max-age`.
+    pub shared_max_age: Option<Duration>,
+    pub stale_while_revalidate: Duration,
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+}
+impl CacheControl {
+    /// Reads a header value such as `public, max-age=60, stale-while-revalidate=300`.
+    /// Unknown directives and malformed durations are ignored.
+    pub fn parse(value: &str) -> CacheControl {
+        let mut cc = CacheControl::default();
+        for directive in value.split(',') {
+            let (name, arg) = match directive.split_once('=') {
+                Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let secs = arg.and_then(|arg| arg.parse::<u64>().ok()).map(Duration::from_secs);
+            match name.to_ascii_lowercase().as_str() {
+                "max-age" => cc.max_age = secs,
+                "s-maxage" => cc.shared_max_age = secs,
+                "stale-while-revalidate" => cc.stale_while_revalidate = secs.unwrap_or_default(),
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                _ => {}
+            }
+        }
+        cc
+    }
+    /// How long a shared cache may serve the response as fresh, or `None` if it may not store it.
+    fn freshness(&self) -> Option<Duration> {
+        if self.no_store || self.no_cache || self.private {
+            return None;
+        }
+        self.shared_max_age.or(self.max_age).filter(|age| !age.is_zero())
+    }
+}
+
+/// What a lookup found.
+#[derive(Debug)]
+pub enum Lookup {
+    /// A fresh response, or a stale one that another request is already revalidating.
+    Hit(HttpResponse<'static>),
+    /// A response past its `max-age` but within `stale-while-revalidate`: send it,
+    /// then build the response again and `store` it.
+    Stale(HttpResponse<'static>),
+    Miss,
+}
+
+struct Entry {
+    headers: Vec<(String, String)>,
+    body: String,
+    stored: Instant,
+    fresh_for: Duration,
+    stale_for: Duration,
+    /// When the file the response was built from was modified, at the time it was built.
+    source: Option<SystemTime>,
+    size: usize,
+    /// Position in `Entries::lru`.
+    used: u64,
+    revalidating: bool,
+}
+impl Entry {
+    fn response(&self, age: Duration) -> HttpResponse<'static> {
+        let mut resp = HttpResponse::new("200", Some(HashMap::new()), Some(self.body.clone()));
+        for (name, value) in &self.headers {
+            resp.set_header(name, value);
+        }
+        resp.with_header("Age", &age.as_secs().to_string())
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    /// Keys by when they were last used, least recent first.
+    lru: BTreeMap<u64, String>,
+    /// Per method and resource, the request headers its responses vary on
+    /// and how many variants are stored.
+    vary: HashMap<String, (Vec<String>, usize)>,
+    size: usize,
+    clock: u64,
+}
+impl Entries {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+    fn touch(&mut self, key: &str) {
+        let used = self.tick();
+        if let Some(entry) = self.map.get_mut(key) {
+            self.lru.remove(&entry.used);
+            entry.used = used;
+            self.lru.insert(used, key.to_string());
+        }
+    }
+    fn insert(&mut self, base: &str, names: Vec<String>, key: String, mut entry: Entry) {
+        self.remove(&key);
+        // Variants stored under other `Vary` headers can no longer be found.
+        if self.vary.get(base).is_some_and(|(old, _)| *old != names) {
+            let stale: Vec<String> = self.map.keys().filter(|k| base_of(k) == base).cloned().collect();
+            stale.iter().for_each(|k| self.remove(k));
+        }
+        entry.used = self.tick();
+        self.size += entry.size;
+        self.lru.insert(entry.used, key.clone());
+        self.map.insert(key, entry);
+        self.vary.entry(base.to_string()).or_insert((names, 0)).1 += 1;
+    }
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.map.remove(key) {
+            self.size -= entry.size;
+            self.lru.remove(&entry.used);
+            let base = base_of(key);
+            if let Some((_, count)) = self.vary.get_mut(base) {
+                *count -= 1;
+                if *count == 0 {
+                    self.vary.remove(base);
+                }
+            }
+        }
+    }
+    fn evict_to(&mut self, max_size: usize) {
+        while self.size > max_size {
+            match self.lru.first_key_value() {
+                Some((_, key)) => {
+                    let key = key.clone();
+                    self.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// The method and resource part of a key; the rest holds the values of the `Vary` headers.
+fn base_of(key: &str) -> &str {
+    key.split('\n').next().unwrap_or(key)
+}
+
+/// Responses by method, resource (including the query) and the request headers
+/// named in their `Vary`, up to `max_size` bytes, evicting the least recently used.
+/// A response is dropped once the file it was built from changes.
+pub struct ResponseCache {
+    max_size: usize,
+    entries: Mutex<Entries>,
+}
+impl ResponseCache {
+    pub fn new(max_size: usize) -> Self {
+        ResponseCache {
+            max_size,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Only GETs without credentials are answered from the cache.
+    pub fn applies_to(req: &HttpRequest) -> bool {
+        req.method == Method::GET && req.header("Authorization").is_none()
+    }
+
+    fn base_key(req: &HttpRequest) -> String {
+        let Resource::Path(resource) = &req.resource;
+        format!("{} {}", req.method.as_str(), resource)
+    }
+    fn key(base: &str, names: &[String], req: &HttpRequest) -> String {
+        let mut key = base.to_string();
+        for name in names {
+            key.push_str(&format!("\n{}: {}", name, req.header(name).unwrap_or("")));
+        }
+        key
+    }
+    fn request_directives(req: &HttpRequest) -> CacheControl {
+        let mut cc = req.header("Cache-Control").map(CacheControl::parse).unwrap_or_default();
+        cc.no_cache |= req.header("Pragma").is_some_and(|p| p.eq_ignore_ascii_case("no-cache"));
+        cc
+    }
+
+    /// Looks up the response to `req`, built from a file last modified at `source`.
+    /// Clients sending `Cache-Control: no-cache` always get a new response.
+    pub fn lookup(&self, req: &HttpRequest, source: Option<SystemTime>, now: Instant) -> Lookup {
+        let cc = Self::request_directives(req);
+        if cc.no_cache || cc.no_store {
+            return Lookup::Miss;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let base = Self::base_key(req);
+        let key = match entries.vary.get(&base) {
+            Some((names, _)) => Self::key(&base, names, req),
+            None => return Lookup::Miss,
+        };
+        let entry = match entries.map.get_mut(&key) {
+            Some(entry) => entry,
+            None => return Lookup::Miss,
+        };
+        let age = now.saturating_duration_since(entry.stored);
+        if entry.source != source || age >= entry.fresh_for + entry.stale_for {
+            entries.remove(&key);
+            return Lookup::Miss;
+        }
+        let resp = entry.response(age);
+        let revalidate = age >= entry.fresh_for && !entry.revalidating;
+        entry.revalidating |= revalidate;
+        entries.touch(&key);
+        if revalidate {
+            Lookup::Stale(resp)
+        } else {
+            Lookup::Hit(resp)
+        }
+    }
+
+    /// Stores `resp` as the answer to `req` if it is a 200 its `Cache-Control` lets a
+    /// shared cache keep; otherwise drops whatever was stored for `req`.
+    pub fn store(&self, req: &HttpRequest, resp: &HttpResponse, source: Option<SystemTime>, now: Instant) {
+        if Self::request_directives(req).no_store {
+            return;
+        }
+        let cc = resp.header("Cache-Control").map(CacheControl::parse).unwrap_or_default();
+        let names: Vec<String> = resp
+            .header("Vary")
+            .unwrap_or("")
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        let base = Self::base_key(req);
+        let mut entries = self.entries.lock().unwrap();
+        let fresh_for = match cc.freshness() {
+            Some(fresh_for) if resp.status_code() == "200" && !names.iter().any(|n| n == "*") => fresh_for,
+            _ => {
+                if let Some((names, _)) = entries.vary.get(&base) {
+                    let key = Self::key(&base, names, req);
+                    entries.remove(&key);
+                }
+                return;
+            }
+        };
+        let key = Self::key(&base, &names, req);
+        let headers: Vec<(String, String)> = resp
+            .header_pairs()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("Age"))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let size = key.len() + resp.body().len() + headers.iter().map(|(n, v)| n.len() + v.len()).sum::<usize>();
+        // The old response, now stale and marked as revalidating, mustn't outlive this one.
+        if size > self.max_size {
+            entries.remove(&key);
+            return;
+        }
+        let entry = Entry {
+            headers,
+            body: resp.body().to_string(),
+            stored: now,
+            fresh_for,
+            stale_for: cc.stale_while_revalidate,
+            source,
+            size,
+            used: 0,
+            revalidating: false,
+        };
+        entries.insert(&base, names, key, entry);
+        entries.evict_to(self.max_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(path: &str, headers: &str) -> HttpRequest {
+        HttpRequest::from(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n", path, headers))
+    }
+
+    fn page<'a>(body: &str, cache_control: &'a str) -> HttpResponse<'a> {
+        HttpResponse::new("200", None, Some(body.to_string())).with_header("Cache-Control", cache_control)
+    }
+
+    fn body(lookup: Lookup) -> Option<String> {
+        match lookup {
+            Lookup::Hit(resp) => Some(resp.body().to_string()),
+            Lookup::Stale(resp) => Some(format!("stale {}", resp.body())),
+            Lookup::Miss => None,
+        }
+    }
+
+    #[test]
+    fn test_parse_cache_control() {
+        let cc = CacheControl::parse("public, max-age=60, S-MAXAGE=\"10\", stale-while-revalidate=30, x-custom");
+        assert_eq!(cc.max_age, Some(Duration::from_secs(60)));
+        assert_eq!(cc.freshness(), Some(Duration::from_secs(10)));
+        assert_eq!(cc.stale_while_revalidate, Duration::from_secs(30));
+        assert_eq!(CacheControl::parse("max-age=60, no-store").freshness(), None);
+        assert_eq!(CacheControl::parse("max-age=0").freshness(), None);
+        assert_eq!(CacheControl::parse("max-age=soon").freshness(), None);
+    }
+
+    #[test]
+    fn test_freshness_age_and_stale_while_revalidate() {
+        let cache = ResponseCache::new(1 << 20);
+        let req = get("/styles.css?v=1", "");
+        let t0 = Instant::now();
+        let modified = Some(SystemTime::UNIX_EPOCH);
+        cache.store(&req, &page("body {}", "max-age=10, stale-while-revalidate=20"), modified, t0);
+        match cache.lookup(&req, modified, t0 + Duration::from_secs(4)) {
+            Lookup::Hit(resp) => {
+                assert_eq!(resp.header("Age"), Some("4"));
+                assert_eq!(resp.header("Cache-Control"), Some("max-age=10, stale-while-revalidate=20"));
+                assert_eq!(resp.header("Content-Type"), Some("text/html"));
+            }
+            other => panic!("expected a hit, got {:?}", other),
+        }
+        assert_eq!(body(cache.lookup(&get("/styles.css", ""), modified, t0)), None);
+        // Only the first request after it goes stale revalidates.
+        let later = t0 + Duration::from_secs(15);
+        assert_eq!(body(cache.lookup(&req, modified, later)).as_deref(), Some("stale body {}"));
+        assert_eq!(body(cache.lookup(&req, modified, later)).as_deref(), Some("body {}"));
+        cache.store(&req, &page("body { margin: 0 }", "max-age=10"), modified, later);
+        assert_eq!(body(cache.lookup(&req, modified, later)).as_deref(), Some("body { margin: 0 }"));
+        assert_eq!(body(cache.lookup(&req, modified, later + Duration::from_secs(10))), None);
+    }
+
+    #[test]
+    fn test_not_stored_or_invalidated() {
+        let cache = ResponseCache::new(1 << 20);
+        let req = get("/api/shipping/orders", "");
+        let t0 = Instant::now();
+        let v1 = Some(SystemTime::UNIX_EPOCH);
+        let v2 = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1));
+        for resp in [page("[]", "no-store"), page("[]", "private, max-age=60"), HttpResponse::new("404", None, None)] {
+            cache.store(&req, &resp, v1, t0);
+            assert_eq!(body(cache.lookup(&req, v1, t0)), None);
+        }
+        cache.store(&req, &page("[]", "max-age=60"), v1, t0);
+        assert_eq!(body(cache.lookup(&get("/api/shipping/orders", "Cache-Control: no-cache\r\n"), v1, t0)), None);
+        assert_eq!(body(cache.lookup(&req, v1, t0)).as_deref(), Some("[]"));
+        // The orders file changed.
+        assert_eq!(body(cache.lookup(&req, v2, t0)), None);
+        assert_eq!(body(cache.lookup(&req, v1, t0)), None);
+        // A response that may no longer be cached replaces the stored one.
+        cache.store(&req, &page("[]", "max-age=60"), v1, t0);
+        cache.store(&req, &HttpResponse::new("500", None, None), v1, t0);
+        assert_eq!(body(cache.lookup(&req, v1, t0)), None);
+        assert!(!ResponseCache::applies_to(&get("/", "Authorization: Basic YTpi\r\n")));
+    }
+
+    #[test]
+    fn test_vary() {
+        let cache = ResponseCache::new(1 << 20);
+        let t0 = Instant::now();
+        let html = get("/docs/", "Accept: text/html\r\n");
+        let json = get("/docs/", "Accept: application/json\r\n");
+        cache.store(&html, &page("<ul>", "max-age=60").with_header("Vary", "Accept"), None, t0);
+        assert_eq!(body(cache.lookup(&json, None, t0)), None);
+        cache.store(&json, &page("[]", "max-age=60").with_header("Vary", "Accept"), None, t0);
+        assert_eq!(body(cache.lookup(&html, None, t0)).as_deref(), Some("<ul>"));
+        assert_eq!(body(cache.lookup(&json, None, t0)).as_deref(), Some("[]"));
+        // Changing what the resource varies on drops the other variants.
+        cache.store(&json, &page("{}", "max-age=60"), None, t0);
+        assert_eq!(body(cache.lookup(&html, None, t0)).as_deref(), Some("{}"));
+        assert_eq!(cache.entries.lock().unwrap().map.len(), 1);
+        cache.store(&html, &page("<ul>", "max-age=60").with_header("Vary", "*"), None, t0);
+        assert_eq!(body(cache.lookup(&html, None, t0)), None);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let t0 = Instant::now();
+        let size = |path: &str| format!("GET {}", path).len() + 1000 + "Content-Type".len() + "text/html".len() + "Cache-Control".len() + "max-age=60".len();
+        let cache = ResponseCache::new(size("/a") * 2);
+        let body_of = "x".repeat(1000);
+        for path in ["/a", "/b"] {
+            cache.store(&get(path, ""), &page(&body_of, "max-age=60"), None, t0);
+        }
+        assert!(body(cache.lookup(&get("/a", ""), None, t0)).is_some());
+        cache.store(&get("/c", ""), &page(&body_of, "max-age=60"), None, t0);
+        assert!(body(cache.lookup(&get("/b", ""), None, t0)).is_none());
+        assert!(body(cache.lookup(&get("/a", ""), None, t0)).is_some());
+        assert!(body(cache.lookup(&get("/c", ""), None, t0)).is_some());
+        assert_eq!(cache.entries.lock().unwrap().size, size("/a") * 2);
+        cache.store(&get("/big", ""), &page(&"x".repeat(10_000), "max-age=60"), None, t0);
+        assert!(body(cache.lookup(&get("/big", ""), None, t0)).is_none());
+        assert!(body(cache.lookup(&get("/a", ""), None, t0)).is_some());
+        cache.store(&get("/a", ""), &page(&"x".repeat(10_000), "max-age=60"), None, t0);
+        assert!(body(cache.lookup(&get("/a", ""), None, t0)).is_none());
+    }
+}
@@ -0,0 +1,596 @@
+use crate::accesslog::AccessLog;
+use crate::auth::{Auth, BasicAuth, JwtAuth};
+use crate::cache::ResponseCache;
+use crate::cors::{is_local_origin, AllowOrigin, CorsPolicy};
+use crate::handler::{OrderEventsHandler, OrderUpdatesHandler, Site};
+use crate::listener::{self, ProxyProtocol};
+use crate::proxy::ProxyHandler;
+use crate::ratelimit::{Limit, RateLimiter};
+use crate::router::Router;
+use crate::vhost::VirtualHosts;
+use http::httprequest::Method;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// HS256 secrets that are examples rather than secrets, matched case-insensitively.
+const PLACEHOLDER_SECRETS: [&str; 4] = ["change-me", "changeme", "secret", "example"];
+/// RFC 7518, 3.2: an HS256 key must be at least as long as the hash, 256 bits.
+const MIN_SECRET_LEN: usize = 32;
+
+/// Server settings, read from the JSON file at `CONFIG_PATH`
+/// (by default `config.json` next to `Cargo.toml`).
+#[derive(Debug, Deserialize)]
+pub struct ServerConfig {
+    /// `host:port`, or `unix:/path/to.sock` for a Unix domain socket. Ignored when
+    /// systemd passes listening sockets (`LISTEN_FDS`).
+    #[serde(default = "default_address")]
+    pub address: String,
+    /// Octal permissions of a Unix domain socket, such as `"660"`.
+    #[serde(default)]
+    pub socket_mode: Option<String>,
+    /// Whether connections to `address` start with a PROXY protocol header from a load balancer.
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocol,
+    /// Further addresses to listen on, each with its own settings.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub default_host: HostConfig,
+    #[serde(default)]
+    pub virtual_hosts: Vec<VirtualHostConfig>,
+    /// Development mode: pages are re-read from disk when their templates change.
+    #[serde(default)]
+    pub dev_mode: bool,
+    /// Where the Prometheus metrics are served on every host; `null` turns them off.
+    #[serde(default = "default_metrics_path")]
+    pub metrics_path: Option<String>,
+    #[serde(default)]
+    pub log: LogConfig,
+    /// How long a shutdown waits for in-flight connections before exiting anyway.
+    #[serde(default = "default_shutdown_timeout_ms")]
+    pub shutdown_timeout_ms: u64,
+    /// Accept HTTP/2 over cleartext, by prior knowledge or `Upgrade: h2c`.
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+    /// Directory relative paths in the file are resolved against.
+    #[serde(skip)]
+    base_dir: PathBuf,
+}
+
+/// An address to listen on besides the main one. Ignored, like `address`, when systemd
+/// passes listening sockets.
+#[derive(Debug, Deserialize)]
+pub struct ListenerConfig {
+    pub address: String,
+    #[serde(default)]
+    pub socket_mode: Option<String>,
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocol,
+}
+impl ListenerConfig {
+    pub fn socket_mode(&self) -> Result<Option<u32>, String> {
+        self.socket_mode.as_deref().map(listener::parse_mode).transpose()
+    }
+}
+
+/// Settings of one host: its files, error pages and routes.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HostConfig {
+    /// Defaults to `PUBLIC_PATH`, or `public/` next to `Cargo.toml`.
+    pub document_root: Option<String>,
+    /// Status code to file name in the document root, e.g. `"404": "404.html"`.
+    pub error_pages: HashMap<String, String>,
+    /// Serve the shipping-orders API and its live update feeds.
+    pub orders_api: bool,
+    pub proxies: Vec<ProxyConfig>,
+    /// Require credentials for some of the host's paths.
+    pub auth: Option<AuthConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Cross-origin access, per route group.
+    pub cors: Vec<CorsConfig>,
+    /// List directories that have no `index.html`.
+    pub autoindex: bool,
+    /// Keep responses in memory as their `Cache-Control` allows.
+    pub cache: Option<CacheConfig>,
+}
+impl Default for HostConfig {
+    fn default() -> Self {
+        HostConfig {
+            document_root: None,
+            error_pages: HashMap::new(),
+            orders_api: true,
+            proxies: Vec::new(),
+            auth: None,
+            rate_limit: None,
+            cors: Vec::new(),
+            autoindex: false,
+            cache: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// Path prefixes that need credentials, e.g. `/api`.
+    #[serde(default = "default_auth_paths")]
+    pub paths: Vec<String>,
+    /// Methods that need credentials, e.g. only writes; all of them if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// A file of `user:hash` lines with bcrypt or argon2 hashes, for HTTP Basic.
+    pub htpasswd: Option<String>,
+    /// Accept JWTs as bearer tokens.
+    pub jwt: Option<JwtConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm,
+    /// The environment variable holding the shared HS256 secret.
+    pub secret_env: Option<String>,
+    /// A file holding the shared HS256 secret, kept out of version control.
+    pub secret_file: Option<String>,
+    /// A PEM file with the RS256 public key.
+    pub public_key_file: Option<String>,
+    /// If not empty, tokens must carry one of these in `aud`.
+    #[serde(default)]
+    pub audience: Vec<String>,
+    /// Clock skew allowed when checking `exp` and `nbf`.
+    #[serde(default = "default_leeway_secs")]
+    pub leeway_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// A level such as `info`, or `tracing` filter directives such as `httpserver=debug,warn`.
+    pub level: String,
+    pub format: LogFormat,
+    /// A file to log every request to, besides the log on stdout.
+    pub access: Option<AccessLogConfig>,
+}
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".into(),
+            format: LogFormat::Text,
+            access: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, for log pipelines.
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccessLogConfig {
+    /// Relative to the config file. Rotated copies are written next to it.
+    pub path: String,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// Rotate the file before it grows past this size.
+    pub max_size_bytes: Option<u64>,
+    /// Rotate the file at the start of every hour or day.
+    pub rotate: Option<RotateEvery>,
+    /// Gzip rotated copies.
+    #[serde(default = "default_access_log_compress")]
+    pub compress: bool,
+    /// How many rotated copies to keep.
+    #[serde(default = "default_access_log_keep")]
+    pub keep: usize,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Common Log Format: `client - user [time] "request line" status bytes`.
+    Common,
+    /// Common Log Format followed by the quoted referer and user agent.
+    #[default]
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotateEvery {
+    Hourly,
+    Daily,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimitConfig {
+    /// The limit of paths no route matches; unlimited if not given.
+    pub default: Option<LimitConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteLimitConfig>,
+    /// Tell clients apart by this header, e.g. `X-Forwarded-For`, instead of
+    /// their address. Only used on connections from `trusted_proxies`.
+    pub key_header: Option<String>,
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Also trust `key_header` on Unix socket connections, which have no address.
+    #[serde(default)]
+    pub trust_unix_peers: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RouteLimitConfig {
+    pub prefix: String,
+    #[serde(flatten)]
+    pub limit: LimitConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LimitConfig {
+    pub requests: u32,
+    #[serde(default = "default_period_secs")]
+    pub period_secs: u64,
+    /// Defaults to `requests`.
+    pub burst: Option<u32>,
+}
+impl From<&LimitConfig> for Limit {
+    fn from(config: &LimitConfig) -> Limit {
+        Limit {
+            requests: config.requests,
+            period: Duration::from_secs(config.period_secs),
+            burst: config.burst.unwrap_or(config.requests),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CorsConfig {
+    #[serde(default = "default_cors_prefix")]
+    pub prefix: String,
+    /// Exact origins such as `https://shop.example.com`, suffixes such as
+    /// `*.example.com`, or `*` for any. In dev mode, local pages are allowed too.
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheConfig {
+    /// The bytes of responses kept, headers included; the least recently used go first.
+    #[serde(default = "default_cache_max_size_bytes")]
+    pub max_size_bytes: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VirtualHostConfig {
+    /// Host names, exact (`example.com`) or wildcard (`*.example.com`).
+    pub names: Vec<String>,
+    #[serde(flatten)]
+    pub host: HostConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProxyConfig {
+    pub prefix: String,
+    pub upstreams: Vec<String>,
+    #[serde(default)]
+    pub strip_prefix: bool,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    #[serde(default = "default_read_timeout_ms")]
+    pub read_timeout_ms: u64,
+    #[serde(default = "default_max_fails")]
+    pub max_fails: u32,
+    #[serde(default = "default_fail_timeout_ms")]
+    pub fail_timeout_ms: u64,
+}
+
+fn default_address() -> String {
+    "localhost:3000".into()
+}
+fn default_shutdown_timeout_ms() -> u64 {
+    30_000
+}
+fn default_http2() -> bool {
+    true
+}
+fn default_access_log_compress() -> bool {
+    true
+}
+fn default_access_log_keep() -> usize {
+    7
+}
+fn default_metrics_path() -> Option<String> {
+    Some("/metrics".into())
+}
+fn default_realm() -> String {
+    "httpserver".into()
+}
+fn default_auth_paths() -> Vec<String> {
+    vec!["/".into()]
+}
+fn default_leeway_secs() -> u64 {
+    60
+}
+fn default_cors_prefix() -> String {
+    "/".into()
+}
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".into(), "HEAD".into(), "POST".into()]
+}
+fn default_period_secs() -> u64 {
+    1
+}
+fn default_cache_max_size_bytes() -> usize {
+    16 * 1024 * 1024
+}
+fn default_connect_timeout_ms() -> u64 {
+    5_000
+}
+fn default_read_timeout_ms() -> u64 {
+    30_000
+}
+fn default_max_fails() -> u32 {
+    3
+}
+fn default_fail_timeout_ms() -> u64 {
+    10_000
+}
+
+/// Parses method names for the `what` section of the config.
+fn methods(names: &[String], what: &str) -> Result<Vec<Method>, String> {
+    names
+        .iter()
+        .map(|name| match Method::from(name.to_ascii_uppercase().as_str()) {
+            Method::UNINITIALIZED => Err(format!("{}: unknown method {}", what, name)),
+            method => Ok(method),
+        })
+        .collect()
+}
+
+impl ServerConfig {
+    /// Loads the config file. Without `CONFIG_PATH` and without a default file,
+    /// a single host serving `public/` is used.
+    pub fn load() -> Result<ServerConfig, String> {
+        let path = match env::var("CONFIG_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.json");
+                if !path.exists() {
+                    return Ok(ServerConfig::parse("{}", Path::new(".")).unwrap());
+                }
+                path
+            }
+        };
+        let contents = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        ServerConfig::parse(&contents, base_dir).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn socket_mode(&self) -> Result<Option<u32>, String> {
+        self.socket_mode.as_deref().map(listener::parse_mode).transpose()
+    }
+
+    pub fn access_log(&self) -> Result<Option<AccessLog>, String> {
+        let config = match &self.log.access {
+            Some(config) => config,
+            None => return Ok(None),
+        };
+        let path = self.base_dir.join(&config.path);
+        let log = AccessLog::open(&path, config.format).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Some(
+            log.max_size(config.max_size_bytes)
+                .rotate(config.rotate)
+                .compress(config.compress)
+                .keep(config.keep),
+        ))
+    }
+
+    fn parse(contents: &str, base_dir: &Path) -> serde_json::Result<ServerConfig> {
+        let mut config: ServerConfig = serde_json::from_str(contents)?;
+        config.base_dir = base_dir.to_path_buf();
+        Ok(config)
+    }
+
+    /// Builds the hosts' routers; fails if a file they need, such as an htpasswd file, can't be read.
+    pub fn virtual_hosts(&self) -> Result<VirtualHosts, String> {
+        let mut hosts = VirtualHosts::new(self.router(&self.default_host)?);
+        for vhost in &self.virtual_hosts {
+            hosts.add(&vhost.names, self.router(&vhost.host)?);
+        }
+        Ok(hosts)
+    }
+
+    fn auth(&self, config: &AuthConfig) -> Result<Auth, String> {
+        let mut auth = Auth::new(&config.realm, &config.paths).methods(&methods(&config.methods, "auth")?);
+        if let Some(htpasswd) = &config.htpasswd {
+            auth = auth.with(BasicAuth::load(&self.base_dir.join(htpasswd))?);
+        }
+        if let Some(jwt) = &config.jwt {
+            let authenticator = match (jwt.algorithm, &jwt.public_key_file) {
+                (JwtAlgorithm::HS256, _) => JwtAuth::hs256(self.jwt_secret(jwt)?.as_bytes()),
+                (JwtAlgorithm::RS256, Some(file)) => {
+                    let path = self.base_dir.join(file);
+                    let pem = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                    JwtAuth::rs256(&pem)?
+                }
+                (JwtAlgorithm::RS256, None) => return Err("jwt: RS256 needs a public_key_file".into()),
+            };
+            auth = auth.with(authenticator.audience(&jwt.audience).leeway(Duration::from_secs(jwt.leeway_secs)));
+        }
+        if config.htpasswd.is_none() && config.jwt.is_none() {
+            return Err("auth: needs htpasswd or jwt".into());
+        }
+        Ok(auth)
+    }
+
+    /// The HS256 secret from `secret_env` or `secret_file`. Short secrets and
+    /// placeholders such as `change-me` are refused rather than trusted.
+    fn jwt_secret(&self, jwt: &JwtConfig) -> Result<String, String> {
+        let secret = match (&jwt.secret_env, &jwt.secret_file) {
+            (Some(name), _) => env::var(name).map_err(|_| format!("jwt: {} is not set", name))?,
+            (None, Some(file)) => {
+                let path = self.base_dir.join(file);
+                fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?
+            }
+            (None, None) => return Err("jwt: HS256 needs a secret_env or secret_file".into()),
+        };
+        let secret = secret.trim();
+        let lowercase = secret.to_ascii_lowercase();
+        if PLACEHOLDER_SECRETS.iter().any(|p| lowercase.contains(p)) || secret.len() < MIN_SECRET_LEN {
+            return Err(format!("jwt: the HS256 secret must be a random value of at least {} bytes", MIN_SECRET_LEN));
+        }
+        Ok(secret.to_string())
+    }
+
+    fn cors(&self, config: &CorsConfig) -> Result<CorsPolicy, String> {
+        let mut policy = CorsPolicy::default()
+            .methods(&methods(&config.allowed_methods, "cors")?)
+            .headers(&config.allowed_headers)
+            .expose_headers(&config.expose_headers)
+            .credentials(config.allow_credentials);
+        for origin in &config.allowed_origins {
+            policy = policy.allow_origin(origin.as_str().into());
+        }
+        if self.dev_mode {
+            policy = policy.allow_origin(AllowOrigin::Predicate(Box::new(is_local_origin)));
+        }
+        if let Some(secs) = config.max_age_secs {
+            policy = policy.max_age(Duration::from_secs(secs));
+        }
+        Ok(policy)
+    }
+
+    fn router(&self, host: &HostConfig) -> Result<Router, String> {
+        let mut site = match &host.document_root {
+            Some(root) => Site::new(self.base_dir.join(root)),
+            None => Site::default(),
+        };
+        site = site.reload_templates(self.dev_mode).autoindex(host.autoindex);
+        for (status, file_name) in &host.error_pages {
+            site = site.error_page(status, file_name);
+        }
+        let mut router = Router::new(site)
+            .orders_api(host.orders_api)
+            .metrics(self.metrics_path.as_deref());
+        if host.orders_api {
+            router = router
+                .websocket("/ws/orders", OrderUpdatesHandler)
+                .event_stream("/events/orders", OrderEventsHandler::default());
+        }
+        for proxy in &host.proxies {
+            let upstreams: Vec<&str> = proxy.upstreams.iter().map(String::as_str).collect();
+            let handler = ProxyHandler::new(&upstreams)
+                .strip_prefix(proxy.strip_prefix)
+                .timeouts(
+                    Duration::from_millis(proxy.connect_timeout_ms),
+                    Duration::from_millis(proxy.read_timeout_ms),
+                )
+                .health_check(proxy.max_fails, Duration::from_millis(proxy.fail_timeout_ms));
+            router = router.proxy(&proxy.prefix, handler);
+        }
+        if let Some(config) = &host.rate_limit {
+            let mut limiter = RateLimiter::default();
+            if let Some(limit) = &config.default {
+                limiter = limiter.route("/", limit.into());
+            }
+            for route in &config.routes {
+                limiter = limiter.route(&route.prefix, (&route.limit).into());
+            }
+            if let Some(header) = &config.key_header {
+                limiter = limiter
+                    .key_header(header, config.trusted_proxies.clone())
+                    .trust_unix_peers(config.trust_unix_peers);
+            }
+            router = router.rate_limit(limiter);
+        }
+        for cors in &host.cors {
+            router = router.cors(&cors.prefix, self.cors(cors)?);
+        }
+        if let Some(auth) = &host.auth {
+            router = router.auth(self.auth(auth)?);
+        }
+        if let Some(cache) = &host.cache {
+            router = router.cache(ResponseCache::new(cache.max_size_bytes));
+        }
+        Ok(router)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> serde_json::Result<ServerConfig> {
+        ServerConfig::parse(contents, Path::new("."))
+    }
+
+    #[test]
+    fn test_log_config() {
+        let log = parse("{}").unwrap().log;
+        assert_eq!((log.level.as_str(), log.format), ("info", LogFormat::Text));
+        let log = parse(r#"{ "log": { "level": "httpserver=debug,warn", "format": "json" } }"#).unwrap().log;
+        assert_eq!((log.level.as_str(), log.format), ("httpserver=debug,warn", LogFormat::Json));
+        let log = parse(r#"{ "log": { "format": "text" } }"#).unwrap().log;
+        assert_eq!((log.level.as_str(), log.format), ("info", LogFormat::Text));
+        let err = parse(r#"{ "log": { "format": "xml" } }"#).unwrap_err();
+        assert!(err.to_string().contains("unknown variant `xml`"), "{}", err);
+        assert!(parse(r#"{ "log": { "level": 3 } }"#).is_err());
+    }
+
+    #[test]
+    fn test_shipped_config_protects_writes() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let config = ServerConfig::parse(&fs::read_to_string(dir.join("config.json")).unwrap(), dir).unwrap();
+        let auth = config.default_host.auth.as_ref().expect("the default host needs auth");
+        assert_eq!(auth.paths, ["/api"]);
+        let auth = Auth::new(&auth.realm, &auth.paths).methods(&methods(&auth.methods, "auth").unwrap());
+        for method in [Method::POST, Method::PUT, Method::PATCH, Method::DELETE] {
+            assert!(auth.protects(method, "/api/shipping/orders"), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn test_jwt_secret() {
+        let dir = env::temp_dir().join(format!("httpserver-jwt-secret-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = ServerConfig::parse("{}", &dir).unwrap();
+        let jwt = |source: &str| -> JwtConfig {
+            serde_json::from_str(&format!(r#"{{ "algorithm": "HS256", {} }}"#, source)).unwrap()
+        };
+        for (name, contents) in [("short", "tlAw9qS0"), ("placeholder", "change-me-change-me-change-me-change-me\n")] {
+            fs::write(dir.join(name), contents).unwrap();
+            assert!(config.jwt_secret(&jwt(&format!(r#""secret_file": "{}""#, name))).is_err(), "{}", name);
+        }
+        fs::write(dir.join("random"), "Zq3v8T1kXo0bR6mJ4yNcWe2uHa9sLpDg\n").unwrap();
+        let secret = config.jwt_secret(&jwt(r#""secret_file": "random""#)).unwrap();
+        assert_eq!(secret, "Zq3v8T1kXo0bR6mJ4yNcWe2uHa9sLpDg");
+        assert!(config.jwt_secret(&jwt(r#""secret_env": "HTTPSERVER_TEST_UNSET_SECRET""#)).is_err());
+        assert!(config.jwt_secret(&jwt(r#""audience": []"#)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
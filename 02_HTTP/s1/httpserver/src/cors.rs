@@ -0,0 +1,277 @@
+use http::httprequest::{HttpRequest, Method};
+use http::httpresponse::HttpResponse;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Which origins may call a route group from the browser.
+pub enum AllowOrigin {
+    Any,
+    /// A whole origin, e.g. `https://shop.example.com`.
+    Exact(String),
+    /// Any origin whose host ends with this, e.g. `.example.com`.
+    Suffix(String),
+    Predicate(Box<dyn Fn(&str) -> bool + Send + Sync>),
+}
+impl From<&str> for AllowOrigin {
+    /// `*`, `*.example.com` or an exact origin.
+    fn from(s: &str) -> AllowOrigin {
+        let s = s.trim().trim_end_matches('/').to_ascii_lowercase();
+        match s.strip_prefix('*') {
+            Some("") => AllowOrigin::Any,
+            Some(suffix) if suffix.starts_with('.') => AllowOrigin::Suffix(suffix.to_string()),
+            _ => AllowOrigin::Exact(s),
+        }
+    }
+}
+impl fmt::Debug for AllowOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllowOrigin::Any => f.write_str("Any"),
+            AllowOrigin::Exact(origin) => f.debug_tuple("Exact").field(origin).finish(),
+            AllowOrigin::Suffix(suffix) => f.debug_tuple("Suffix").field(suffix).finish(),
+            AllowOrigin::Predicate(_) => f.write_str("Predicate(..)"),
+        }
+    }
+}
+impl AllowOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        match self {
+            AllowOrigin::Any => true,
+            AllowOrigin::Exact(exact) => *exact == origin,
+            AllowOrigin::Suffix(suffix) => {
+                let host = origin.split_once("://").map_or("", |(_, rest)| rest);
+                let host = host.split([':', '/']).next().unwrap_or("");
+                host.len() > suffix.len() && host.ends_with(suffix.as_str())
+            }
+            AllowOrigin::Predicate(allowed) => allowed(&origin),
+        }
+    }
+}
+
+/// Whether `origin` is a page served from this machine, e.g. `http://localhost:5173`.
+pub fn is_local_origin(origin: &str) -> bool {
+    let authority = origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://"));
+    let host = authority.map(|a| match a.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => a,
+    });
+    matches!(host, Some("localhost" | "127.0.0.1" | "[::1]"))
+}
+
+/// The CORS rules of one route group.
+#[derive(Debug)]
+pub struct CorsPolicy {
+    origins: Vec<AllowOrigin>,
+    methods: Vec<Method>,
+    /// Request headers a preflight may ask for; `*` allows any.
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            origins: Vec::new(),
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: Vec::new(),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+impl CorsPolicy {
+    pub fn allow_origin(mut self, origin: AllowOrigin) -> Self {
+        self.origins.push(origin);
+        self
+    }
+    pub fn methods(mut self, methods: &[Method]) -> Self {
+        self.methods = methods.to_vec();
+        self
+    }
+    pub fn headers(mut self, headers: &[String]) -> Self {
+        self.headers = headers.to_vec();
+        self
+    }
+    /// Response headers scripts may read besides the safelisted ones, e.g. `Link`.
+    pub fn expose_headers(mut self, headers: &[String]) -> Self {
+        self.expose_headers = headers.to_vec();
+        self
+    }
+    /// Lets requests carry cookies and `Authorization`.
+    pub fn credentials(mut self, allowed: bool) -> Self {
+        self.credentials = allowed;
+        self
+    }
+    /// How long browsers may cache a preflight result.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| allowed.matches(origin))
+    }
+
+    /// `Access-Control-Allow-Origin` and friends for a response to `origin`.
+    /// `Vary: Origin` is always included, since the answer depends on it.
+    pub fn response_headers(&self, origin: Option<&str>) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("Vary", "Origin".to_string())];
+        let origin = match origin.filter(|origin| self.allows(origin)) {
+            Some(origin) => origin,
+            None => return headers,
+        };
+        // `*` can't be combined with credentials, so the origin is echoed instead.
+        let any = self.origins.iter().any(|o| matches!(o, AllowOrigin::Any));
+        let allow_origin = if any && !self.credentials { "*" } else { origin };
+        headers.push(("Access-Control-Allow-Origin", allow_origin.to_string()));
+        if self.credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true".into()));
+        }
+        if !self.expose_headers.is_empty() {
+            headers.push(("Access-Control-Expose-Headers", self.expose_headers.join(", ")));
+        }
+        headers
+    }
+
+    /// Whether `req` is a preflight, which is answered without reaching a handler.
+    pub fn is_preflight(req: &HttpRequest) -> bool {
+        req.method == Method::OPTIONS
+            && req.header("Origin").is_some()
+            && req.header("Access-Control-Request-Method").is_some()
+    }
+
+    /// 204 if the origin may send the request described by the preflight, 403 otherwise.
+    pub fn preflight<'a>(&self, req: &HttpRequest) -> HttpResponse<'a> {
+        let origin = req.header("Origin").unwrap_or("");
+        let method = Method::from(req.header("Access-Control-Request-Method").unwrap_or("").trim());
+        let requested: Vec<&str> = req
+            .header("Access-Control-Request-Headers")
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .collect();
+        let any_header = self.headers.iter().any(|h| h == "*");
+        let refusal = if !self.allows(origin) {
+            Some(format!("Origin {} is not allowed", origin))
+        } else if !self.methods.contains(&method) {
+            Some("Method is not allowed".to_string())
+        } else {
+            requested
+                .iter()
+                .find(|h| !any_header && !self.headers.iter().any(|allowed| allowed.eq_ignore_ascii_case(h)))
+                .map(|h| format!("Header {} is not allowed", h))
+        };
+        let mut resp = match refusal {
+            Some(message) => {
+                let mut headers: HashMap<&str, &str> = HashMap::new();
+                headers.insert("Content-Type", "application/json");
+                let body = json!({ "error_message": message }).to_string();
+                return with_headers(HttpResponse::new("403", Some(headers), Some(body)), self.response_headers(None));
+            }
+            None => with_headers(HttpResponse::new("204", None, None), self.response_headers(Some(origin))),
+        };
+        let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+        resp.set_header("Access-Control-Allow-Methods", &methods.join(", "));
+        if !requested.is_empty() {
+            // With `*`, the requested headers are echoed: a literal `*` isn't honoured with credentials.
+            let allowed = if any_header { requested.join(", ") } else { self.headers.join(", ") };
+            resp.set_header("Access-Control-Allow-Headers", &allowed);
+        }
+        if let Some(max_age) = self.max_age {
+            resp.set_header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+        resp.set_header("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+        resp
+    }
+}
+
+/// Adds `headers` to `resp`, appending to any `Vary` it already has.
+pub fn with_headers<'a>(mut resp: HttpResponse<'a>, headers: Vec<(&str, String)>) -> HttpResponse<'a> {
+    for (name, value) in headers {
+        let value = match resp.header(name) {
+            Some(existing) if name == "Vary" => format!("{}, {}", existing, value),
+            _ => value,
+        };
+        resp.set_header(name, &value);
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preflight(origin: &str, method: &str, headers: &str) -> HttpRequest {
+        format!(
+            "OPTIONS /api/shipping/orders HTTP/1.1\r\nOrigin: {}\r\nAccess-Control-Request-Method: {}\r\nAccess-Control-Request-Headers: {}\r\n\r\n",
+            origin, method, headers
+        )
+        .into()
+    }
+
+    fn policy() -> CorsPolicy {
+        CorsPolicy::default()
+            .allow_origin("https://shop.example.com".into())
+            .allow_origin("*.partner.test".into())
+            .allow_origin(AllowOrigin::Predicate(Box::new(is_local_origin)))
+            .methods(&[Method::GET, Method::POST, Method::PATCH])
+            .headers(&["Content-Type".into(), "Authorization".into()])
+            .credentials(true)
+            .max_age(Duration::from_secs(600))
+    }
+
+    #[test]
+    fn test_origin_rules() {
+        let policy = policy();
+        for origin in ["https://shop.example.com", "https://eu.partner.test:8443", "http://localhost:5173"] {
+            assert!(policy.allows(origin), "{}", origin);
+        }
+        for origin in ["https://evil.example.com", "https://partner.test", "https://partner.test.evil", "null"] {
+            assert!(!policy.allows(origin), "{}", origin);
+        }
+        assert!(matches!(AllowOrigin::from("*"), AllowOrigin::Any));
+    }
+
+    #[test]
+    fn test_preflight() {
+        let policy = policy();
+        let req = preflight("https://shop.example.com", "PATCH", "content-type, authorization");
+        assert!(CorsPolicy::is_preflight(&req));
+        let resp = policy.preflight(&req);
+        assert_eq!(resp.status_code(), "204");
+        assert_eq!(resp.header("Access-Control-Allow-Origin"), Some("https://shop.example.com"));
+        assert_eq!(resp.header("Access-Control-Allow-Credentials"), Some("true"));
+        assert_eq!(resp.header("Access-Control-Allow-Methods"), Some("GET, POST, PATCH"));
+        assert_eq!(resp.header("Access-Control-Allow-Headers"), Some("Content-Type, Authorization"));
+        assert_eq!(resp.header("Access-Control-Max-Age"), Some("600"));
+
+        for req in [
+            preflight("https://evil.example.com", "PATCH", ""),
+            preflight("https://shop.example.com", "DELETE", ""),
+            preflight("https://shop.example.com", "GET", "X-Secret"),
+        ] {
+            let resp = policy.preflight(&req);
+            assert_eq!(resp.status_code(), "403");
+            assert_eq!(resp.header("Access-Control-Allow-Origin"), None);
+        }
+    }
+
+    #[test]
+    fn test_response_headers() {
+        let public = CorsPolicy::default().allow_origin("*".into()).expose_headers(&["Link".into()]);
+        let resp = with_headers(
+            HttpResponse::new("200", None, None).with_header("Vary", "Accept"),
+            public.response_headers(Some("https://anywhere.test")),
+        );
+        assert_eq!(resp.header("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(resp.header("Access-Control-Expose-Headers"), Some("Link"));
+        assert_eq!(resp.header("Vary"), Some("Accept, Origin"));
+        assert_eq!(policy().response_headers(Some("https://evil.test")), [("Vary", "Origin".to_string())]);
+    }
+}
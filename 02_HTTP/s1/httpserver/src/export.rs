@@ -0,0 +1,193 @@
+//! Orders in the formats other than JSON that the API serves: CSV and NDJSON downloads for
+//! spreadsheets and log tools, written a row at a time, and an HTML table.
+
+use crate::listing::{ListQuery, FIELDS};
+use crate::store::OrderStatus;
+use crate::template::escape_html;
+use http::httpresponse::HttpResponse;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+pub const CSV: &str = "text/csv";
+pub const NDJSON: &str = "application/x-ndjson";
+
+/// The columns for `orders`: the order fields any of them has, in the usual order,
+/// so that `?fields=` selects columns too. All of them if there are no orders.
+pub fn columns(orders: &[Value]) -> Vec<&'static str> {
+    if orders.is_empty() {
+        return FIELDS.to_vec();
+    }
+    FIELDS
+        .into_iter()
+        .filter(|field| orders.iter().any(|order| order.get(field).is_some()))
+        .collect()
+}
+
+/// A field as text: strings as they are, `null` and missing fields empty.
+fn cell(order: &Value, field: &str) -> String {
+    match order.get(field) {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Quotes a CSV field if it holds a comma, quote or line break, doubling the quotes (RFC 4180).
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// One CSV record, ended by CRLF.
+pub fn csv_record<S: AsRef<str>>(fields: &[S]) -> String {
+    let fields: Vec<String> = fields.iter().map(|field| csv_field(field.as_ref())).collect();
+    format!("{}\r\n", fields.join(","))
+}
+
+/// The `Content-Type` of orders as `media_type`, with the charset of the text formats.
+pub fn content_type(media_type: &'static str) -> &'static str {
+    match media_type {
+        CSV => "text/csv; charset=utf-8",
+        "text/html" => "text/html; charset=utf-8",
+        _ => media_type,
+    }
+}
+
+/// The `Content-Disposition` that has browsers save orders as `media_type` to a file,
+/// for the formats meant for other tools.
+pub fn content_disposition(media_type: &str) -> Option<&'static str> {
+    match media_type {
+        CSV => Some("attachment; filename=\"orders.csv\""),
+        NDJSON => Some("attachment; filename=\"orders.ndjson\""),
+        _ => None,
+    }
+}
+
+/// Whether orders as `media_type` are a download, served by `Export`.
+pub fn is_download(media_type: &str) -> bool {
+    media_type == CSV || media_type == NDJSON
+}
+
+/// Orders as a download in CSV, a header record and a record per order, or NDJSON, a
+/// line of JSON per order, written a row at a time.
+pub struct Export {
+    media_type: &'static str,
+    orders: Vec<OrderStatus>,
+    query: ListQuery,
+}
+impl Export {
+    /// Exports `orders` in the order and with the fields `query` asks for. Paging
+    /// parameters are ignored: an export holds every order.
+    pub fn new(media_type: &'static str, mut orders: Vec<OrderStatus>, query: ListQuery) -> Self {
+        query.sort(&mut orders);
+        Export { media_type, orders, query }
+    }
+    /// The head of the response, for a body sent chunked by `write_to`.
+    pub fn response<'a>(&self) -> HttpResponse<'a> {
+        self.with_body(None)
+    }
+    /// The whole response, for callers that can't stream it.
+    pub fn to_response<'a>(&self) -> HttpResponse<'a> {
+        let mut body = Vec::new();
+        // Writing to memory doesn't fail, and rows are UTF-8.
+        let _ = self.write_to(&mut body);
+        self.with_body(Some(String::from_utf8_lossy(&body).into_owned()))
+    }
+    fn with_body<'a>(&self, body: Option<String>) -> HttpResponse<'a> {
+        let mut headers: HashMap<&str, &str> = HashMap::new();
+        headers.insert("Content-Type", content_type(self.media_type));
+        if let Some(disposition) = content_disposition(self.media_type) {
+            headers.insert("Content-Disposition", disposition);
+        }
+        HttpResponse::new("200", Some(headers), body).with_header("X-Total-Count", &self.orders.len().to_string())
+    }
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let columns = self.query.columns();
+        if self.media_type == CSV {
+            out.write_all(csv_record(&columns).as_bytes())?;
+        }
+        for order in &self.orders {
+            let order = self.query.project(order);
+            let row = match self.media_type {
+                CSV => csv_record(&columns.iter().map(|field| cell(&order, field)).collect::<Vec<_>>()),
+                _ => format!("{}\n", order),
+            };
+            out.write_all(row.as_bytes())?;
+        }
+        out.flush()
+    }
+}
+
+/// A page with a table of `orders`.
+pub fn to_html(orders: &[Value]) -> String {
+    let columns = columns(orders);
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Orders</title>\n</head>\n<body>\n<table>\n<tr>",
+    );
+    for field in &columns {
+        html.push_str(&format!("<th>{}</th>", field));
+    }
+    html.push_str("</tr>\n");
+    for order in orders {
+        html.push_str("<tr>");
+        for field in &columns {
+            html.push_str(&format!("<td>{}</td>", escape_html(&cell(order, field))));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(csv_field("shipped"), "shipped");
+        assert_eq!(csv_field("say \"hi\", then\nleave"), "\"say \"\"hi\"\", then\nleave\"");
+        assert_eq!(csv_field("a\rb"), "\"a\rb\"");
+        assert_eq!(csv_record(&["1", "", "Back, \"ordered\""]), "1,,\"Back, \"\"ordered\"\"\"\r\n");
+    }
+
+    #[test]
+    fn test_export() {
+        let orders: Vec<OrderStatus> = [
+            json!({ "order_id": 2, "order_date": "2022-02-22", "order_status": "Pending" }),
+            json!({ "order_id": 1, "order_date": "2022-01-21", "order_status": "Delivered" }),
+        ]
+        .iter()
+        .map(|order| OrderStatus::try_from(order).unwrap())
+        .collect();
+        let query = |q: &str| ListQuery::from_params(&http::form::parse_urlencoded(q)).unwrap();
+        let written = |export: Export| {
+            let mut out = Vec::new();
+            export.write_to(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let csv = Export::new(CSV, orders.clone(), query("fields=order_status,order_id&per_page=1"));
+        let resp = csv.response();
+        assert_eq!(resp.header("Content-Disposition"), Some("attachment; filename=\"orders.csv\""));
+        assert_eq!(resp.header("X-Total-Count"), Some("2"));
+        assert_eq!(written(csv), "order_id,order_status\r\n1,Delivered\r\n2,Pending\r\n");
+        let ndjson = Export::new(NDJSON, orders, query("sort=-order_date&fields=order_id"));
+        assert_eq!(ndjson.response().header("Content-Type"), Some(NDJSON));
+        assert_eq!(ndjson.to_response().body(), "{\"order_id\":2}\n{\"order_id\":1}\n");
+        assert_eq!(written(ndjson), "{\"order_id\":2}\n{\"order_id\":1}\n");
+        let empty = Export::new(CSV, Vec::new(), query(""));
+        assert_eq!(written(empty), "order_id,order_date,order_status\r\n");
+    }
+
+    #[test]
+    fn test_html() {
+        let html = to_html(&[json!({ "order_id": 1, "order_status": "<b>Shipped</b>" })]);
+        assert!(html.contains("<tr><th>order_id</th><th>order_status</th></tr>"));
+        assert!(html.contains("<tr><td>1</td><td>&lt;b&gt;Shipped&lt;/b&gt;</td></tr>"));
+    }
+}
@@ -1,84 +1,691 @@
-use http::{httprequest::HttpRequest, httpresponse::HttpResponse};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use crate::auth::Identity;
+use crate::autoindex::{self, Sort};
+use crate::export::{self, Export};
+use crate::listing::ListQuery;
+use crate::metrics::{format_uptime, Metrics};
+use crate::template::{TemplateError, Templates};
+use crate::server::{ClientAddr, Connection};
+use http::sse::{Event, EventStream};
+use http::websocket::{Message, WebSocket};
+use crate::store::{parse_date, FieldError, OrderStatus, OrderStore, Status, StoreError};
+use chrono::NaiveDate;
+use http::negotiation::{self, Negotiation};
+use http::{httprequest::{HttpRequest, Method, Resource}, httpresponse::HttpResponse};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::{error, field, info};
 
 pub trait Handler {
-    fn handle(req:&HttpRequest) -> HttpResponse;
-    fn load_file(file_name: &str) -> Option<String>{
+    fn handle<'a>(req:&'a HttpRequest, site:&Site) -> HttpResponse<'a>;
+}
+
+/// `name` below `root`, or `None` if the name could escape it.
+pub fn resolve_path(root: &Path, name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(root.join(path))
+}
+
+/// Pages rendered as templates besides the error pages. Other files, HTML or not, are
+/// served as they are, so that a `{{` in a static page stays as it is.
+const TEMPLATE_PAGES: [&str; 2] = ["index.html", "health.html"];
+
+/// Settings of one (virtual) host: where its files live and which pages it shows for errors.
+/// The `TEMPLATE_PAGES` and error pages are rendered as templates; see `template::Templates`.
+#[derive(Debug, Clone)]
+pub struct Site {
+    document_root: PathBuf,
+    error_pages: HashMap<String, String>,
+    templates: Arc<Templates>,
+    /// List directories that have no `index.html`.
+    autoindex: bool,
+}
+impl Default for Site {
+    fn default() -> Self {
         let default_path = format!("{}/public", env!("CARGO_MANIFEST_DIR"));
         let public_path = env::var("PUBLIC_PATH").unwrap_or(default_path);
-        let full_path = format!("{}/{}", public_path, file_name);
-        let contents = fs::read_to_string(full_path);
-        contents.ok()
+        Site::new(public_path)
+    }
+}
+impl Site {
+    pub fn new(document_root: impl Into<PathBuf>) -> Self {
+        let document_root = document_root.into();
+        let mut error_pages = HashMap::new();
+        error_pages.insert("404".to_string(), "404.html".to_string());
+        Site {
+            templates: Arc::new(Templates::new(&document_root, false)),
+            document_root,
+            error_pages,
+            autoindex: false,
+        }
+    }
+    /// Re-reads templates when they change on disk, for development.
+    pub fn reload_templates(mut self, reload: bool) -> Self {
+        self.templates = Arc::new(Templates::new(&self.document_root, reload));
+        self
+    }
+    /// Lists directories without an `index.html`, as HTML or JSON depending on `Accept`.
+    pub fn autoindex(mut self, autoindex: bool) -> Self {
+        self.autoindex = autoindex;
+        self
+    }
+    /// Serves `file_name` from the document root for responses with `status`.
+    pub fn error_page(mut self, status: &str, file_name: &str) -> Self {
+        self.error_pages.insert(status.to_string(), file_name.to_string());
+        self
+    }
+    /// Reads a file below the document root. Names that could escape it are refused.
+    pub fn load_file(&self, file_name: &str) -> Option<String>{
+        fs::read_to_string(resolve_path(&self.document_root, file_name)?).ok()
+    }
+    /// Whether the file `name` below the document root is rendered as a template.
+    pub fn is_template(&self, name: &str) -> bool {
+        TEMPLATE_PAGES.contains(&name) || self.error_pages.values().any(|page| page == name)
     }
+    /// Renders the template `name` as a 200 response, or a 404 if there is no such page.
+    pub fn render_page<'a>(&self, name: &str, context: &Value) -> HttpResponse<'a> {
+        match self.templates.render(name, context) {
+            Ok(page) => HttpResponse::new("200", None, Some(page)),
+            Err(TemplateError::NotFound(_)) => self.error_response("404"),
+            Err(e) => {
+                error!(error = %e, "failed to render page");
+                self.error_response("500")
+            }
+        }
+    }
+    /// When the file or directory a request for `url_path` is served from was last modified.
+    pub fn modified(&self, url_path: &str) -> Option<SystemTime> {
+        let mut path = resolve_path(&self.document_root, &autoindex::relative_path(url_path)?)?;
+        if path.join("index.html").is_file() {
+            path.push("index.html");
+        }
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+    /// The response for a request naming a directory, if `autoindex` is on: a redirect
+    /// to the path with a trailing slash, the directory's `index.html`, or a listing.
+    /// Hidden directories and ones outside the document root (through a symlink) are left alone.
+    pub fn directory_response<'a>(&self, req: &HttpRequest) -> Option<HttpResponse<'a>> {
+        if !self.autoindex {
+            return None;
+        }
+        let url_path = req.path();
+        let name = autoindex::relative_path(url_path)?;
+        let dir = resolve_path(&self.document_root, &name)?;
+        let root = self.document_root.canonicalize().ok()?;
+        if !dir.is_dir() || !dir.canonicalize().ok()?.starts_with(&root) {
+            return None;
+        }
+        if !url_path.ends_with('/') {
+            let Resource::Path(resource) = &req.resource;
+            let location = resource.replacen(url_path, &format!("{}/", url_path), 1);
+            return Some(HttpResponse::new("301", None, None).with_header("Location", &location));
+        }
+        if dir.join("index.html").is_file() {
+            let index = Path::new(&name).join("index.html").to_string_lossy().into_owned();
+            if self.is_template(&index) {
+                return Some(self.render_page(&index, &json!({ "path": url_path })));
+            }
+            let mut headers: HashMap<&str, &str> = HashMap::new();
+            headers.insert("Content-Type", "text/html");
+            return Some(HttpResponse::new("200", Some(headers), self.load_file(&index)));
+        }
+        let mut entries = match autoindex::read_entries(&dir, &root) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!(error = %e, dir = %dir.display(), "failed to list directory");
+                return Some(self.error_response("500"));
+            }
+        };
+        let sort = Sort::from_params(&req.query_params());
+        autoindex::sort_entries(&mut entries, sort);
+        let display_path = if name.is_empty() { "/".to_string() } else { format!("/{}/", name) };
+        let mut headers: HashMap<&str, &str> = HashMap::new();
+        let body = if autoindex::wants_json(req.header("Accept")) {
+            headers.insert("Content-Type", "application/json");
+            autoindex::render_json(&display_path, &entries).to_string()
+        } else {
+            headers.insert("Content-Type", "text/html; charset=utf-8");
+            autoindex::render_html(&display_path, &entries, sort)
+        };
+        Some(HttpResponse::new("200", Some(headers), Some(body)).with_header("Vary", "Accept"))
+    }
+    /// A response for `status` with the host's error page for it, if it has one.
+    /// The page is rendered with the `status` variable.
+    pub fn error_response<'a>(&self, status: &'a str) -> HttpResponse<'a> {
+        let page = self.error_pages.get(status).and_then(|name| {
+            self.templates
+                .render(name, &json!({ "status": status }))
+                .map_err(|e| error!(error = %e, "failed to render error page"))
+                .ok()
+        });
+        HttpResponse::new(status, None, page)
+    }
+}
+
+/// Takes over a connection after the router has completed the WebSocket handshake.
+pub trait WebSocketHandler: Send + Sync {
+    fn handle(&self, req: &HttpRequest, ws: &mut WebSocket<&mut dyn Connection>);
 }
 
+/// Streams Server-Sent Events over a chunked response set up by the router.
+pub trait EventStreamHandler: Send + Sync {
+    fn handle(&self, req: &HttpRequest, events: &mut EventStream<&mut dyn Connection>);
+}
+
+/// How long clients and the response cache may keep static files and pages.
+const STATIC_CACHE_CONTROL: &str = "public, max-age=60, stale-while-revalidate=300";
+/// Orders change, so only the response cache keeps them; it drops them when `orders.json` changes.
+const ORDERS_CACHE_CONTROL: &str = "max-age=0, s-maxage=60";
+/// The representations of orders, JSON being the default.
+const ORDER_MEDIA_TYPES: [&str; 4] = ["application/json", export::CSV, export::NDJSON, "text/html"];
+/// The values of `?format=`, which picks a representation of orders regardless of `Accept`.
+const ORDER_FORMATS: [(&str, &str); 4] =
+    [("json", "application/json"), ("csv", export::CSV), ("ndjson", export::NDJSON), ("html", "text/html")];
+
 pub struct StaticPageHandler;
 pub struct PageNotFoundHandler;
 pub struct WebServiceHandler;
-
-#[derive(Serialize, Deserialize)]
-pub struct OrderStatus{
-    order_id:i32,
-    order_date:String,
-    order_status:String,
+/// Pushes the contents of `orders.json` to WebSocket clients whenever the file changes.
+pub struct OrderUpdatesHandler;
+/// Pushes an `order-status` event to SSE clients for every order whose status changes.
+#[derive(Default)]
+pub struct OrderEventsHandler {
+    history: Mutex<OrderHistory>,
 }
 
 impl Handler for PageNotFoundHandler {
-    fn handle(req:&HttpRequest) -> HttpResponse {
-        HttpResponse::new("404", None, Self::load_file("404.html"))
+    /// The error page, or a JSON error for API clients that prefer it.
+    fn handle<'a>(req:&'a HttpRequest, site:&Site) -> HttpResponse<'a> {
+        let mut negotiation = Negotiation::new(req);
+        let resp = match negotiation.media_type(&["text/html", "application/json"]) {
+            Some("application/json") => WebServiceHandler::error_response("404", "Not found"),
+            _ => site.error_response("404"),
+        };
+        resp.with_header("Vary", &negotiation.vary())
     }
 }
-impl Handler for StaticPageHandler {
-    fn handle(req:&HttpRequest) -> HttpResponse {
-        let http::httprequest::Resource::Path(s) = &req.resource;
-        let route: Vec<&str> = s.split("/").collect();
-        match route[1] {
-            "" => HttpResponse::new("200", None, Self::load_file("index.html")),
-            "health" => HttpResponse::new("200", None, Self::load_file("health.html")),
-            path => match Self::load_file(path) {
-                Some(content) => {
-                    let mut map : HashMap<&str, &str> = HashMap::new();
-                    if path.ends_with(".css"){
-                        map.insert("Content-Type", "text/css");
-                    }else if path.ends_with(".js"){
-                        map.insert("Content-Type", "text/javascript");
-                    }else{
-                        map.insert("Content-Type", "text/html");
+impl StaticPageHandler {
+    /// Browsers ask for HTML; load balancer probes, which don't, get the JSON health check.
+    fn wants_html(req: &HttpRequest) -> bool {
+        negotiation::media_type(req.header("Accept"), &["application/json", "text/html"]) == Some("text/html")
+    }
+    /// Uptime, request counts and a summary of the orders by status.
+    fn health_context(req: &HttpRequest) -> Value {
+        let metrics = Metrics::global();
+        let orders = match OrderStore::shared().list() {
+            Ok(orders) => {
+                let mut counts: Vec<(Status, usize)> = Vec::new();
+                for order in &orders {
+                    match counts.iter_mut().find(|(s, _)| *s == order.order_status) {
+                        Some((_, count)) => *count += 1,
+                        None => counts.push((order.order_status, 1)),
                     }
-                    HttpResponse::new("200", Some(map), Some(content))
-                },
-                None => HttpResponse::new("404", None, Self::load_file("404.html")),
+                }
+                counts.sort();
+                let by_status: Vec<Value> = counts
+                    .iter()
+                    .map(|(status, count)| json!({ "status": status, "count": count }))
+                    .collect();
+                json!({ "total": orders.len(), "by_status": by_status })
             }
+            Err(e) => json!({ "error": e.to_string() }),
+        };
+        json!({
+            "path": req.path(),
+            "uptime": format_uptime(metrics.uptime()),
+            "uptime_seconds": metrics.uptime().as_secs(),
+            "connections": metrics.connections(),
+            "requests": metrics.requests(),
+            "orders": orders,
+        })
+    }
+    fn page<'a>(req: &'a HttpRequest, site: &Site) -> HttpResponse<'a> {
+        if let Some(resp) = site.directory_response(req) {
+            return resp;
+        }
+        let route: Vec<&str> = req.path().split("/").collect();
+        let context = json!({ "path": req.path() });
+        match route[1] {
+            "" => site.render_page("index.html", &context),
+            "health" if Self::wants_html(req) => site
+                .render_page("health.html", &Self::health_context(req))
+                .with_header("Cache-Control", "no-store"),
+            "health" => {
+                let metrics = Metrics::global();
+                let body = json!({
+                    "status": "ok",
+                    "uptime_seconds": metrics.uptime().as_secs(),
+                    "active_connections": metrics.active_connections(),
+                });
+                let mut headers: HashMap<&str, &str> = HashMap::new();
+                headers.insert("Content-Type", "application/json");
+                headers.insert("Cache-Control", "no-store");
+                HttpResponse::new("200", Some(headers), Some(body.to_string()))
+            }
+            _ => match autoindex::relative_path(req.path()) {
+                Some(name) if site.is_template(&name) => site.render_page(&name, &context),
+                name => match name.and_then(|name| site.load_file(&name).map(|content| (name, content))) {
+                    Some((name, content)) => {
+                        let mut map : HashMap<&str, &str> = HashMap::new();
+                        if name.ends_with(".css"){
+                            map.insert("Content-Type", "text/css");
+                        }else if name.ends_with(".js"){
+                            map.insert("Content-Type", "text/javascript");
+                        }else{
+                            map.insert("Content-Type", "text/html");
+                        }
+                        HttpResponse::new("200", Some(map), Some(content))
+                    },
+                    None => site.error_response("404"),
+                },
+            },
 
         }
     }
 }
+impl Handler for StaticPageHandler {
+    fn handle<'a>(req:&'a HttpRequest, site:&Site) -> HttpResponse<'a> {
+        let mut resp = Self::page(req, site);
+        if resp.status_code() == "200" && resp.header("Cache-Control").is_none() {
+            resp.set_header("Cache-Control", STATIC_CACHE_CONTROL);
+        }
+        resp
+    }
+}
 impl WebServiceHandler{
-    fn load_json() -> Vec<OrderStatus>{
-        let default_path = format!("{}/data", env!("CARGO_MANIFEST_DIR"));
-        let data_path = env::var("DATA_PATH").unwrap_or(default_path);
-        let full_path = format!("{}/{}", data_path, "orders.json");
-        let json_contents = fs::read_to_string(full_path);
-        let orders: Vec<OrderStatus> = 
-        serde_json::from_str(json_contents.unwrap().as_str()).unwrap();
-        orders
+    fn json_response<'a>(status: &'a str, body: &impl Serialize) -> HttpResponse<'a> {
+        let mut headers: HashMap<&str, &str> = HashMap::new();
+        headers.insert("Content-Type", "application/json");
+        let body = serde_json::to_string(body).unwrap_or_default();
+        HttpResponse::new(status, Some(headers), Some(body))
+    }
+    fn error_response<'a>(status: &'a str, message: &str) -> HttpResponse<'a> {
+        Self::json_response(status, &json!({ "error_message": message }))
+    }
+    /// Orders, or one order, as JSON or HTML; downloads are written by `Export`.
+    fn orders_response<'a>(media_type: &'static str, body: Value) -> HttpResponse<'a> {
+        let orders = match &body {
+            Value::Array(orders) => orders.as_slice(),
+            order => std::slice::from_ref(order),
+        };
+        match media_type {
+            "text/html" => {
+                let mut headers: HashMap<&str, &str> = HashMap::new();
+                headers.insert("Content-Type", export::content_type(media_type));
+                HttpResponse::new("200", Some(headers), Some(export::to_html(orders)))
+            }
+            _ => Self::json_response("200", &body),
+        }
+    }
+    /// Adds `Vary`, unless the response depends on no request header.
+    fn with_vary<'a>(resp: HttpResponse<'a>, negotiation: &Negotiation) -> HttpResponse<'a> {
+        match negotiation.vary() {
+            vary if vary.is_empty() => resp,
+            vary => resp.with_header("Vary", &vary),
+        }
+    }
+    /// The representation of orders a GET asks for: the one `?format=` names, or else the one
+    /// `Accept` and `Accept-Charset` prefer. An error response if there's none.
+    fn media_type<'a>(req: &HttpRequest, negotiation: &mut Negotiation) -> Result<&'static str, HttpResponse<'a>> {
+        if let Some((_, format)) = req.query_params().into_iter().find(|(name, _)| name == "format") {
+            return match ORDER_FORMATS.iter().find(|(name, _)| name.eq_ignore_ascii_case(&format)) {
+                Some((_, media_type)) => Ok(media_type),
+                None => {
+                    let names: Vec<&str> = ORDER_FORMATS.iter().map(|(name, _)| *name).collect();
+                    let message = format!("must be one of {}", names.join(", "));
+                    Err(Self::store_error(StoreError::Invalid(vec![FieldError::new("format", message)])))
+                }
+            };
+        }
+        match (negotiation.media_type(&ORDER_MEDIA_TYPES), negotiation.charset(&["utf-8"])) {
+            (Some(media_type), Some(_)) => Ok(media_type),
+            _ => {
+                let message = format!("Orders are available as {} in UTF-8", ORDER_MEDIA_TYPES.join(", "));
+                Err(Self::with_vary(Self::error_response("406", &message), negotiation))
+            }
+        }
+    }
+    fn store_error<'a>(e: StoreError) -> HttpResponse<'a> {
+        let status = match &e {
+            StoreError::NotFound(_) => "404",
+            StoreError::Conflict(_) => "409",
+            StoreError::BadRequest(_) => "400",
+            StoreError::Invalid(errors) => {
+                let body = json!({ "error_message": e.to_string(), "errors": errors });
+                return Self::json_response("422", &body);
+            }
+            StoreError::Io(_) | StoreError::Parse(_) => {
+                error!(error = %e, "order store error");
+                "500"
+            }
+        };
+        Self::error_response(status, &e.to_string())
+    }
+    fn method_not_allowed<'a>(allow: &str) -> HttpResponse<'a> {
+        Self::error_response("405", "Method not allowed").with_header("Allow", allow)
+    }
+    fn parse_body(req: &HttpRequest) -> Result<Value, StoreError> {
+        serde_json::from_slice(&req.msg_body)
+            .map_err(|e| StoreError::BadRequest(format!("Invalid JSON body: {}", e)))
+    }
+    fn parse_order(value: &Value) -> Result<OrderStatus, StoreError> {
+        OrderStatus::try_from(value).map_err(StoreError::Invalid)
+    }
+    fn parse_filter_date(name: &str, value: &str) -> Result<NaiveDate, StoreError> {
+        parse_date(value)
+            .ok_or_else(|| StoreError::Invalid(vec![FieldError::new(name, format!("is not a date: \"{}\"", value))]))
+    }
+
+    /// Orders matching `?order_status=` and an inclusive `?from=`/`?to=` date range, if given.
+    fn filter(req: &HttpRequest) -> Result<Vec<OrderStatus>, StoreError> {
+        let mut orders = OrderStore::shared().list()?;
+        for (name, value) in req.query_params() {
+            match name.as_str() {
+                "order_status" => {
+                    let status: Status = value
+                        .parse()
+                        .map_err(|e| StoreError::Invalid(vec![FieldError::new("order_status", e)]))?;
+                    orders.retain(|o| o.order_status == status);
+                }
+                "from" => {
+                    let from = Self::parse_filter_date("from", &value)?;
+                    orders.retain(|o| o.order_date >= from);
+                }
+                "to" => {
+                    let to = Self::parse_filter_date("to", &value)?;
+                    orders.retain(|o| o.order_date <= to);
+                }
+                _ => {}
+            }
+        }
+        Ok(orders)
+    }
+    /// One page of the filtered orders as JSON or HTML, with `X-Total-Count` and `Link`
+    /// headers; see `ListQuery`.
+    fn list<'a>(req: &HttpRequest, media_type: &'static str) -> Result<HttpResponse<'a>, StoreError> {
+        let query = ListQuery::from_params(&req.query_params()).map_err(StoreError::Invalid)?;
+        let page = query.apply(Self::filter(req)?, req.path()).map_err(StoreError::Invalid)?;
+        let link = page.link_header();
+        let mut resp = Self::orders_response(media_type, Value::Array(page.items))
+            .with_header("X-Total-Count", &page.total.to_string());
+        if let Some(link) = link {
+            resp.set_header("Link", &link);
+        }
+        Ok(resp)
+    }
+    /// `orders` as a CSV or NDJSON download, sorted and with the fields the query selects.
+    /// Unlike JSON and HTML lists, downloads aren't paged: `page` and `per_page` are ignored.
+    fn download(req: &HttpRequest, media_type: &'static str, orders: Vec<OrderStatus>) -> Result<Export, StoreError> {
+        let query = ListQuery::from_params(&req.query_params()).map_err(StoreError::Invalid)?;
+        Ok(Export::new(media_type, orders, query))
+    }
+    /// A GET of the orders, or of one order, as CSV or NDJSON: the response head, and the
+    /// export to stream as its body. `None` for other requests, and for ones `handle`
+    /// answers with an error.
+    pub fn export<'a>(req: &HttpRequest) -> Option<(HttpResponse<'a>, Export)> {
+        let route: Vec<&str> = req.path().trim_end_matches('/').split('/').collect();
+        if req.method != Method::GET {
+            return None;
+        }
+        let mut negotiation = Negotiation::new(req);
+        let media_type = Self::media_type(req, &mut negotiation).ok().filter(|m| export::is_download(m))?;
+        let orders = match route[1..] {
+            ["api", "shipping", "orders"] => Self::filter(req).ok()?,
+            ["api", "shipping", "orders", id] => vec![OrderStore::shared().get(id.parse().ok()?).ok()?],
+            _ => return None,
+        };
+        let export = Self::download(req, media_type, orders).ok()?;
+        let resp = export.response().with_header("Cache-Control", ORDERS_CACHE_CONTROL);
+        Some((Self::with_vary(resp, &negotiation), export))
+    }
+    fn replace(id: i32, req: &HttpRequest) -> Result<OrderStatus, StoreError> {
+        let mut order = Self::parse_order(&Self::parse_body(req)?)?;
+        if order.order_id == 0 {
+            order.order_id = id;
+        }
+        OrderStore::shared().update(id, |_| Ok(order))
+    }
+    /// Merges the fields of a JSON object body into the stored order.
+    fn patch(id: i32, req: &HttpRequest) -> Result<OrderStatus, StoreError> {
+        let changes = match Self::parse_body(req)? {
+            Value::Object(changes) => changes,
+            _ => return Err(StoreError::BadRequest("Expected a JSON object".into())),
+        };
+        OrderStore::shared().update(id, |order| {
+            let mut merged = serde_json::to_value(order)?;
+            if let Value::Object(fields) = &mut merged {
+                fields.extend(changes);
+            }
+            Self::parse_order(&merged)
+        })
+    }
+}
+impl WebServiceHandler {
+    pub fn route_label(path: &str) -> &'static str {
+        let route: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+        match route[1..] {
+            ["api", "shipping", "orders"] => "/api/shipping/orders",
+            ["api", "shipping", "orders", _] => "/api/shipping/orders/:id",
+            _ => "/api/*",
+        }
     }
 }
 impl Handler for WebServiceHandler{
-    fn handle(req:&HttpRequest) -> HttpResponse {
-        let http::httprequest::Resource::Path(s) = &req.resource;
-        let route:Vec<&str> = s.split("/").collect();
-        match route[2] {
-            "shipping" if route.len() > 2 && route[3] == "orders" => {
-                let body = Some(serde_json::to_string(&Self::load_json()).unwrap());
-                let mut headers:HashMap<&str, &str> = HashMap::new();
-                headers.insert("Content-Type", "application/json");
-                HttpResponse::new("200", Some(headers), body)
+    fn handle<'a>(req:&'a HttpRequest, site:&Site) -> HttpResponse<'a> {
+        let route: Vec<&str> = req.path().trim_end_matches('/').split('/').collect();
+        let store = OrderStore::shared();
+        // Orders are read in any of the `ORDER_MEDIA_TYPES`, and always written as JSON.
+        let mut negotiation = Negotiation::new(req);
+        let media_type = match req.method {
+            Method::GET => match Self::media_type(req, &mut negotiation) {
+                Ok(media_type) => media_type,
+                Err(resp) => return resp,
             },
-            _ => HttpResponse::new("404", None, Self::load_file("404.html")),
+            _ => "application/json",
+        };
+        let result = match route[1..] {
+            ["api", "shipping", "orders"] => match req.method {
+                // The router streams downloads instead; see `export`.
+                Method::GET if export::is_download(media_type) => Self::filter(req)
+                    .and_then(|orders| Self::download(req, media_type, orders))
+                    .map(|export| export.to_response()),
+                Method::GET => Self::list(req, media_type),
+                Method::POST => Self::parse_body(req)
+                    .and_then(|body| Self::parse_order(&body))
+                    .and_then(|order| store.create(order))
+                    .map(|order| {
+                    let location = format!("/api/shipping/orders/{}", order.order_id);
+                    Self::json_response("201", &order).with_header("Location", &location)
+                }),
+                _ => Ok(Self::method_not_allowed("GET, POST")),
+            },
+            ["api", "shipping", "orders", id] => {
+                let id = match id.parse::<i32>() {
+                    Ok(id) => id,
+                    Err(_) => return Self::error_response("404", &format!("Order {} not found", id)),
+                };
+                match req.method {
+                    Method::GET if export::is_download(media_type) => store
+                        .get(id)
+                        .and_then(|order| Self::download(req, media_type, vec![order]))
+                        .map(|export| export.to_response()),
+                    Method::GET => store
+                        .get(id)
+                        .and_then(|order| Ok(Self::orders_response(media_type, serde_json::to_value(order)?))),
+                    Method::PUT => Self::replace(id, req).map(|order| Self::json_response("200", &order)),
+                    Method::PATCH => Self::patch(id, req).map(|order| Self::json_response("200", &order)),
+                    Method::DELETE => store.delete(id).map(|_| HttpResponse::new("204", None, None)),
+                    _ => Ok(Self::method_not_allowed("GET, PUT, PATCH, DELETE")),
+                }
+            }
+            _ => Ok(site.error_response("404")),
+        };
+        let result = result.map(|resp| match req.method {
+            Method::GET if resp.status_code() == "200" => {
+                Self::with_vary(resp.with_header("Cache-Control", ORDERS_CACHE_CONTROL), &negotiation)
+            }
+            _ => resp,
+        });
+        if let (Ok(resp), Some(identity)) = (&result, req.extensions.get::<Identity>()) {
+            if req.method != Method::GET {
+                info!(
+                    user = %identity.subject,
+                    scheme = %identity.scheme,
+                    client = req.extensions.get::<ClientAddr>().map(|addr| field::display(addr.0)),
+                    method = req.method.as_str(),
+                    path = req.path(),
+                    status = resp.status_code(),
+                    "order changed"
+                );
+            }
+        }
+        result.unwrap_or_else(Self::store_error)
+    }
+}
+
+const ORDER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+impl OrderUpdatesHandler {
+    fn send_orders(ws: &mut WebSocket<&mut dyn Connection>) -> io::Result<()> {
+        let orders = OrderStore::shared().list().map_err(|e| io::Error::other(e.to_string()))?;
+        ws.send_text(&serde_json::to_string(&orders)?)
+    }
+}
+impl WebSocketHandler for OrderUpdatesHandler {
+    fn handle(&self, _req: &HttpRequest, ws: &mut WebSocket<&mut dyn Connection>) {
+        let mut last_modified = OrderStore::shared().modified();
+        if Self::send_orders(ws).is_err() {
+            return;
+        }
+        // Wake up periodically to check the file even when the client is silent.
+        let _ = ws.get_ref().set_read_timeout(Some(ORDER_POLL_INTERVAL));
+        loop {
+            match ws.recv() {
+                Ok(Message::Close(_)) => break,
+                Ok(Message::Text(t)) if t == "refresh" => {
+                    if Self::send_orders(ws).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    let modified = OrderStore::shared().modified();
+                    if modified != last_modified {
+                        last_modified = modified;
+                        if Self::send_orders(ws).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Recent order-status changes, shared by every SSE connection so that
+/// event IDs are stable and a reconnecting client can resume from `Last-Event-ID`.
+#[derive(Default)]
+struct OrderHistory {
+    modified: Option<SystemTime>,
+    statuses: HashMap<i32, Status>,
+    events: VecDeque<(u64, Event)>,
+    last_id: u64,
+}
+
+const ORDER_HISTORY_LEN: usize = 100;
+const ORDER_EVENTS_RETRY: Duration = Duration::from_secs(3);
+
+impl OrderHistory {
+    /// Reloads `orders.json` if it changed and records an event per status change.
+    fn refresh(&mut self) {
+        let store = OrderStore::shared();
+        let modified = store.modified();
+        if modified.is_some() && modified == self.modified {
+            return;
+        }
+        let orders = match store.list() {
+            Ok(orders) => orders,
+            Err(_) => return,
+        };
+        let first_load = self.modified.is_none();
+        self.modified = modified;
+        for order in orders {
+            let previous = self.statuses.insert(order.order_id, order.order_status);
+            if first_load || previous == Some(order.order_status) {
+                continue;
+            }
+            self.last_id += 1;
+            let data = serde_json::json!({
+                "order_id": order.order_id,
+                "order_status": order.order_status,
+            });
+            let event = Event::new(&data.to_string())
+                .id(&self.last_id.to_string())
+                .event("order-status");
+            self.events.push_back((self.last_id, event));
+            if self.events.len() > ORDER_HISTORY_LEN {
+                self.events.pop_front();
+            }
+        }
+    }
+    /// Events after `after`, or `None` if they are no longer all retained.
+    fn since(&self, after: u64) -> Option<Vec<Event>> {
+        let oldest = self.events.front().map(|(id, _)| *id).unwrap_or(self.last_id + 1);
+        if after > self.last_id || after + 1 < oldest {
+            return None;
+        }
+        Some(
+            self.events
+                .iter()
+                .filter(|(id, _)| *id > after)
+                .map(|(_, e)| e.clone())
+                .collect(),
+        )
+    }
+}
+
+impl OrderEventsHandler {
+    fn snapshot(last_id: u64) -> io::Result<Event> {
+        let orders = OrderStore::shared().list().map_err(|e| io::Error::other(e.to_string()))?;
+        let data = serde_json::to_string(&orders)?;
+        Ok(Event::new(&data).id(&last_id.to_string()).event("snapshot"))
+    }
+    /// Sends whatever the client hasn't seen yet: the missed events when resuming,
+    /// otherwise a full snapshot.
+    fn catch_up(&self, events: &mut EventStream<&mut dyn Connection>) -> io::Result<()> {
+        let after = events.last_event_id().and_then(|id| id.parse::<u64>().ok());
+        let (missed, last_id) = {
+            let mut history = self.history.lock().unwrap();
+            history.refresh();
+            (after.and_then(|after| history.since(after)), history.last_id)
+        };
+        match missed {
+            Some(missed) => missed.iter().try_for_each(|e| events.send(e)),
+            None => events.send(&Self::snapshot(last_id)?),
+        }
+    }
+}
+impl EventStreamHandler for OrderEventsHandler {
+    fn handle(&self, _req: &HttpRequest, events: &mut EventStream<&mut dyn Connection>) {
+        if events.retry(ORDER_EVENTS_RETRY).is_err() {
+            return;
+        }
+        loop {
+            if self.catch_up(events).and_then(|_| events.keep_alive()).is_err() {
+                break;
+            }
+            thread::sleep(ORDER_POLL_INTERVAL);
         }
     }
 }
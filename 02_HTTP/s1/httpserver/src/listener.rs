@@ -0,0 +1,326 @@
+//! Where connections come from: TCP addresses, Unix domain sockets, and
+//! listening sockets handed down by systemd socket activation (`LISTEN_FDS`).
+
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+/// The first descriptor passed by socket activation (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// An address to listen on: `host:port`, or `unix:/path/to.sock` for a Unix domain socket.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+impl From<&str> for ListenAddr {
+    fn from(s: &str) -> ListenAddr {
+        match s.strip_prefix("unix:") {
+            Some(path) => ListenAddr::Unix(PathBuf::from(path)),
+            None => ListenAddr::Tcp(s.to_string()),
+        }
+    }
+}
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parses Unix permission bits written in octal, such as `660` or `0o660`.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid socket mode \"{}\", expected octal such as \"660\"", s))
+}
+
+/// Whether connections to a listener start with a PROXY protocol header, sent by a
+/// load balancer in front to pass on the client's address.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// Connections come straight from clients.
+    #[default]
+    Off,
+    /// A header is read if present. Any client can then claim any address, so this is
+    /// only for moving a listener behind a load balancer.
+    Optional,
+    /// Connections without a header are refused.
+    Required,
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// With the path of the socket file if this process created it, to remove it when done.
+    Unix(UnixListener, Option<PathBuf>),
+}
+impl Listener {
+    /// Binds `addr`. A Unix socket gets the permissions `mode` if given, e.g. `0o660` to let
+    /// a proxy in the same group connect. A socket file left behind by a server that is no
+    /// longer running is replaced; one that is still in use is an `AddrInUse` error.
+    pub fn bind(addr: &ListenAddr, mode: Option<u32>) -> io::Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                if let Some(mode) = mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                }
+                Ok(Listener::Unix(listener, Some(path.clone())))
+            }
+        }
+    }
+
+    /// Takes over a listening stream socket, such as one passed by the service manager.
+    ///
+    /// # Safety
+    /// `fd` must be an open descriptor that nothing else owns or closes. It is left
+    /// open if an error is returned.
+    pub unsafe fn from_fd(fd: RawFd) -> io::Result<Listener> {
+        let int_option = |name| {
+            let mut value: libc::c_int = 0;
+            let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+            match libc::getsockopt(fd, libc::SOL_SOCKET, name, &mut value as *mut _ as *mut libc::c_void, &mut len) {
+                0 => Ok(value),
+                _ => Err(io::Error::last_os_error()),
+            }
+        };
+        if int_option(libc::SO_TYPE)? != libc::SOCK_STREAM || int_option(libc::SO_ACCEPTCONN)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {} is not a listening stream socket", fd)));
+        }
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Keep the socket from leaking into processes started later.
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        match addr.ss_family as libc::c_int {
+            libc::AF_UNIX => Ok(Listener::Unix(UnixListener::from_raw_fd(fd), None)),
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(TcpListener::from_raw_fd(fd))),
+            family => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {} has unsupported address family {}", fd, family))),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            Listener::Unix(listener, _) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+}
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "fd {}", listener.as_raw_fd()),
+            },
+            Listener::Unix(listener, _) => match listener.local_addr().ok().and_then(|a| a.as_pathname().map(Path::to_path_buf)) {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "unix:fd {}", listener.as_raw_fd()),
+            },
+        }
+    }
+}
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Removes the socket file at `path` if no server accepts connections on it any more.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display()))),
+            Err(_) => fs::remove_file(path),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// How many sockets the service manager passed to this process: `LISTEN_FDS`,
+/// if `LISTEN_PID` is `pid`, or 0 if they were meant for another process.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> io::Result<usize> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(fds)) if listen_pid.parse::<u32>().ok() == Some(pid) => {
+            fds.parse().map_err(|_| invalid(format!("LISTEN_FDS is not a number: \"{}\"", fds)))
+        }
+        _ => Ok(0),
+    }
+}
+
+/// The listening sockets passed by systemd socket activation, if any. The variables
+/// describing them are removed so that processes started later don't take them too.
+pub fn from_env() -> io::Result<Vec<Listener>> {
+    let count = listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        process::id(),
+    )?;
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    // The descriptors from 3 on were passed to this process for it to own.
+    (0..count as RawFd).map(|i| unsafe { Listener::from_fd(LISTEN_FDS_START + i) }).collect()
+}
+
+/// An accepted connection.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+impl Stream {
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+    /// The client's address; clients of a Unix socket have none.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+}
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("httpserver-listener-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Accepts `client`, checks that bytes go both ways and returns the server side.
+    fn echo(listener: &Listener, mut client: impl Read + Write) -> Stream {
+        client.write_all(b"ping").unwrap();
+        let mut stream = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        stream
+    }
+
+    #[test]
+    fn test_parse_addresses_and_modes() {
+        assert_eq!(ListenAddr::from("localhost:3000"), ListenAddr::Tcp("localhost:3000".into()));
+        let unix = ListenAddr::from("unix:/run/httpserver.sock");
+        assert_eq!(unix, ListenAddr::Unix("/run/httpserver.sock".into()));
+        assert_eq!(unix.to_string(), "unix:/run/httpserver.sock");
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        assert!(parse_mode("rw-rw----").is_err());
+        assert!(parse_mode("99").is_err());
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let path = temp_dir("unix").join("server.sock");
+        // Left behind by a server that is gone.
+        drop(UnixListener::bind(&path).unwrap());
+        let addr = ListenAddr::Unix(path.clone());
+        let listener = Listener::bind(&addr, Some(0o660)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o660);
+        assert_eq!(listener.to_string(), format!("unix:{}", path.display()));
+        assert_eq!(echo(&listener, UnixStream::connect(&path).unwrap()).peer_addr(), None);
+        assert_eq!(Listener::bind(&addr, None).err().map(|e| e.kind()), Some(io::ErrorKind::AddrInUse));
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_inherited_sockets() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42).unwrap(), 2);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42).unwrap(), 0);
+        assert_eq!(listen_fds(None, None, 42).unwrap(), 0);
+        assert!(listen_fds(Some("42"), Some("two"), 42).is_err());
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = unsafe { Listener::from_fd(tcp.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Tcp(_)));
+        assert!(echo(&listener, TcpStream::connect(addr).unwrap()).peer_addr().is_some());
+
+        let path = temp_dir("inherited").join("activated.sock");
+        let _ = fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        let listener = unsafe { Listener::from_fd(unix.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Unix(_, None)));
+        echo(&listener, UnixStream::connect(&path).unwrap());
+        drop(listener);
+        // The service manager owns the file.
+        assert!(path.exists());
+
+        let not_listening = UnixStream::pair().unwrap().0;
+        assert!(unsafe { Listener::from_fd(not_listening.into_raw_fd()) }.is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::store::{FieldError, OrderStatus};
+use http::form::encode_urlencoded;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+pub const DEFAULT_PER_PAGE: usize = 20;
+pub const MAX_PER_PAGE: usize = 100;
+
+pub const FIELDS: [&str; 3] = ["order_id", "order_date", "order_status"];
+
+/// How to page through a list of orders: by page number or after an order ID,
+/// in which order, and with which fields.
+#[derive(Debug, PartialEq)]
+pub struct ListQuery {
+    page: usize,
+    per_page: usize,
+    /// Cursor: start after the order with this ID instead of at a page.
+    after: Option<i32>,
+    /// Field names, each descending if `true`.
+    sort: Vec<(String, bool)>,
+    fields: Option<Vec<String>>,
+    /// The other query parameters, such as filters, carried over into links.
+    params: Vec<(String, String)>,
+}
+
+/// One page of orders with what a client needs to fetch the others.
+#[derive(Debug)]
+pub struct Page {
+    pub items: Vec<Value>,
+    pub total: usize,
+    /// `rel` and URL pairs for the `Link` header.
+    pub links: Vec<(&'static str, String)>,
+}
+impl Page {
+    pub fn link_header(&self) -> Option<String> {
+        if self.links.is_empty() {
+            return None;
+        }
+        let links: Vec<String> = self
+            .links
+            .iter()
+            .map(|(rel, url)| format!("<{}>; rel=\"{}\"", url, rel))
+            .collect();
+        Some(links.join(", "))
+    }
+}
+
+/// Parses a whole number of at least 1 and at most `max`, if given.
+fn number<T: std::str::FromStr + PartialOrd + From<u8> + std::fmt::Display>(
+    name: &str,
+    value: &str,
+    max: Option<T>,
+) -> Result<T, FieldError> {
+    let in_range = |n: &T| *n >= T::from(1) && max.as_ref().is_none_or(|max| n <= max);
+    value.parse::<T>().ok().filter(in_range).ok_or_else(|| {
+        let expected = match &max {
+            Some(max) => format!("a whole number from 1 to {}", max),
+            None => "a positive whole number".to_string(),
+        };
+        FieldError::new(name, format!("must be {}, got \"{}\"", expected, value))
+    })
+}
+
+/// Splits a comma-separated list of field names, rejecting unknown ones.
+fn field_list(name: &str, value: &str, errors: &mut Vec<FieldError>) -> Vec<(String, bool)> {
+    let mut list = Vec::new();
+    for item in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (field, descending) = match item.strip_prefix('-') {
+            Some(field) if name == "sort" => (field, true),
+            _ => (item, false),
+        };
+        if FIELDS.contains(&field) {
+            list.push((field.to_string(), descending));
+        } else {
+            errors.push(FieldError::new(name, format!("unknown field \"{}\"", field)));
+        }
+    }
+    list
+}
+
+fn compare(a: &OrderStatus, b: &OrderStatus, field: &str) -> Ordering {
+    match field {
+        "order_date" => a.order_date.cmp(&b.order_date),
+        "order_status" => a.order_status.cmp(&b.order_status),
+        _ => a.order_id.cmp(&b.order_id),
+    }
+}
+
+impl ListQuery {
+    /// Reads `page`, `per_page`, `after`, `sort` and `fields` from the query parameters.
+    pub fn from_params(params: &[(String, String)]) -> Result<ListQuery, Vec<FieldError>> {
+        let mut query = ListQuery {
+            page: 1,
+            per_page: DEFAULT_PER_PAGE,
+            after: None,
+            sort: Vec::new(),
+            fields: None,
+            params: Vec::new(),
+        };
+        let mut errors = Vec::new();
+        let mut has_page = false;
+        for (name, value) in params {
+            let result = match name.as_str() {
+                "page" => {
+                    has_page = true;
+                    number::<usize>(name, value, None).map(|n| query.page = n)
+                }
+                "per_page" => number(name, value, Some(MAX_PER_PAGE)).map(|n| query.per_page = n),
+                "after" => number::<i32>(name, value, None).map(|n| query.after = Some(n)),
+                "sort" => {
+                    query.sort = field_list(name, value, &mut errors);
+                    Ok(())
+                }
+                "fields" => {
+                    let fields = field_list(name, value, &mut errors);
+                    query.fields = Some(fields.into_iter().map(|(f, _)| f).collect());
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                errors.push(e);
+            }
+            if name != "page" && name != "after" {
+                query.params.push((name.clone(), value.clone()));
+            }
+        }
+        if has_page && query.after.is_some() {
+            errors.push(FieldError::new("after", "cannot be combined with page"));
+        }
+        if errors.is_empty() {
+            Ok(query)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Sorts `orders` and cuts out the requested page. Links point at `path`.
+    pub fn apply(&self, mut orders: Vec<OrderStatus>, path: &str) -> Result<Page, Vec<FieldError>> {
+        self.sort(&mut orders);
+        let total = orders.len();
+        let last_page = total.div_ceil(self.per_page).max(1);
+        let mut links = Vec::new();
+        let start = match self.after {
+            Some(after) => match orders.iter().position(|o| o.order_id == after) {
+                Some(i) => i + 1,
+                None => return Err(vec![FieldError::new("after", format!("no order {} in this list", after))]),
+            },
+            None => (self.page - 1).saturating_mul(self.per_page).min(total),
+        };
+        let end = (start + self.per_page).min(total);
+        match self.after {
+            Some(_) => {
+                links.push(("first", self.url(path, None)));
+                if end < total {
+                    let cursor = ("after", orders[end - 1].order_id.to_string());
+                    links.push(("next", self.url(path, Some(cursor))));
+                }
+            }
+            None => {
+                let page = |n: usize| self.url(path, Some(("page", n.to_string())));
+                links.push(("first", page(1)));
+                if self.page > 1 {
+                    links.push(("prev", page((self.page - 1).min(last_page))));
+                }
+                if self.page < last_page {
+                    links.push(("next", page(self.page + 1)));
+                }
+                links.push(("last", page(last_page)));
+            }
+        }
+        let items = orders[start..end].iter().map(|o| self.project(o)).collect();
+        Ok(Page { items, total, links })
+    }
+
+    /// Sorts `orders` by the `sort` fields.
+    pub fn sort(&self, orders: &mut [OrderStatus]) {
+        // Ties fall back to the ID so that pages and cursors are stable.
+        orders.sort_by(|a, b| {
+            self.sort
+                .iter()
+                .map(|(field, descending)| {
+                    let order = compare(a, b, field);
+                    if *descending {
+                        order.reverse()
+                    } else {
+                        order
+                    }
+                })
+                .find(|o| o.is_ne())
+                .unwrap_or_else(|| a.order_id.cmp(&b.order_id))
+        });
+    }
+
+    /// The fields `fields` selects, in the usual order, or all of them.
+    pub fn columns(&self) -> Vec<&'static str> {
+        match &self.fields {
+            Some(fields) => FIELDS.into_iter().filter(|f| fields.iter().any(|s| s == f)).collect(),
+            None => FIELDS.to_vec(),
+        }
+    }
+
+    /// `order` as JSON, with only the selected fields.
+    pub fn project(&self, order: &OrderStatus) -> Value {
+        let mut value = serde_json::to_value(order).unwrap_or(Value::Null);
+        if let (Some(fields), Value::Object(map)) = (&self.fields, &mut value) {
+            map.retain(|k, _| fields.contains(k));
+        }
+        value
+    }
+
+    fn url(&self, path: &str, position: Option<(&str, String)>) -> String {
+        let mut params = self.params.clone();
+        if let Some((name, value)) = position {
+            params.push((name.to_string(), value));
+        }
+        if params.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}?{}", path, encode_urlencoded(&params))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{parse_date, Status};
+
+    fn orders() -> Vec<OrderStatus> {
+        (1..=5)
+            .map(|id| OrderStatus {
+                order_id: id,
+                order_date: parse_date(&format!("2022-01-{:02}", 10 - id)).unwrap(),
+                order_status: if id % 2 == 0 { Status::Shipped } else { Status::Pending },
+            })
+            .collect()
+    }
+
+    fn query(q: &str) -> Result<ListQuery, Vec<FieldError>> {
+        ListQuery::from_params(&http::form::parse_urlencoded(q))
+    }
+
+    fn ids(page: &Page) -> Vec<i64> {
+        page.items.iter().map(|o| o["order_id"].as_i64().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_pages_and_links() {
+        let page = query("per_page=2&page=2&order_status=pending").unwrap().apply(orders(), "/o").unwrap();
+        assert_eq!(ids(&page), [3, 4]);
+        assert_eq!(page.total, 5);
+        assert_eq!(
+            page.link_header().unwrap(),
+            "</o?per_page=2&order_status=pending&page=1>; rel=\"first\", \
+             </o?per_page=2&order_status=pending&page=1>; rel=\"prev\", \
+             </o?per_page=2&order_status=pending&page=3>; rel=\"next\", \
+             </o?per_page=2&order_status=pending&page=3>; rel=\"last\""
+        );
+        let beyond = query("page=9").unwrap().apply(orders(), "/o").unwrap();
+        assert!(beyond.items.is_empty());
+    }
+
+    #[test]
+    fn test_sort_cursor_and_fields() {
+        let q = query("sort=-order_status,order_date&per_page=2&after=4&fields=order_id").unwrap();
+        let page = q.apply(orders(), "/o").unwrap();
+        // Shipped (4, 2) sort before Pending (5, 3, 1), each by date ascending.
+        assert_eq!(ids(&page), [2, 5]);
+        assert_eq!(page.items[0], serde_json::json!({ "order_id": 2 }));
+        assert_eq!(page.links[1], ("next", "/o?sort=-order_status%2Corder_date&per_page=2&fields=order_id&after=5".to_string()));
+        assert!(q.apply(orders()[..1].to_vec(), "/o").is_err());
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let errors = query("page=0&per_page=1000&sort=price&fields=order_id,secret").unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["page", "per_page", "sort", "fields"]);
+        assert!(query("page=1&after=3").is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use crate::config::{LogConfig, LogFormat};
+use tracing_subscriber::EnvFilter;
+
+/// The configured level, unless `RUST_LOG` is set.
+fn filter(config: &LogConfig) -> Result<EnvFilter, String> {
+    match EnvFilter::try_from_default_env() {
+        Ok(filter) => Ok(filter),
+        Err(_) => EnvFilter::try_new(&config.level).map_err(|e| format!("log level {:?}: {}", config.level, e)),
+    }
+}
+
+/// Installs the global `tracing` subscriber. `RUST_LOG`, if set, overrides the configured level.
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let builder = tracing_subscriber::fmt().with_env_filter(filter(config)?);
+    let result = match config.format {
+        LogFormat::Text => builder.try_init(),
+        // One JSON object per line, with the fields of the enclosing connection and request spans.
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).try_init(),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tracing::Level;
+
+    #[test]
+    fn test_rust_log_overrides_the_configured_level() {
+        let config = LogConfig { level: "httpserver=loud".into(), ..LogConfig::default() };
+        env::remove_var("RUST_LOG");
+        assert!(filter(&config).is_err());
+        assert!(filter(&LogConfig::default()).is_ok());
+        // Only this module, so that the other tests don't log.
+        env::set_var("RUST_LOG", "httpserver::logging=trace");
+        let installed = init(&config);
+        env::remove_var("RUST_LOG");
+        installed.unwrap();
+        assert!(tracing::enabled!(target: "httpserver::logging", Level::TRACE));
+        assert!(!tracing::enabled!(target: "httpserver::server", Level::ERROR));
+    }
+}
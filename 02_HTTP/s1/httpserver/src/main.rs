@@ -1,9 +1,57 @@
+use config::ServerConfig;
 use server::Server;
+use std::time::Duration;
+use tracing::warn;
 
+mod accesslog;
+mod auth;
+mod autoindex;
+mod cache;
+mod config;
+mod cors;
+mod export;
 mod handler;
+mod listener;
+mod listing;
+mod logging;
+mod metrics;
+mod proxy;
+mod ratelimit;
 mod router;
 mod server;
+mod shutdown;
+mod store;
+mod template;
+#[cfg(test)]
+mod testing;
+mod vhost;
 fn main() {
-    let server = Server::new("localhost:3000");
+    let config = ServerConfig::load().unwrap_or_else(|e| panic!("Invalid config: {}", e));
+    logging::init(&config.log).unwrap_or_else(|e| panic!("Invalid log config: {}", e));
+    let hosts = config.virtual_hosts().unwrap_or_else(|e| panic!("Invalid config: {}", e));
+    let socket_mode = config.socket_mode().unwrap_or_else(|e| panic!("Invalid config: {}", e));
+    let access_log = config.access_log().unwrap_or_else(|e| panic!("Invalid access log config: {}", e));
+    let reopen = access_log.as_ref().map(|log| log.reopen_handle()).unwrap_or_default();
+    let inherited = listener::from_env().unwrap_or_else(|e| panic!("Invalid socket activation: {}", e));
+    let mut server = Server::new(&config.address, hosts)
+        .socket_mode(socket_mode)
+        .proxy_protocol(config.proxy_protocol)
+        .listeners(inherited)
+        .shutdown_timeout(Duration::from_millis(config.shutdown_timeout_ms))
+        .http2(config.http2)
+        .access_log(access_log)
+        .on_reload(|| {
+            let reloaded = ServerConfig::load()?;
+            if reloaded.address != config.address {
+                warn!(address = %reloaded.address, "the listen address can't change without a restart");
+            }
+            reloaded.virtual_hosts()
+        });
+    for listener in &config.listeners {
+        let socket_mode = listener.socket_mode().unwrap_or_else(|e| panic!("Invalid config: {}", e));
+        server = server.listen(&listener.address, socket_mode, listener.proxy_protocol);
+    }
+    shutdown::handle_signals(server.shutdown_handle(), server.reload_handle(), reopen)
+        .unwrap_or_else(|e| panic!("Failed to install signal handlers: {}", e));
     server.run();
 }
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket of `LATENCY_BUCKETS`, plus one for anything slower.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let i = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[i] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Per-route and per-status counts and latencies, kept together under one lock.
+#[derive(Default)]
+struct RequestStats {
+    /// Requests by method and route.
+    requests: BTreeMap<(String, String), u64>,
+    /// Responses by status code; 0 when the connection closed without one.
+    statuses: BTreeMap<u16, u64>,
+    latencies: BTreeMap<String, Histogram>,
+}
+
+/// Counters for the whole server process, shown on the health page and
+/// exported in the Prometheus text format.
+pub struct Metrics {
+    started: Instant,
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    requests: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    stats: Mutex<RequestStats>,
+}
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            stats: Mutex::new(RequestStats::default()),
+        }
+    }
+    pub fn global() -> &'static Metrics {
+        static METRICS: OnceLock<Metrics> = OnceLock::new();
+        METRICS.get_or_init(Metrics::new)
+    }
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+    pub fn request_received(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Records a finished request. `route` is the matched route pattern rather
+    /// than the raw path, so that the number of series stays bounded.
+    pub fn request_finished(&self, method: &str, route: &str, status: Option<u16>, duration: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        *stats.requests.entry((method.to_string(), route.to_string())).or_default() += 1;
+        *stats.statuses.entry(status.unwrap_or(0)).or_default() += 1;
+        stats
+            .latencies
+            .entry(route.to_string())
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+    pub fn bytes_transferred(&self, received: u64, sent: u64) {
+        self.bytes_received.fetch_add(received, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+    }
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// All metrics in the Prometheus text exposition format (version 0.0.4).
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut single = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+        };
+        single("process_uptime_seconds", "gauge", "Seconds since the server started.", format!("{:.3}", self.uptime().as_secs_f64()));
+        single("http_connections_total", "counter", "Connections accepted.", self.connections().to_string());
+        single("http_connections_active", "gauge", "Connections currently open.", self.active_connections().to_string());
+        single("http_received_bytes_total", "counter", "Bytes read from clients.", self.bytes_received.load(Ordering::Relaxed).to_string());
+        single("http_sent_bytes_total", "counter", "Bytes written to clients.", self.bytes_sent.load(Ordering::Relaxed).to_string());
+
+        let stats = self.stats.lock().unwrap();
+        out.push_str("# HELP http_requests_total Requests by method and route.\n# TYPE http_requests_total counter\n");
+        for ((method, route), count) in &stats.requests {
+            let _ = writeln!(out, "http_requests_total{{method=\"{}\",route=\"{}\"}} {}", escape_label(method), escape_label(route), count);
+        }
+        out.push_str("# HELP http_responses_total Responses by status code.\n# TYPE http_responses_total counter\n");
+        for (status, count) in &stats.statuses {
+            let _ = writeln!(out, "http_responses_total{{status=\"{}\"}} {}", status, count);
+        }
+        out.push_str("# HELP http_request_duration_seconds Request latency by route.\n# TYPE http_request_duration_seconds histogram\n");
+        for (route, histogram) in &stats.latencies {
+            let route = escape_label(route);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}", route, bound, cumulative);
+            }
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}", route, histogram.count);
+            let _ = writeln!(out, "http_request_duration_seconds_sum{{route=\"{}\"}} {}", route, histogram.sum);
+            let _ = writeln!(out, "http_request_duration_seconds_count{{route=\"{}\"}} {}", route, histogram.count);
+        }
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Formats a duration as e.g. `2d 3h 4m 5s`, leaving out leading zero units.
+pub fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let units = [(secs / 86_400, "d"), (secs / 3_600 % 24, "h"), (secs / 60 % 60, "m")];
+    let mut out: Vec<String> = units
+        .iter()
+        .skip_while(|(n, _)| *n == 0)
+        .map(|(n, unit)| format!("{}{}", n, unit))
+        .collect();
+    out.push(format!("{}s", secs % 60));
+    out.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus() {
+        let metrics = Metrics::new();
+        metrics.connection_opened();
+        metrics.bytes_transferred(100, 250);
+        metrics.request_finished("GET", "/api/shipping/orders/:id", Some(200), Duration::from_millis(20));
+        metrics.request_finished("GET", "/api/shipping/orders/:id", Some(404), Duration::from_secs(20));
+        let text = metrics.render_prometheus();
+        for line in [
+            "http_connections_active 1",
+            "http_sent_bytes_total 250",
+            "http_requests_total{method=\"GET\",route=\"/api/shipping/orders/:id\"} 2",
+            "http_responses_total{status=\"404\"} 1",
+            "http_request_duration_seconds_bucket{route=\"/api/shipping/orders/:id\",le=\"0.01\"} 0",
+            "http_request_duration_seconds_bucket{route=\"/api/shipping/orders/:id\",le=\"0.025\"} 1",
+            "http_request_duration_seconds_bucket{route=\"/api/shipping/orders/:id\",le=\"10\"} 1",
+            "http_request_duration_seconds_bucket{route=\"/api/shipping/orders/:id\",le=\"+Inf\"} 2",
+            "http_request_duration_seconds_count{route=\"/api/shipping/orders/:id\"} 2",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert_eq!(escape_label("a\"b\\"), "a\\\"b\\\\");
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(5)), "5s");
+        assert_eq!(format_uptime(Duration::from_secs(3_600)), "1h 0m 0s");
+        assert_eq!(format_uptime(Duration::from_secs(2 * 86_400 + 3 * 3_600 + 4 * 60 + 5)), "2d 3h 4m 5s");
+    }
+}
@@ -0,0 +1,430 @@
+use crate::server::Connection;
+use http::chunked::{ChunkedReader, ChunkedWriter};
+use http::httprequest::{HttpRequest, Method, Resource};
+use http::httpresponse::{HttpResponse, ResponseHead};
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Headers that describe a single hop and must not be forwarded (RFC 7230, 6.1).
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+struct Upstream {
+    addr: String,
+    failures: AtomicU32,
+    down_until: Mutex<Option<Instant>>,
+}
+
+/// Why a request could not be forwarded, and so which error the client sees.
+enum ProxyError {
+    BadGateway,
+    Timeout,
+}
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ProxyError::Timeout,
+            _ => ProxyError::BadGateway,
+        }
+    }
+}
+
+/// Forwards requests under a path prefix to one of several upstream servers,
+/// picked round-robin. An upstream that fails `max_fails` times in a row is
+/// skipped for `fail_timeout` (a passive health check).
+pub struct ProxyHandler {
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    strip_prefix: bool,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    max_fails: u32,
+    fail_timeout: Duration,
+}
+impl ProxyHandler {
+    pub fn new(upstreams: &[&str]) -> Self {
+        ProxyHandler {
+            upstreams: upstreams
+                .iter()
+                .map(|addr| Upstream {
+                    addr: addr.to_string(),
+                    failures: AtomicU32::new(0),
+                    down_until: Mutex::new(None),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            strip_prefix: false,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(30),
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(10),
+        }
+    }
+    /// Forwards `/prefix/rest` as `/rest` instead of the full path.
+    pub fn strip_prefix(mut self, strip: bool) -> Self {
+        self.strip_prefix = strip;
+        self
+    }
+    pub fn timeouts(mut self, connect: Duration, read: Duration) -> Self {
+        self.connect_timeout = connect;
+        self.read_timeout = read;
+        self
+    }
+    pub fn health_check(mut self, max_fails: u32, fail_timeout: Duration) -> Self {
+        self.max_fails = max_fails;
+        self.fail_timeout = fail_timeout;
+        self
+    }
+
+    /// Forwards `req`, whose body is still unread in `client`, and streams the
+    /// upstream response back. Sends 502 or 504 if no upstream answers.
+    pub fn handle(&self, prefix: &str, req: &HttpRequest, client: &mut dyn Connection) {
+        if let Err(e) = self.forward(prefix, req, client) {
+            let (status, text) = match e {
+                ProxyError::BadGateway => ("502", "Bad Gateway"),
+                ProxyError::Timeout => ("504", "Gateway Timeout"),
+            };
+            let mut headers: HashMap<&str, &str> = HashMap::new();
+            headers.insert("Content-Type", "text/plain");
+            let resp = HttpResponse::new(status, Some(headers), Some(text.into()));
+            let _ = resp.send_response(client);
+        }
+    }
+
+    fn forward(&self, prefix: &str, req: &HttpRequest, client: &mut dyn Connection) -> Result<(), ProxyError> {
+        let (upstream, stream) = self.connect()?;
+        let result = self.exchange(upstream, stream, prefix, req, client);
+        match &result {
+            Ok(_) => upstream.failures.store(0, Ordering::Relaxed),
+            Err(_) => self.mark_failed(upstream),
+        }
+        result.map(|_| ())
+    }
+
+    /// Tries each live upstream once, starting from the round-robin position.
+    fn connect(&self) -> Result<(&Upstream, TcpStream), ProxyError> {
+        let count = self.upstreams.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut error = ProxyError::BadGateway;
+        for i in 0..count {
+            let upstream = &self.upstreams[(start + i) % count];
+            if !self.is_up(upstream) {
+                continue;
+            }
+            match self.connect_to(&upstream.addr) {
+                Ok(stream) => return Ok((upstream, stream)),
+                Err(e) => {
+                    self.mark_failed(upstream);
+                    error = e.into();
+                }
+            }
+        }
+        Err(error)
+    }
+
+    fn connect_to(&self, addr: &str) -> io::Result<TcpStream> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no address");
+        for sock_addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&sock_addr, self.connect_timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.read_timeout))?;
+                    stream.set_write_timeout(Some(self.read_timeout))?;
+                    return Ok(stream);
+                }
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn is_up(&self, upstream: &Upstream) -> bool {
+        let mut down_until = upstream.down_until.lock().unwrap();
+        match *down_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                // Give it another chance; one more failure takes it down again.
+                *down_until = None;
+                upstream.failures.store(self.max_fails.saturating_sub(1), Ordering::Relaxed);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn mark_failed(&self, upstream: &Upstream) {
+        let failures = upstream.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.max_fails {
+            *upstream.down_until.lock().unwrap() = Some(Instant::now() + self.fail_timeout);
+            warn!(upstream = %upstream.addr, failures, "upstream marked down");
+        }
+    }
+
+    fn exchange(
+        &self,
+        upstream: &Upstream,
+        mut stream: TcpStream,
+        prefix: &str,
+        req: &HttpRequest,
+        client: &mut dyn Connection,
+    ) -> Result<(), ProxyError> {
+        self.send_request(upstream, &mut stream, prefix, req, client)?;
+        let mut reader = BufReader::new(stream);
+        let head = ResponseHead::read_from(&mut reader)?;
+        // From here on the client has seen part of the response, so failures
+        // can only be reported by closing the connection.
+        let _ = Self::send_response(&head, &mut reader, req, client);
+        Ok(())
+    }
+
+    fn send_request(
+        &self,
+        upstream: &Upstream,
+        stream: &mut TcpStream,
+        prefix: &str,
+        req: &HttpRequest,
+        client: &mut dyn Connection,
+    ) -> io::Result<()> {
+        let Resource::Path(path) = &req.resource;
+        let path = match path.strip_prefix(prefix) {
+            Some(rest) if self.strip_prefix => {
+                if rest.starts_with('/') {
+                    rest.to_string()
+                } else {
+                    format!("/{}", rest)
+                }
+            }
+            _ => path.clone(),
+        };
+        // A body already read, as over HTTP/2, is sent as it is; otherwise it's copied from
+        // the client with the framing it came with.
+        let buffered = !req.msg_body.is_empty();
+        let content_length = if buffered {
+            Some(req.msg_body.len())
+        } else if req.is_chunked() {
+            None
+        } else {
+            req.content_length()?
+        };
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", req.method.as_str(), path, upstream.addr);
+        for (k, v) in req.headers.iter() {
+            let (k, v) = (k.trim(), v.trim());
+            let lower = k.to_ascii_lowercase();
+            if HOP_BY_HOP.contains(&lower.as_str())
+                || lower == "host"
+                || lower == "content-length"
+                || lower.starts_with("x-forwarded-")
+            {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        // A Unix socket peer has no address to add, but the hops before it are kept.
+        let forwarded_for = match (req.header("X-Forwarded-For"), client.peer_addr()) {
+            (Some(prior), Some(peer)) => Some(format!("{}, {}", prior, peer.ip())),
+            (None, Some(peer)) => Some(peer.ip().to_string()),
+            (prior, None) => prior.map(str::to_string),
+        };
+        if let Some(forwarded_for) = forwarded_for {
+            head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+        }
+        if let Some(host) = req.header("Host") {
+            head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+        }
+        head.push_str("X-Forwarded-Proto: http\r\n");
+        match content_length {
+            Some(len) => head.push_str(&format!("Content-Length: {}\r\n", len)),
+            None if req.is_chunked() => head.push_str("Transfer-Encoding: chunked\r\n"),
+            None => {}
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        stream.write_all(head.as_bytes())?;
+
+        if buffered {
+            stream.write_all(&req.msg_body)?;
+        } else if req.is_chunked() {
+            let mut body = ChunkedWriter::new(&mut *stream);
+            io::copy(&mut ChunkedReader::new(&mut *client), &mut body)?;
+            body.finish()?;
+        } else if let Some(len) = content_length {
+            io::copy(&mut client.take(len as u64), stream)?;
+        }
+        stream.flush()
+    }
+
+    fn send_response(
+        head: &ResponseHead,
+        upstream: &mut BufReader<TcpStream>,
+        req: &HttpRequest,
+        client: &mut dyn Connection,
+    ) -> io::Result<()> {
+        let mut out = format!("HTTP/1.1 {} {}\r\n", head.status_code, head.status_text);
+        for (k, v) in head.headers.iter() {
+            if !HOP_BY_HOP.contains(&k.to_ascii_lowercase().as_str()) {
+                out.push_str(&format!("{}: {}\r\n", k, v));
+            }
+        }
+        let no_body = req.method == Method::HEAD
+            || head.status_code == 204
+            || head.status_code == 304
+            || head.status_code < 200;
+        if no_body {
+            out.push_str("\r\n");
+            client.write_all(out.as_bytes())?;
+            return client.flush();
+        }
+        if head.is_chunked() {
+            out.push_str("Transfer-Encoding: chunked\r\n\r\n");
+            client.write_all(out.as_bytes())?;
+            let mut body = ChunkedWriter::new(&mut *client);
+            io::copy(&mut ChunkedReader::new(upstream), &mut body)?;
+            body.finish()
+        } else if let Some(len) = head.content_length() {
+            out.push_str("\r\n");
+            client.write_all(out.as_bytes())?;
+            io::copy(&mut upstream.take(len), client)?;
+            client.flush()
+        } else {
+            // No framing: the body runs until the upstream closes, and so does ours.
+            out.push_str("Connection: close\r\n\r\n");
+            client.write_all(out.as_bytes())?;
+            io::copy(upstream, client)?;
+            client.flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MemoryStream, TestResponse};
+    use std::io::BufRead;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// An upstream that answers `count` requests with its `name`, passing on each request it got.
+    fn upstream(name: &'static str, count: usize) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (requests, received) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut head = String::new();
+                while stream.read_line(&mut head).unwrap() > 2 {}
+                let len = HttpRequest::from(head.clone()).content_length().unwrap().unwrap_or(0);
+                let mut body = vec![0; len];
+                stream.read_exact(&mut body).unwrap();
+                let _ = requests.send(head + &String::from_utf8(body).unwrap());
+                let resp = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", name.len(), name);
+                stream.get_mut().write_all(resp.as_bytes()).unwrap();
+            }
+        });
+        (addr, received)
+    }
+
+    /// An address nothing listens on.
+    fn closed_port() -> String {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+    }
+
+    fn forward(proxy: &ProxyHandler, prefix: &str, raw: &str) -> TestResponse {
+        let mut client = MemoryStream::new(raw.as_bytes().to_vec(), "127.0.0.1:40000".parse().unwrap());
+        let req = HttpRequest::read_head(&mut client).unwrap();
+        proxy.handle(prefix, &req, &mut client);
+        TestResponse::parse(client.output())
+    }
+
+    #[test]
+    fn test_forwarded_request_head() {
+        let (addr, requests) = upstream("a", 2);
+        let proxy = ProxyHandler::new(&[&addr]).strip_prefix(true);
+        let raw = "POST /app/items?x=1 HTTP/1.1\r\nHost: shop.test\r\nX-Forwarded-For: 10.0.0.1\r\n\
+                   X-Forwarded-Proto: https\r\nConnection: keep-alive\r\nContent-Length: 5\r\n\r\nhello";
+        forward(&proxy, "/app", raw).assert_status(200).assert_body_contains("a");
+        let forwarded = requests.recv().unwrap();
+        assert!(forwarded.starts_with("POST /items?x=1 HTTP/1.1\r\n"), "{}", forwarded);
+        for line in [
+            format!("Host: {}", addr),
+            "X-Forwarded-For: 10.0.0.1, 127.0.0.1".to_string(),
+            "X-Forwarded-Host: shop.test".to_string(),
+            "X-Forwarded-Proto: http".to_string(),
+            "Content-Length: 5".to_string(),
+            "Connection: close".to_string(),
+        ] {
+            assert!(forwarded.contains(&format!("{}\r\n", line)), "{:?} in {}", line, forwarded);
+        }
+        assert!(!forwarded.contains("\nHost: shop.test") && !forwarded.contains("https") && !forwarded.contains("keep-alive"));
+        assert!(forwarded.ends_with("\r\n\r\nhello"));
+
+        // A body read already, as over HTTP/2, is sent with its length.
+        let mut client = MemoryStream::new(Vec::new(), "127.0.0.1:40000".parse().unwrap());
+        let mut req = HttpRequest::from(String::from("PUT /app HTTP/1.1\r\nHost: shop.test\r\n\r\n"));
+        req.msg_body = b"hello".to_vec();
+        ProxyHandler::new(&[&addr]).handle("/app", &req, &mut client);
+        let forwarded = requests.recv().unwrap();
+        assert!(forwarded.starts_with("PUT /app HTTP/1.1\r\n"), "{}", forwarded);
+        assert!(forwarded.contains("Content-Length: 5\r\n") && forwarded.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_unix_peers_keep_forwarded_for() {
+        let (addr, requests) = upstream("a", 1);
+        let proxy = ProxyHandler::new(&[&addr]);
+        let mut client = MemoryStream::unix(b"GET /app HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7\r\n\r\n".to_vec());
+        let req = HttpRequest::read_head(&mut client).unwrap();
+        proxy.handle("/app", &req, &mut client);
+        TestResponse::parse(client.output()).assert_status(200);
+        let forwarded = requests.recv().unwrap();
+        assert!(forwarded.contains("\r\nX-Forwarded-For: 203.0.113.7\r\n"), "{}", forwarded);
+    }
+
+    #[test]
+    fn test_round_robin_skips_failed_upstreams() {
+        let (a, _) = upstream("a", 6);
+        let (b, _) = upstream("b", 2);
+        let proxy = ProxyHandler::new(&[&a, &b]);
+        let picked: Vec<String> = (0..3).map(|_| forward(&proxy, "/", "GET / HTTP/1.1\r\n\r\n").text()).collect();
+        assert_eq!(picked, ["a", "b", "a"]);
+
+        // Down after `max_fails` failures, until `fail_timeout` is over.
+        let proxy = ProxyHandler::new(&[&a, &b]).health_check(2, Duration::from_secs(60));
+        proxy.mark_failed(&proxy.upstreams[1]);
+        assert!(proxy.is_up(&proxy.upstreams[1]));
+        proxy.mark_failed(&proxy.upstreams[1]);
+        assert!(!proxy.is_up(&proxy.upstreams[1]));
+        let picked: Vec<String> = (0..2).map(|_| forward(&proxy, "/", "GET / HTTP/1.1\r\n\r\n").text()).collect();
+        assert_eq!(picked, ["a", "a"]);
+
+        // An upstream that refuses the connection is marked failed and the next one tried.
+        let proxy = ProxyHandler::new(&[&closed_port(), &a]).health_check(1, Duration::from_secs(60));
+        assert_eq!(forward(&proxy, "/", "GET / HTTP/1.1\r\n\r\n").text(), "a");
+        assert!(!proxy.is_up(&proxy.upstreams[0]));
+        assert_eq!(forward(&proxy, "/", "GET / HTTP/1.1\r\n\r\n").text(), "a");
+    }
+
+    #[test]
+    fn test_gateway_errors() {
+        let proxy = ProxyHandler::new(&[&closed_port()]);
+        forward(&proxy, "/", "GET / HTTP/1.1\r\n\r\n").assert_status(502);
+
+        // Accepted by the kernel, never answered.
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+        let proxy = ProxyHandler::new(&[&addr]).timeouts(Duration::from_secs(1), Duration::from_millis(100));
+        forward(&proxy, "/", "GET / HTTP/1.1\r\n\r\n").assert_status(504);
+    }
+}
@@ -0,0 +1,263 @@
+use crate::router::under_prefix;
+use http::httprequest::HttpRequest;
+use http::httpresponse::HttpResponse;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often buckets that have refilled completely are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// `requests` per `period`, with bursts of up to `burst` requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub requests: u32,
+    pub period: Duration,
+    pub burst: u32,
+}
+impl Limit {
+    fn refill_per_sec(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+    /// How long an empty bucket holding `tokens` takes to get `wanted` tokens.
+    fn time_to(&self, tokens: f64, wanted: f64) -> Duration {
+        Duration::from_secs_f64(((wanted - tokens).max(0.0) / self.refill_per_sec()).min(1e9))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill_per_sec()).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+struct Buckets {
+    /// Keyed by the index of the rule and the client.
+    map: HashMap<(usize, String), Bucket>,
+    swept: Instant,
+}
+
+/// Why a request was turned away, for the `RateLimit-*` headers.
+#[derive(Debug, PartialEq)]
+pub struct Limited {
+    pub limit: u32,
+    /// Until the bucket is full again.
+    pub reset: Duration,
+    /// Until the next request would be allowed.
+    pub retry_after: Duration,
+}
+
+/// Token buckets per client and route. Clients are told apart by their address,
+/// or by a header such as `X-Forwarded-For` when they connect through a trusted proxy.
+pub struct RateLimiter {
+    /// Path prefixes and their limits; an empty prefix matches every path.
+    rules: Vec<(String, Limit)>,
+    key_header: Option<String>,
+    trusted_proxies: Vec<IpAddr>,
+    /// Whether clients of a Unix socket, which have no address, are trusted proxies.
+    trust_unix_peers: bool,
+    buckets: Mutex<Buckets>,
+}
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter {
+            rules: Vec::new(),
+            key_header: None,
+            trusted_proxies: Vec::new(),
+            trust_unix_peers: false,
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+}
+impl RateLimiter {
+    /// Limits requests under `prefix`; the longest matching prefix applies, and `/` matches everything.
+    pub fn route(mut self, prefix: &str, limit: Limit) -> Self {
+        self.rules.push((prefix.trim_end_matches('/').to_string(), limit));
+        self
+    }
+    /// Tells clients apart by `header` on connections from `trusted_proxies`.
+    pub fn key_header(mut self, header: &str, trusted_proxies: Vec<IpAddr>) -> Self {
+        self.key_header = Some(header.to_string());
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+    /// Also trusts the key header on Unix socket connections, e.g. from a local nginx.
+    /// Without it they all count against one client.
+    pub fn trust_unix_peers(mut self, trust: bool) -> Self {
+        self.trust_unix_peers = trust;
+        self
+    }
+
+    fn rule(&self, path: &str) -> Option<usize> {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter(|(_, (prefix, _))| under_prefix(path, prefix))
+            .max_by_key(|(_, (prefix, _))| prefix.len())
+            .map(|(i, _)| i)
+    }
+
+    /// Who the request counts against: the peer, unless it is a trusted proxy
+    /// that names the client in the key header. `peer` is `None` on a Unix socket.
+    pub fn client_key(&self, req: &HttpRequest, peer: Option<IpAddr>) -> String {
+        let forwarded = match (&self.key_header, peer) {
+            (Some(header), Some(ip)) if self.trusted_proxies.contains(&ip) => req.header(header),
+            (Some(header), None) if self.trust_unix_peers => req.header(header),
+            _ => None,
+        };
+        let trusted = |hop: &&str| hop.parse::<IpAddr>().is_ok_and(|ip| self.trusted_proxies.contains(&ip));
+        match forwarded {
+            // Proxies append to the list, so the last hop that isn't one of ours is the client.
+            Some(value) => {
+                let hops: Vec<&str> = value.split(',').map(str::trim).filter(|h| !h.is_empty()).collect();
+                match hops.iter().rev().find(|hop| !trusted(hop)).or(hops.first()) {
+                    Some(hop) => hop.to_string(),
+                    None => peer.map(|ip| ip.to_string()).unwrap_or_default(),
+                }
+            }
+            None => peer.map(|ip| ip.to_string()).unwrap_or_default(),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket for `path`, if the path is limited.
+    pub fn acquire(&self, path: &str, client: &str, now: Instant) -> Result<(), Limited> {
+        let rule = match self.rule(path) {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+        let limit = &self.rules[rule].1;
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.saturating_duration_since(buckets.swept) >= SWEEP_INTERVAL {
+            self.sweep(&mut buckets, now);
+        }
+        let bucket = buckets.map.entry((rule, client.to_string())).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Limited {
+                limit: limit.burst,
+                reset: limit.time_to(bucket.tokens, limit.burst as f64),
+                retry_after: limit.time_to(bucket.tokens, 1.0),
+            })
+        }
+    }
+
+    /// Drops the buckets that have refilled since their last use; they would be created full anyway.
+    fn sweep(&self, buckets: &mut Buckets, now: Instant) {
+        buckets.map.retain(|(rule, _), bucket| {
+            bucket.refill(&self.rules[*rule].1, now);
+            bucket.tokens < self.rules[*rule].1.burst as f64
+        });
+        buckets.swept = now;
+    }
+
+    /// Counts the request against its client, or returns the 429 to send instead.
+    pub fn check<'a>(&self, req: &HttpRequest, peer: Option<IpAddr>) -> Result<(), HttpResponse<'a>> {
+        let client = self.client_key(req, peer);
+        self.acquire(req.path(), &client, Instant::now()).map_err(|limited| {
+            let secs = |d: Duration| d.as_secs_f64().ceil().max(1.0).to_string();
+            let mut headers: HashMap<&str, &str> = HashMap::new();
+            headers.insert("Content-Type", "application/json");
+            let body = json!({ "error_message": "Too many requests" }).to_string();
+            HttpResponse::new("429", Some(headers), Some(body))
+                .with_header("Retry-After", &secs(limited.retry_after))
+                .with_header("RateLimit-Limit", &limited.limit.to_string())
+                .with_header("RateLimit-Remaining", "0")
+                .with_header("RateLimit-Reset", &secs(limited.reset))
+        })
+    }
+
+    #[cfg(test)]
+    fn bucket_count(&self) -> usize {
+        self.buckets.lock().unwrap().map.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn per_second(requests: u32, burst: u32) -> Limit {
+        Limit {
+            requests,
+            period: Duration::from_secs(1),
+            burst,
+        }
+    }
+
+    #[test]
+    fn test_bucket_refills_and_routes_are_separate() {
+        let limiter = RateLimiter::default()
+            .route("/", per_second(100, 100))
+            .route("/api", per_second(2, 3));
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.acquire("/api/shipping/orders", "a", start).is_ok());
+        }
+        let limited = limiter.acquire("/api/shipping/orders", "a", start).unwrap_err();
+        assert_eq!(limited.limit, 3);
+        assert_eq!(limited.retry_after, Duration::from_millis(500));
+        assert_eq!(limited.reset, Duration::from_millis(1500));
+        // Other clients and other routes have their own buckets.
+        assert!(limiter.acquire("/api", "b", start).is_ok());
+        assert!(limiter.acquire("/index.html", "a", start).is_ok());
+        assert!(limiter.acquire("/apis", "a", start).is_ok());
+        assert!(limiter.acquire("/api", "a", start + Duration::from_millis(500)).is_ok());
+        assert!(limiter.acquire("/api", "a", start + Duration::from_millis(600)).is_err());
+        assert!(RateLimiter::default().acquire("/api", "a", start).is_ok());
+    }
+
+    #[test]
+    fn test_idle_buckets_are_swept() {
+        let limiter = RateLimiter::default().route("/", per_second(1, 5));
+        let start = Instant::now();
+        limiter.acquire("/", "idle", start).unwrap();
+        for _ in 0..5 {
+            let _ = limiter.acquire("/", "busy", start + SWEEP_INTERVAL - Duration::from_secs(1));
+        }
+        assert_eq!(limiter.bucket_count(), 2);
+        limiter.acquire("/", "new", start + SWEEP_INTERVAL).unwrap();
+        assert_eq!(limiter.bucket_count(), 2);
+    }
+
+    #[test]
+    fn test_client_key_trusts_only_known_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let limiter = RateLimiter::default().key_header("X-Forwarded-For", vec![proxy]);
+        let req: HttpRequest = "GET / HTTP/1.1\r\nX-Forwarded-For: 6.6.6.6, 203.0.113.7, 10.0.0.1\r\n\r\n"
+            .to_string()
+            .into();
+        assert_eq!(limiter.client_key(&req, Some(proxy)), "203.0.113.7");
+        assert_eq!(limiter.client_key(&req, Some("198.51.100.2".parse().unwrap())), "198.51.100.2");
+        let plain: HttpRequest = "GET / HTTP/1.1\r\n\r\n".to_string().into();
+        assert_eq!(limiter.client_key(&plain, Some(proxy)), "10.0.0.1");
+    }
+
+    #[test]
+    fn test_client_key_of_unix_peers() {
+        let req: HttpRequest = "GET / HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7\r\n\r\n".to_string().into();
+        let limiter = RateLimiter::default().key_header("X-Forwarded-For", Vec::new());
+        assert_eq!(limiter.client_key(&req, None), "");
+        let limiter = limiter.trust_unix_peers(true);
+        assert_eq!(limiter.client_key(&req, None), "203.0.113.7");
+        assert_eq!(limiter.client_key(&req, Some("198.51.100.2".parse().unwrap())), "198.51.100.2");
+        let plain: HttpRequest = "GET / HTTP/1.1\r\n\r\n".to_string().into();
+        assert_eq!(limiter.client_key(&plain, None), "");
+    }
+}
@@ -1,32 +1,647 @@
-use crate::handler::{WebServiceHandler, StaticPageHandler};
+use crate::auth::Auth;
+use crate::cache::{Lookup, ResponseCache};
+use crate::cors::{self, CorsPolicy};
+use crate::handler::{EventStreamHandler, Site, WebServiceHandler, StaticPageHandler, WebSocketHandler};
+use crate::metrics::Metrics;
+use crate::proxy::ProxyHandler;
+use crate::ratelimit::RateLimiter;
+use crate::server::{ClientAddr, Connection};
+use crate::store::OrderStore;
 use super::handler::{Handler, PageNotFoundHandler};
-use http::{httprequest, httprequest::HttpRequest, httpresponse::HttpResponse};
-use std::io::prelude::*;
+use http::httprequest::{Extensions, HttpRequest};
+use http::{httprequest, httpresponse::HttpResponse, sse, websocket};
+use std::collections::HashMap;
+use std::io::{self, BufWriter};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Instant, SystemTime};
+use tracing::{debug, warn};
 
-pub struct Router;
+pub const MAX_BODY_SIZE: usize = 20 * 1024 * 1024;
+
+/// Whether `path` is `prefix` or below it; the empty prefix contains every path.
+pub fn under_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// The routes of one (virtual) host.
+pub struct Router {
+    site: Site,
+    orders_api: bool,
+    websockets: HashMap<String, Box<dyn WebSocketHandler>>,
+    event_streams: HashMap<String, Box<dyn EventStreamHandler>>,
+    proxies: Vec<(String, ProxyHandler)>,
+    metrics_path: Option<String>,
+    auth: Option<Auth>,
+    rate_limiter: Option<RateLimiter>,
+    cors: Vec<(String, CorsPolicy)>,
+    cache: Option<Arc<ResponseCache>>,
+}
 impl Router{
-    pub fn route(req: HttpRequest, stream:&mut impl Write) -> (){
-        match req.method {
-            httprequest::Method::GET => match  &req.resource{
-                httprequest::Resource::Path(s) => {
-                    let route: Vec<&str> = s.split("/").collect();
-                    match route[1] {
-                        "api" => {
-                            let resp : HttpResponse = WebServiceHandler::handle(&req);
-                            let _ = resp.send_response(stream);
-                        }
-                        _=>{
-                            let resp: HttpResponse = StaticPageHandler::handle(&req);
-                            let _ = resp.send_response(stream);
-                        }
+    pub fn new(site: Site) -> Self {
+        Router {
+            site,
+            orders_api: true,
+            websockets: HashMap::new(),
+            event_streams: HashMap::new(),
+            proxies: Vec::new(),
+            metrics_path: None,
+            auth: None,
+            rate_limiter: None,
+            cors: Vec::new(),
+            cache: None,
+        }
+    }
+    /// Serves the server metrics in the Prometheus text format on `path`.
+    pub fn metrics(mut self, path: Option<&str>) -> Self {
+        self.metrics_path = path.map(str::to_string);
+        self
+    }
+    /// Lets browsers call the routes under `prefix` from the origins `policy` allows.
+    pub fn cors(mut self, prefix: &str, policy: CorsPolicy) -> Self {
+        self.cors.push((prefix.trim_end_matches('/').to_string(), policy));
+        self
+    }
+    /// Turns away clients sending more requests than `limiter` allows.
+    pub fn rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+    /// Answers repeated requests for pages and orders from `cache`, as their `Cache-Control` allows.
+    pub fn cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+    /// Requires credentials for the paths `auth` protects.
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+    /// Whether `/api/...` is served by the shipping-orders web service.
+    pub fn orders_api(mut self, enabled: bool) -> Self {
+        self.orders_api = enabled;
+        self
+    }
+    /// Registers `handler` to take over connections upgraded to WebSocket on `path`.
+    pub fn websocket(mut self, path: &str, handler: impl WebSocketHandler + 'static) -> Self {
+        self.websockets.insert(path.to_string(), Box::new(handler));
+        self
+    }
+    /// Registers `handler` to stream Server-Sent Events to GET requests on `path`.
+    pub fn event_stream(mut self, path: &str, handler: impl EventStreamHandler + 'static) -> Self {
+        self.event_streams.insert(path.to_string(), Box::new(handler));
+        self
+    }
+    /// Forwards every request under `prefix` (e.g. `/teachers`) to `handler`'s upstreams.
+    pub fn proxy(mut self, prefix: &str, handler: ProxyHandler) -> Self {
+        self.proxies.push((prefix.trim_end_matches('/').to_string(), handler));
+        self
+    }
+    /// The proxy with the longest prefix matching `path`, if any.
+    fn find_proxy(&self, path: &str) -> Option<&(String, ProxyHandler)> {
+        self.proxies
+            .iter()
+            .filter(|(prefix, _)| under_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+    }
+    /// The CORS policy of the route group with the longest prefix matching `path`, if any.
+    fn find_cors(&self, path: &str) -> Option<&CorsPolicy> {
+        self.cors
+            .iter()
+            .filter(|(prefix, _)| under_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, policy)| policy)
+    }
+    /// The route `path` is dispatched to, as a pattern such as `/api/shipping/orders/:id`,
+    /// to label metrics with. Static files all share one label.
+    pub fn route_label(&self, path: &str) -> String {
+        if let Some((prefix, _)) = self.find_proxy(path) {
+            return format!("{}/*", prefix);
+        }
+        if self.metrics_path.as_deref() == Some(path)
+            || self.websockets.contains_key(path)
+            || self.event_streams.contains_key(path)
+        {
+            return path.to_string();
+        }
+        if self.orders_api && path.split('/').nth(1) == Some("api") {
+            return WebServiceHandler::route_label(path).to_string();
+        }
+        match path {
+            "/" | "/health" => path.to_string(),
+            _ => "static".to_string(),
+        }
+    }
+    /// Dispatches a request whose body is still unread in `stream`. The request is left with
+    /// what routing added to its extensions, such as the `Identity`, for the access log.
+    pub fn route(&self, req: &mut HttpRequest, stream:&mut impl Connection){
+        let httprequest::Resource::Path(s) = &req.resource;
+        let path = s.split('?').next().unwrap_or("").to_string();
+        let proxy = self.find_proxy(&path);
+        // Proxied responses come from the upstream as they are, CORS headers included.
+        let cors = self.find_cors(&path).filter(|_| proxy.is_none());
+        let cors_headers = cors.map(|cors| cors.response_headers(req.header("Origin"))).unwrap_or_default();
+        let send = |resp: HttpResponse, stream: &mut dyn Connection| {
+            let _ = cors::with_headers(resp, cors_headers.clone()).send_response(stream);
+        };
+        if let Some(addr) = stream.peer_addr() {
+            req.extensions.insert(ClientAddr(addr));
+        }
+        if let Some(limiter) = &self.rate_limiter {
+            if let Err(resp) = limiter.check(req, stream.peer_addr().map(|addr| addr.ip())) {
+                warn!("rate limit exceeded");
+                send(resp, stream);
+                return;
+            }
+        }
+        // Preflights never carry credentials, so they are answered before authentication.
+        if let Some(cors) = cors.filter(|_| CorsPolicy::is_preflight(req)) {
+            let _ = cors.preflight(req).send_response(stream);
+            return;
+        }
+        if let Some(auth) = self.auth.as_ref().filter(|auth| auth.protects(req.method, &path)) {
+            match auth.authenticate(req) {
+                Ok(identity) => {
+                    debug!(user = %identity.subject, scheme = %identity.scheme, "authenticated");
+                    req.extensions.insert(identity);
+                }
+                Err(resp) => {
+                    warn!("authentication failed");
+                    send(resp, stream);
+                    return;
+                }
+            }
+        }
+        if let Some((prefix, proxy)) = proxy {
+            proxy.handle(prefix, req, stream);
+            return;
+        }
+        if let Err(e) = req.read_body(stream, MAX_BODY_SIZE) {
+            warn!(error = %e, "failed to read request body");
+            match e.kind() {
+                io::ErrorKind::FileTooLarge => send(self.site.error_response("413"), stream),
+                io::ErrorKind::InvalidData => send(self.site.error_response("400"), stream),
+                // The connection failed; there's no one to answer.
+                _ => {}
+            }
+            return;
+        }
+        let path = path.as_str();
+        if self.metrics_path.as_deref() == Some(path) {
+            let mut headers: HashMap<&str, &str> = HashMap::new();
+            headers.insert("Content-Type", "text/plain; version=0.0.4");
+            let resp = HttpResponse::new("200", Some(headers), Some(Metrics::global().render_prometheus()));
+            send(resp, stream);
+            return;
+        }
+        if let Some(handler) = self.websockets.get(path) {
+            Self::upgrade(handler.as_ref(), req, stream);
+            return;
+        }
+        if let Some(handler) = self.event_streams.get(path) {
+            if req.method == httprequest::Method::GET {
+                Self::stream_events(handler.as_ref(), req, &cors_headers, stream);
+                return;
+            }
+        }
+        // Exports hold every order, so they are streamed a row at a time rather than cached.
+        if let Some((resp, export)) = WebServiceHandler::export(req).filter(|_| self.orders_api) {
+            let conn: &mut dyn Connection = stream;
+            if let Ok(mut body) = cors::with_headers(resp, cors_headers.clone()).send_chunked(conn) {
+                if let Err(e) = export.write_to(&mut BufWriter::new(&mut body)) {
+                    debug!(error = %e, "orders export interrupted");
+                }
+            }
+            return;
+        }
+        let cache = self.cache.as_ref().filter(|_| ResponseCache::applies_to(req));
+        match cache {
+            Some(cache) => {
+                let source = self.source_modified(path);
+                let now = Instant::now();
+                match cache.lookup(req, source, now) {
+                    Lookup::Hit(resp) => send(resp, stream),
+                    Lookup::Stale(resp) => {
+                        send(resp, stream);
+                        self.revalidate(cache, req, source);
                     }
+                    Lookup::Miss => {
+                        let resp = Self::respond(&self.site, self.orders_api, req);
+                        cache.store(req, &resp, source, now);
+                        send(resp, stream);
+                    }
+                }
+            }
+            None => send(Self::respond(&self.site, self.orders_api, req), stream),
+        }
+    }
+    /// The response of the orders API or the static files to a request that none of the other routes took.
+    fn respond<'a>(site: &Site, orders_api: bool, req: &'a HttpRequest) -> HttpResponse<'a> {
+        let route: Vec<&str> = req.path().split('/').collect();
+        if orders_api && route.get(1) == Some(&"api") {
+            return WebServiceHandler::handle(req, site);
+        }
+        match req.method {
+            httprequest::Method::GET => StaticPageHandler::handle(req, site),
+            _ => PageNotFoundHandler::handle(req, site),
+        }
+    }
+    /// Rebuilds a stale cached response on its own thread, so the connection that got the
+    /// stale copy isn't held up. The cache hands out one `Stale` per entry, so only one
+    /// refresh of it runs at a time.
+    fn revalidate(&self, cache: &Arc<ResponseCache>, req: &HttpRequest, source: Option<SystemTime>) {
+        // A cacheable GET has no body or credentials, so its line and headers are the whole request.
+        let req = HttpRequest {
+            method: req.method,
+            version: req.version,
+            resource: req.resource.clone(),
+            headers: req.headers.clone(),
+            msg_body: Vec::new(),
+            extensions: Extensions::default(),
+        };
+        let (site, orders_api, cache) = (self.site.clone(), self.orders_api, Arc::clone(cache));
+        thread::spawn(move || {
+            let resp = Self::respond(&site, orders_api, &req);
+            cache.store(&req, &resp, source, Instant::now());
+        });
+    }
+    /// When the file a response to `path` is built from last changed, so that cached responses are
+    /// dropped when it does. Templates the file includes aren't checked.
+    fn source_modified(&self, path: &str) -> Option<SystemTime> {
+        if self.orders_api && path.split('/').nth(1) == Some("api") {
+            return OrderStore::shared().modified();
+        }
+        self.site.modified(path)
+    }
+    fn stream_events(
+        handler: &dyn EventStreamHandler,
+        req: &HttpRequest,
+        cors_headers: &[(&str, String)],
+        stream: &mut impl Connection,
+    ) {
+        let mut headers: HashMap<&str, &str> = HashMap::new();
+        headers.insert("Content-Type", "text/event-stream");
+        headers.insert("Cache-Control", "no-cache");
+        let resp = cors::with_headers(HttpResponse::new("200", Some(headers), None), cors_headers.to_vec());
+        let conn: &mut dyn Connection = stream;
+        if let Ok(body) = resp.send_chunked(conn) {
+            let mut events = sse::EventStream::new(body, sse::last_event_id(req));
+            handler.handle(req, &mut events);
+        }
+    }
+    fn upgrade(handler: &dyn WebSocketHandler, req: &HttpRequest, stream: &mut impl Connection) {
+        match websocket::validate_handshake(req) {
+            Ok(accept) => {
+                if websocket::write_handshake_response(stream, &accept).is_ok() {
+                    let conn: &mut dyn Connection = stream;
+                    handler.handle(req, &mut websocket::WebSocket::new(conn));
                 }
-            },
-            _ => {
-                let resp:HttpResponse = PageNotFoundHandler::handle(&req);
+            }
+            Err(websocket::HandshakeError::UnsupportedVersion) => {
+                let mut headers: HashMap<&str, &str> = HashMap::new();
+                headers.insert("Sec-WebSocket-Version", "13");
+                let resp = HttpResponse::new("426", Some(headers), Some("".into()));
+                let _ = resp.send_response(stream);
+            }
+            Err(e) => {
+                let resp = HttpResponse::new("400", None, Some(e.to_string()));
                 let _ = resp.send_response(stream);
             }
         }
-       
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::BasicAuth;
+    use crate::cache::ResponseCache;
+    use crate::cors::AllowOrigin;
+    use crate::ratelimit::Limit;
+    use crate::testing::TestServer;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use http::httprequest::Method;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn site() -> Site {
+        Site::new(TestServer::document_root())
+    }
+
+    #[test]
+    fn test_orders_api_errors() {
+        let server = TestServer::new(Router::new(site()));
+        let client = server.client();
+        client
+            .delete("/api/shipping/orders")
+            .send()
+            .assert_status(405)
+            .assert_header("Allow", "GET, POST")
+            .assert_snapshot("orders_method_not_allowed");
+        client
+            .get("/api/shipping/orders/abc")
+            .send()
+            .assert_status(404)
+            .assert_json(&json!({ "error_message": "Order abc not found" }));
+        client
+            .get("/api/shipping/orders?order_status=Lost")
+            .send()
+            .assert_status(422)
+            .assert_json_at("/errors/0/field", &json!("order_status"));
+        client.put("/api/shipping/orders/1").body("{").send().assert_status(400);
+        client
+            .patch("/api/shipping/orders/1")
+            .json(&json!([1]))
+            .send()
+            .assert_status(400)
+            .assert_json_at("/error_message", &json!("Expected a JSON object"));
+        let orders_off = TestServer::new(Router::new(site()).orders_api(false));
+        orders_off.client().get("/api/shipping/orders").send().assert_status(404);
+    }
+
+    #[test]
+    fn test_unreadable_bodies() {
+        let server = TestServer::new(Router::new(site()));
+        let client = server.client();
+        let too_large = (MAX_BODY_SIZE + 1).to_string();
+        client.post("/api/shipping/orders").header("Content-Length", &too_large).send().assert_status(413);
+        client.post("/api/shipping/orders").header("Content-Length", "ten").send().assert_status(400);
+        client
+            .post("/api/shipping/orders")
+            .header("Transfer-Encoding", "chunked")
+            .body("zz\r\n{}\r\n0\r\n\r\n")
+            .send()
+            .assert_status(400);
+        client.post("/api/shipping/orders").header("Transfer-Encoding", "gzip").body("{}").send().assert_status(400);
+    }
+
+    #[test]
+    fn test_orders_content_negotiation() {
+        let server = TestServer::new(Router::new(site()));
+        let client = server.client();
+        let list = "/api/shipping/orders?fields=order_id,order_status&sort=order_id&per_page=1";
+        let csv = client.get(list).header("Accept", "text/csv").send();
+        csv.assert_status(200)
+            .assert_header("Content-Type", "text/csv; charset=utf-8")
+            .assert_header("Vary", "Accept, Accept-Charset");
+        assert!(csv.text().starts_with("order_id,order_status\r\n1,"));
+        client
+            .get("/api/shipping/orders/1")
+            .header("Accept", "text/html;q=0.9, application/json;q=0.5")
+            .send()
+            .assert_header("Content-Type", "text/html; charset=utf-8")
+            .assert_body_contains("<th>order_id</th><th>order_date</th><th>order_status</th>");
+        client.get(list).header("Accept", "*/*").send().assert_header("Content-Type", "application/json");
+        for (name, value) in [("Accept", "image/png"), ("Accept-Charset", "iso-8859-1")] {
+            client
+                .get(list)
+                .header(name, value)
+                .send()
+                .assert_status(406)
+                .assert_header("Vary", "Accept, Accept-Charset")
+                .assert_json_at(
+                    "/error_message",
+                    &json!("Orders are available as application/json, text/csv, application/x-ndjson, text/html in UTF-8"),
+                );
+        }
+        client
+            .post("/nowhere")
+            .header("Accept", "application/json")
+            .send()
+            .assert_status(404)
+            .assert_header("Vary", "Accept")
+            .assert_json(&json!({ "error_message": "Not found" }));
+        client.post("/nowhere").send().assert_status(404).assert_header("Content-Type", "text/html");
+    }
+
+    #[test]
+    fn test_orders_export() {
+        let server = TestServer::new(Router::new(site()));
+        let client = server.client();
+        let csv = client.get("/api/shipping/orders?format=csv&fields=order_id&per_page=1").header("Accept", "application/json").send();
+        csv.assert_status(200)
+            .assert_header("Content-Type", "text/csv; charset=utf-8")
+            .assert_header("Content-Disposition", "attachment; filename=\"orders.csv\"")
+            .assert_header("Transfer-Encoding", "chunked")
+            .assert_no_header("Vary");
+        let text = csv.text();
+        let rows: Vec<&str> = text.split_terminator("\r\n").collect();
+        assert_eq!(rows[0], "order_id");
+        assert_eq!(rows.len() - 1, csv.header("X-Total-Count").unwrap().parse::<usize>().unwrap());
+        let ndjson = client.get("/api/shipping/orders?sort=order_id").header("Accept", "application/x-ndjson").send();
+        ndjson
+            .assert_status(200)
+            .assert_header("Content-Type", "application/x-ndjson")
+            .assert_header("Content-Disposition", "attachment; filename=\"orders.ndjson\"")
+            .assert_header("Vary", "Accept, Accept-Charset");
+        for line in ndjson.text().lines() {
+            assert!(serde_json::from_str::<serde_json::Value>(line).unwrap()["order_id"].is_number(), "{:?}", line);
+        }
+        client
+            .get("/api/shipping/orders/1?format=NDJSON&fields=order_id")
+            .send()
+            .assert_header("Content-Disposition", "attachment; filename=\"orders.ndjson\"")
+            .assert_json(&json!({ "order_id": 1 }));
+        let one = client.get("/api/shipping/orders/1?format=csv&fields=order_id").send();
+        assert_eq!(one.text(), "order_id\r\n1\r\n");
+        one.assert_header("X-Total-Count", "1");
+
+        // JSON and HTML lists are paged; downloads hold every order.
+        let json = client.get("/api/shipping/orders?per_page=1").send();
+        let total: usize = json.header("X-Total-Count").unwrap().parse().unwrap();
+        assert!(total > 1);
+        assert_eq!(json.json().as_array().unwrap().len(), 1);
+        let html = client.get("/api/shipping/orders?per_page=1&format=html").send().text();
+        assert_eq!(html.matches("<tr><td>").count(), 1);
+        let csv = client.get("/api/shipping/orders?per_page=1&page=2&format=csv").send().text();
+        assert_eq!(csv.lines().count(), total + 1);
+        let ndjson = client.get("/api/shipping/orders?per_page=1&page=2&format=ndjson").send().text();
+        assert_eq!(ndjson.lines().count(), total);
+        client
+            .get("/api/shipping/orders?format=xml")
+            .send()
+            .assert_status(422)
+            .assert_json_at("/errors/0/field", &json!("format"));
+        client.get("/api/shipping/orders?format=csv&sort=bogus").send().assert_status(422);
+    }
+
+    #[test]
+    fn test_cors_runs_before_auth() {
+        let htpasswd = format!("admin:{}\n", bcrypt::hash("shipping", 4).unwrap());
+        let router = Router::new(site())
+            .auth(Auth::new("orders", &["/api".into()]).with(BasicAuth::from_htpasswd(&htpasswd).unwrap()))
+            .cors("/api", CorsPolicy::default().allow_origin(AllowOrigin::from("https://shop.test")).methods(&[Method::GET, Method::DELETE]));
+        let server = TestServer::new(router);
+        let client = server.client().header("Origin", "https://shop.test");
+        client
+            .request("OPTIONS", "/api/shipping/orders/1")
+            .header("Access-Control-Request-Method", "DELETE")
+            .send()
+            .assert_status(204)
+            .assert_header("Access-Control-Allow-Methods", "GET, DELETE");
+        client
+            .get("/api/shipping/orders/abc")
+            .send()
+            .assert_status(401)
+            .assert_header("Access-Control-Allow-Origin", "https://shop.test");
+        server.client().get("/api/shipping/orders").send().assert_status(401).assert_no_header("Access-Control-Allow-Origin");
+        let credentials = format!("Basic {}", STANDARD.encode("admin:shipping"));
+        client
+            .get("/api/shipping/orders/abc")
+            .header("Authorization", &credentials)
+            .send()
+            .assert_status(404)
+            .assert_header("Vary", "Origin");
+    }
+
+    #[test]
+    fn test_auth_for_some_methods() {
+        let htpasswd = format!("admin:{}\n", bcrypt::hash("shipping", 4).unwrap());
+        let auth = Auth::new("orders", &["/api".into()])
+            .methods(&[Method::POST, Method::DELETE])
+            .with(BasicAuth::from_htpasswd(&htpasswd).unwrap());
+        let server = TestServer::new(Router::new(site()).auth(auth));
+        server.client().get("/api/shipping/orders/abc").send().assert_status(404);
+        server.client().request("DELETE", "/api/shipping/orders/abc").send().assert_status(401);
+        server
+            .client()
+            .request("DELETE", "/api/shipping/orders/abc")
+            .header("Authorization", &format!("Basic {}", STANDARD.encode("admin:shipping")))
+            .send()
+            .assert_status(404);
+    }
+
+    #[test]
+    fn test_rate_limit_per_client() {
+        let limit = Limit { requests: 1, period: Duration::from_secs(60), burst: 2 };
+        let server = TestServer::new(Router::new(site()).rate_limit(RateLimiter::default().route("/", limit)));
+        let client = server.client().peer("192.0.2.1:5000");
+        client.get("/").send().assert_status(200);
+        client.get("/").send().assert_status(200);
+        client
+            .get("/")
+            .send()
+            .assert_status(429)
+            .assert_header("RateLimit-Limit", "2")
+            .assert_header("Retry-After", "60");
+        server.client().peer("192.0.2.2:5000").get("/").send().assert_status(200);
+    }
+
+    #[test]
+    fn test_autoindex() {
+        let root = std::env::temp_dir().join(format!("httpserver-autoindex-root-{}", std::process::id()));
+        let outside = root.with_extension("outside");
+        for dir in [root.join("docs"), root.join(".git"), root.join("site"), outside.clone()] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        for (file, contents) in [("b.txt", "bbb"), ("a b.txt", "a"), (".env", "SECRET=1"), ("docs/readme.txt", "read me"), ("site/index.html", "<p>{{ path }}</p>")] {
+            std::fs::write(root.join(file), contents).unwrap();
+        }
+        let _ = std::os::unix::fs::symlink(&outside, root.join("out"));
+        let server = TestServer::new(Router::new(Site::new(&root).autoindex(true)));
+        let client = server.client();
+        let page = client.get("/").send();
+        page.assert_status(200)
+            .assert_header("Content-Type", "text/html; charset=utf-8")
+            .assert_header("Vary", "Accept")
+            .assert_body_contains("<a href=\"a%20b.txt\">a b.txt</a>")
+            .assert_body_contains("<a href=\"docs/\">docs/</a>");
+        assert!(!page.text().contains(".env") && !page.text().contains(".git") && !page.text().contains("out/"));
+        client
+            .get("/?sort=-size")
+            .header("Accept", "application/json")
+            .send()
+            .assert_status(200)
+            .assert_json_at("/path", &json!("/"))
+            .assert_json_at("/entries/0/name", &json!("site"))
+            .assert_json_at("/entries/1/name", &json!("docs"))
+            .assert_json_at("/entries/2/name", &json!("b.txt"))
+            .assert_json_at("/entries/3/name", &json!("a b.txt"))
+            .assert_json_at("/entries/3/size", &json!(1));
+        client.get("/docs?sort=size").send().assert_status(301).assert_header("Location", "/docs/?sort=size");
+        client.get("/docs/").send().assert_body_contains("<a href=\"../\">");
+        client.get("/docs/readme.txt").send().assert_status(200).assert_body_contains("read me");
+        client.get("/a%20b.txt").send().assert_status(200);
+        client.get("/site/").send().assert_status(200).assert_body_contains("<p>{{ path }}</p>");
+        for hidden in ["/.git/", "/.env", "/%2e%2e/", "/docs/../.git/", "/out/"] {
+            client.get(hidden).send().assert_status(404);
+        }
+        let off = TestServer::new(Router::new(Site::new(&root)));
+        off.client().get("/docs/").send().assert_status(404);
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_dir_all(outside).unwrap();
+    }
+
+    #[test]
+    fn test_cache_serves_until_the_file_changes() {
+        let root = std::env::temp_dir().join(format!("httpserver-cache-root-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("styles.css");
+        std::fs::write(&file, "body {}").unwrap();
+        let server = TestServer::new(Router::new(Site::new(&root)).cache(ResponseCache::new(1 << 20)));
+        let client = server.client();
+        client
+            .get("/styles.css")
+            .send()
+            .assert_status(200)
+            .assert_header("Cache-Control", "public, max-age=60, stale-while-revalidate=300")
+            .assert_no_header("Age");
+        std::fs::write(&file, "body { color: red }").unwrap();
+        let touch = |secs| {
+            let modified = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            std::fs::File::options().write(true).open(&file).unwrap().set_modified(modified).unwrap();
+        };
+        touch(1_000);
+        client.get("/styles.css").send().assert_body_contains("red").assert_no_header("Age");
+        client.get("/styles.css").send().assert_body_contains("red").assert_header("Age", "0");
+        client.get("/styles.css").header("Authorization", "Basic YTpi").send().assert_no_header("Age");
+        std::fs::write(&file, "body { color: blue }").unwrap();
+        touch(2_000);
+        client.get("/styles.css").send().assert_body_contains("blue").assert_no_header("Age");
+        client.get("/health").send().assert_header("Cache-Control", "no-store");
+        client.get("/health").send().assert_no_header("Age");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_only_template_pages_are_rendered() {
+        let root = std::env::temp_dir().join(format!("httpserver-static-root-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        let page = "<p>Write {{ name }}, {% raw %} or {# note #} in a template.</p>";
+        std::fs::write(root.join("docs/templates.html"), page).unwrap();
+        std::fs::write(root.join("about.html"), page).unwrap();
+        std::fs::write(root.join("docs/index.html"), page).unwrap();
+        std::fs::write(root.join("index.html"), "<p>{{ path }}</p>").unwrap();
+        let server = TestServer::new(Router::new(Site::new(&root).autoindex(true)));
+        let client = server.client();
+        for path in ["/about.html", "/docs/templates.html", "/docs/"] {
+            let resp = client.get(path).send();
+            resp.assert_status(200).assert_header("Content-Type", "text/html");
+            assert_eq!(resp.text(), page);
+        }
+        client.get("/").send().assert_status(200).assert_body_contains("<p>/</p>");
+        client.get("/index.html").send().assert_status(200).assert_body_contains("<p>/index.html</p>");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_nested_files_content_type() {
+        let root = std::env::temp_dir().join(format!("httpserver-nested-root-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("css/theme")).unwrap();
+        std::fs::create_dir_all(root.join("js")).unwrap();
+        std::fs::write(root.join("css/theme/site.css"), "body { color: blue; }").unwrap();
+        std::fs::write(root.join("js/app.js"), "console.log(1);").unwrap();
+        let server = TestServer::new(Router::new(Site::new(&root)));
+        let client = server.client();
+        client
+            .get("/css/theme/site.css")
+            .send()
+            .assert_status(200)
+            .assert_header("Content-Type", "text/css")
+            .assert_body_contains("blue");
+        client.get("/js/app.js").send().assert_status(200).assert_header("Content-Type", "text/javascript");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
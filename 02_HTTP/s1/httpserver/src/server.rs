@@ -1,27 +1,643 @@
-use super::router::Router;
-use http::httprequest::HttpRequest;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::str;
+use crate::accesslog::{AccessLog, Record};
+use crate::auth::Identity;
+use crate::listener::{ListenAddr, Listener, ProxyProtocol, Stream};
+use crate::metrics::Metrics;
+use crate::router::MAX_BODY_SIZE;
+use crate::shutdown::{ReloadHandle, ShutdownHandle};
+use crate::vhost::VirtualHosts;
+use chrono::Local;
+use http::h2::{self, ServerConnection};
+use http::httprequest::{HttpRequest, Resource, Version};
+use http::proxy_protocol;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, field, info, info_span, warn, Span};
+
+/// A client connection the router can write responses to, or hand over to an
+/// upgraded protocol such as WebSocket. Reads are buffered so that whatever
+/// follows the request head stays available to the handler of the body.
+pub trait Connection: BufRead + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn peer_addr(&self) -> Option<SocketAddr>;
+    /// A second handle for writing from other threads, for protocols such as
+    /// HTTP/2 that read and write independently.
+    fn writer(&self) -> Option<Box<dyn Write + Send>> {
+        None
+    }
+}
+
+/// Buffered reads over a stream, with writes passed straight through.
+pub struct BufStream<S: Read + Write> {
+    inner: BufReader<S>,
+}
+impl<S: Read + Write> BufStream<S> {
+    pub fn new(stream: S) -> Self {
+        BufStream {
+            inner: BufReader::new(stream),
+        }
+    }
+}
+impl<S: Read + Write> Read for BufStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+impl<S: Read + Write> BufRead for BufStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+impl<S: Read + Write> Write for BufStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.get_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.get_mut().flush()
+    }
+}
+impl Connection for BufStream<Stream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.get_ref().set_read_timeout(timeout)
+    }
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.get_ref().peer_addr()
+    }
+    fn writer(&self) -> Option<Box<dyn Write + Send>> {
+        let stream = self.inner.get_ref().try_clone().ok()?;
+        Some(Box::new(stream))
+    }
+}
+
+/// One request of an HTTP/2 connection, which its handler sees as a connection of its own.
+pub struct H2Stream {
+    inner: h2::Stream,
+    peer: Option<SocketAddr>,
+}
+impl Read for H2Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+impl BufRead for H2Stream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+impl Write for H2Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl Connection for H2Stream {
+    /// The body has been read in full by the time the handler runs.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+}
+
+/// A connection that came through a load balancer, with the client's address
+/// taken from its PROXY protocol header.
+pub struct Proxied<C: Connection> {
+    inner: C,
+    client: SocketAddr,
+}
+impl<C: Connection> Read for Proxied<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+impl<C: Connection> BufRead for Proxied<C> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+impl<C: Connection> Write for Proxied<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<C: Connection> Connection for Proxied<C> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.client)
+    }
+    fn writer(&self) -> Option<Box<dyn Write + Send>> {
+        self.inner.writer()
+    }
+}
+
+/// The address of the client a request came from, the real one for connections
+/// through a load balancer, found in the request's extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// Counts the bytes passing through a connection and notes the status code
+/// of the response written to it, for the metrics.
+pub struct Metered<C: Connection> {
+    inner: C,
+    bytes_read: u64,
+    bytes_written: u64,
+    /// The start of the response, up to and including its status code.
+    head: Vec<u8>,
+}
+impl<C: Connection> Metered<C> {
+    pub fn new(inner: C) -> Self {
+        Metered {
+            inner,
+            bytes_read: 0,
+            bytes_written: 0,
+            head: Vec::new(),
+        }
+    }
+    /// The status code of the response, once its status line has been written.
+    pub fn status(&self) -> Option<u16> {
+        let line = std::str::from_utf8(&self.head).ok()?;
+        line.split(' ').nth(1)?.parse().ok()
+    }
+}
+impl<C: Connection> Read for Metered<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+impl<C: Connection> BufRead for Metered<C> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.bytes_read += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+impl<C: Connection> Write for Metered<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        // "HTTP/1.1 200" is 12 bytes.
+        let wanted = 12usize.saturating_sub(self.head.len()).min(n);
+        self.head.extend_from_slice(&buf[..wanted]);
+        self.bytes_written += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<C: Connection> Connection for Metered<C> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+    fn writer(&self) -> Option<Box<dyn Write + Send>> {
+        self.inner.writer()
+    }
+}
+
+/// How often the accept loop checks for shutdown and reload requests.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a load balancer has to send the PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Builds the hosts anew, e.g. from the reloaded config file.
+type Reloader<'a> = Box<dyn Fn() -> Result<VirtualHosts, String> + 'a>;
 
 pub struct Server<'a> {
-    socket_addr: &'a str,
+    address: ListenAddr,
+    socket_mode: Option<u32>,
+    /// Whether `address` or the inherited sockets expect PROXY protocol headers.
+    proxy_protocol: ProxyProtocol,
+    /// Sockets already listening, served instead of binding `address`.
+    inherited: Vec<Listener>,
+    /// Further addresses, with their socket mode and PROXY protocol setting.
+    extra: Vec<(ListenAddr, Option<u32>, ProxyProtocol)>,
+    hosts: Arc<VirtualHosts>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    reload: ReloadHandle,
+    reloader: Option<Reloader<'a>>,
+    http2: bool,
+    access_log: Option<Arc<AccessLog>>,
 }
 impl<'a> Server<'a> {
-    pub fn new(socket_addr: &'a str) -> Self {
-        Server { socket_addr }
+    /// Listens on `address`: `host:port`, or `unix:/path/to.sock` for a Unix domain socket.
+    pub fn new(address: &str, hosts: VirtualHosts) -> Self {
+        Server {
+            address: ListenAddr::from(address),
+            socket_mode: None,
+            proxy_protocol: ProxyProtocol::Off,
+            inherited: Vec::new(),
+            extra: Vec::new(),
+            hosts: Arc::new(hosts),
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            reload: ReloadHandle::default(),
+            reloader: None,
+            http2: true,
+            access_log: None,
+        }
+    }
+    /// How long `run` waits for in-flight connections once shutdown was requested.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+    /// Called to rebuild the hosts when a reload is requested. Connections in
+    /// flight keep the hosts they started with.
+    pub fn on_reload(mut self, reloader: impl Fn() -> Result<VirtualHosts, String> + 'a) -> Self {
+        self.reloader = Some(Box::new(reloader));
+        self
+    }
+    /// The permissions of a Unix domain socket, e.g. `0o660`; by default they follow the umask.
+    pub fn socket_mode(mut self, mode: Option<u32>) -> Self {
+        self.socket_mode = mode;
+        self
+    }
+    /// Serves sockets that are already listening, such as those passed by systemd
+    /// socket activation, instead of binding the address.
+    pub fn listeners(mut self, listeners: Vec<Listener>) -> Self {
+        self.inherited = listeners;
+        self
+    }
+    /// Whether connections to the address, or to the inherited sockets, start with a
+    /// PROXY protocol header.
+    pub fn proxy_protocol(mut self, mode: ProxyProtocol) -> Self {
+        self.proxy_protocol = mode;
+        self
+    }
+    /// Listens on `address` as well, e.g. on a port for the load balancer next to one for local clients.
+    pub fn listen(mut self, address: &str, socket_mode: Option<u32>, proxy_protocol: ProxyProtocol) -> Self {
+        self.extra.push((ListenAddr::from(address), socket_mode, proxy_protocol));
+        self
+    }
+    /// Whether clients may speak HTTP/2 over cleartext.
+    pub fn http2(mut self, enabled: bool) -> Self {
+        self.http2 = enabled;
+        self
+    }
+    /// Where every request is logged, besides the `request completed` events.
+    pub fn access_log(mut self, access_log: Option<AccessLog>) -> Self {
+        self.access_log = access_log.map(Arc::new);
+        self
+    }
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.reload.clone()
+    }
+
+    /// Serves connections until shutdown is requested, then waits up to the
+    /// shutdown timeout for those in flight.
+    pub fn run(&mut self) {
+        let listeners: Vec<(Listener, ProxyProtocol)> = if self.inherited.is_empty() {
+            vec![(self.address.clone(), self.socket_mode, self.proxy_protocol)]
+                .into_iter()
+                .chain(self.extra.iter().cloned())
+                .map(|(address, mode, proxy_protocol)| {
+                    let listener = Listener::bind(&address, mode)
+                        .unwrap_or_else(|e| panic!("Failed to listen on {}: {}", address, e));
+                    (listener, proxy_protocol)
+                })
+                .collect()
+        } else {
+            std::mem::take(&mut self.inherited).into_iter().map(|listener| (listener, self.proxy_protocol)).collect()
+        };
+        for (listener, proxy_protocol) in &listeners {
+            // Polled, so that shutdown and reload requests are seen without a new connection.
+            listener.set_nonblocking(true).unwrap();
+            info!(address = %listener, proxy_protocol = ?proxy_protocol, "listening");
+        }
+        // Uptime counts from here.
+        Metrics::global();
+        while !self.shutdown.is_shutting_down() {
+            if self.reload.take_request() {
+                self.reload_hosts();
+            }
+            let mut accepted = false;
+            for (listener, proxy_protocol) in &listeners {
+                match listener.accept() {
+                    Ok(stream) => {
+                        accepted = true;
+                        self.spawn_connection(stream, *proxy_protocol);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => warn!(error = %e, "failed to accept connection"),
+                }
+            }
+            if !accepted {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+        }
+        drop(listeners);
+        info!(in_flight = self.shutdown.in_flight(), "stopped accepting connections");
+        if self.shutdown.wait_idle(self.shutdown_timeout) {
+            info!("all connections finished");
+        } else {
+            warn!(in_flight = self.shutdown.in_flight(), "shutdown timeout passed, dropping connections");
+        }
+    }
+
+    fn spawn_connection(&self, stream: Stream, proxy_protocol: ProxyProtocol) {
+        if let Err(e) = stream.set_nonblocking(false) {
+            warn!(error = %e, "failed to set up connection");
+            return;
+        }
+        Metrics::global().connection_opened();
+        let in_flight = self.shutdown.start();
+        let hosts = Arc::clone(&self.hosts);
+        let http2 = self.http2;
+        let access_log = self.access_log.clone();
+        let peer = match (&stream, stream.peer_addr()) {
+            (_, Some(addr)) => addr.to_string(),
+            (Stream::Unix(_), None) => "unix".to_string(),
+            (Stream::Tcp(_), None) => String::new(),
+        };
+        let span = info_span!("connection", peer = %peer, client = field::Empty);
+        // Each connection gets its own thread so long-lived WebSocket
+        // sessions don't block other clients.
+        thread::spawn(move || {
+            let _entered = span.enter();
+            debug!("connection established");
+            let mut stream = BufStream::new(stream);
+            let access_log = access_log.as_deref();
+            match read_proxy_header(&mut stream, proxy_protocol) {
+                Ok(Some(client)) => {
+                    Span::current().record("client", field::display(client));
+                    handle_connection(Proxied { inner: stream, client }, &hosts, http2, access_log);
+                }
+                Ok(None) => handle_connection(stream, &hosts, http2, access_log),
+                Err(e) => warn!(error = %e, "connection refused"),
+            }
+            Metrics::global().connection_closed();
+            drop(in_flight);
+            debug!("connection closed");
+        });
+    }
+
+    fn reload_hosts(&mut self) {
+        let reloader = match &self.reloader {
+            Some(reloader) => reloader,
+            None => return,
+        };
+        match reloader() {
+            Ok(hosts) => {
+                self.hosts = Arc::new(hosts);
+                info!("configuration reloaded");
+            }
+            Err(e) => error!(error = %e, "reload failed, keeping the current configuration"),
+        }
+    }
+}
+
+/// Reads the PROXY protocol header a connection starts with, if the listener expects one,
+/// and returns the client's address if the load balancer passed it on.
+fn read_proxy_header(stream: &mut BufStream<Stream>, mode: ProxyProtocol) -> io::Result<Option<SocketAddr>> {
+    if mode == ProxyProtocol::Off {
+        return Ok(None);
+    }
+    stream.set_read_timeout(Some(PROXY_HEADER_TIMEOUT))?;
+    let header = proxy_protocol::read_header(stream)?;
+    stream.set_read_timeout(None)?;
+    match header {
+        Some(header) => Ok(header.source()),
+        None if mode == ProxyProtocol::Optional => Ok(None),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "PROXY protocol header missing")),
+    }
+}
+
+/// Whether the client opened with the HTTP/2 preface rather than an HTTP/1.1 request line.
+fn has_h2_preface(stream: &mut impl BufRead) -> bool {
+    match stream.fill_buf() {
+        // Enough to tell it from a request line such as `PUT /`.
+        Ok(buf) if buf.len() >= 4 => h2::PREFACE.starts_with(&buf[..buf.len().min(h2::PREFACE.len())]),
+        _ => false,
+    }
+}
+
+fn handle_connection(stream: impl Connection, hosts: &VirtualHosts, http2: bool, access_log: Option<&AccessLog>) {
+    let started = Instant::now();
+    let mut stream = Metered::new(stream);
+    if http2 && has_h2_preface(&mut stream) {
+        serve_h2(stream, hosts, None, access_log);
+        return;
+    }
+    let mut req = match HttpRequest::read_head(&mut stream) {
+        Ok(req) => req,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            debug!("connection closed before a request");
+            return;
+        }
+        Err(e) => {
+            warn!(error = %e, "failed to read request");
+            Metrics::global().bytes_transferred(stream.bytes_read, stream.bytes_written);
+            return;
+        }
+    };
+    if http2 && h2::is_h2c_upgrade(&req) && stream.writer().is_some() {
+        // The request is answered over HTTP/2, so its body has to be read first.
+        let upgraded = req.read_body(&mut stream, MAX_BODY_SIZE).and_then(|_| h2::write_upgrade_response(&mut stream));
+        match upgraded {
+            Ok(()) => serve_h2(stream, hosts, Some(req), access_log),
+            Err(e) => warn!(error = %e, "failed to upgrade to HTTP/2"),
+        }
+        return;
+    }
+    dispatch(req, &mut stream, hosts, started, access_log);
+    Metrics::global().bytes_transferred(stream.bytes_read, stream.bytes_written);
+}
+
+/// Serves an HTTP/2 connection, each stream on its own thread.
+fn serve_h2<C: Connection>(
+    mut stream: Metered<C>,
+    hosts: &VirtualHosts,
+    upgraded: Option<HttpRequest>,
+    access_log: Option<&AccessLog>,
+) {
+    let writer = match stream.writer() {
+        Some(writer) => writer,
+        None => {
+            warn!("connection can't be shared for HTTP/2");
+            return;
+        }
+    };
+    debug!("speaking HTTP/2");
+    let peer = stream.peer_addr();
+    let connection = Span::current();
+    let handler = |req: HttpRequest, inner: h2::Stream| {
+        let _entered = connection.enter();
+        let started = Instant::now();
+        let mut stream = Metered::new(H2Stream { inner, peer });
+        dispatch(req, &mut stream, hosts, started, access_log);
+        // The body was counted as it came in over the connection.
+        Metrics::global().bytes_transferred(0, stream.bytes_written);
+    };
+    let server = ServerConnection::default().max_body(MAX_BODY_SIZE);
+    if let Err(e) = server.serve(&mut stream, writer, upgraded, &handler) {
+        warn!(error = %e, "HTTP/2 connection failed");
+    }
+    Metrics::global().bytes_transferred(stream.bytes_read, 0);
+}
+
+/// Routes one request inside its span and records it in the metrics and the access log.
+fn dispatch<C: Connection>(
+    mut req: HttpRequest,
+    stream: &mut Metered<C>,
+    hosts: &VirtualHosts,
+    started: Instant,
+    access_log: Option<&AccessLog>,
+) {
+    let received = Local::now();
+    let metrics = Metrics::global();
+    metrics.request_received();
+    let router = hosts.select(req.header("Host"));
+    let method = req.method;
+    let route = router.route_label(req.path());
+    let span = info_span!(
+        "request",
+        method = method.as_str(),
+        path = req.path(),
+        status = field::Empty,
+        duration_ms = field::Empty,
+    );
+    let _entered = span.enter();
+    router.route(&mut req, stream);
+    let duration = started.elapsed();
+    if let Some(status) = stream.status() {
+        span.record("status", status);
+    }
+    span.record("duration_ms", duration.as_secs_f64() * 1000.0);
+    info!("request completed");
+    metrics.request_finished(method.as_str(), &route, stream.status(), duration);
+    if let Some(access_log) = access_log {
+        let Resource::Path(target) = &req.resource;
+        access_log.log(&Record {
+            time: received.fixed_offset(),
+            client: stream.peer_addr().map(|addr| addr.ip()),
+            user: req.extensions.get::<Identity>().map(|identity| identity.subject.as_str()),
+            method: method.as_str(),
+            target,
+            version: match req.version {
+                Version::V1_1 => Some("HTTP/1.1"),
+                Version::V2_0 => Some("HTTP/2.0"),
+                Version::UNINITIALIZED => None,
+            },
+            status: stream.status(),
+            bytes: stream.bytes_written,
+            host: req.header("Host"),
+            referer: req.header("Referer"),
+            user_agent: req.header("User-Agent"),
+            duration,
+        });
     }
-    pub fn run(&self) {
-        let connection_listener = TcpListener::bind(self.socket_addr).unwrap();
-        println!("Running on {}", self.socket_addr);
-        for stream in connection_listener.incoming() {
-            let mut stream = stream.unwrap();
-            println!("Connection established");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::Site;
+    use crate::ratelimit::{Limit, RateLimiter};
+    use crate::router::Router;
+    use crate::testing::{TestResponse, TestServer};
+    use http::proxy_protocol::ProxyHeader;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::sync::mpsc;
+    use std::{env, fs, process};
 
-            let mut read_buf = [0; 200];
-            stream.read(&mut read_buf).unwrap();
-            let req: HttpRequest = String::from_utf8(read_buf.to_vec()).unwrap().into();
-            Router::route(req, &mut stream);
+    const HEALTH: &[u8] = b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+    /// Runs `server` on a Unix socket in a temporary directory until `test` returns.
+    fn with_unix_server(name: &str, server: impl FnOnce(&str) -> Server<'static> + Send + 'static, test: impl FnOnce(&Path)) {
+        let dir = env::temp_dir().join(format!("httpserver-server-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("httpserver.sock");
+        let address = format!("unix:{}", path.display());
+        let (handles, received) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut server = server(&address);
+            handles.send(server.shutdown_handle()).unwrap();
+            server.run();
+        });
+        let shutdown = received.recv().unwrap();
+        while UnixStream::connect(&path).is_err() {
+            thread::sleep(Duration::from_millis(10));
         }
+        test(&path);
+        shutdown.shutdown();
+        thread.join().unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn exchange(path: &Path, request: &[u8]) -> Vec<u8> {
+        let mut client = UnixStream::connect(path).unwrap();
+        client.write_all(request).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serves_over_unix_socket() {
+        let server = |address: &str| {
+            let hosts = VirtualHosts::new(Router::new(Site::new(TestServer::document_root())));
+            Server::new(address, hosts).socket_mode(Some(0o600))
+        };
+        with_unix_server("unix", server, |path| {
+            assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+            let response = exchange(path, HEALTH);
+            TestResponse::parse(&response).assert_status(200).assert_json_at("/status", &serde_json::json!("ok"));
+        });
+    }
+
+    #[test]
+    fn test_proxy_protocol() {
+        let server = |address: &str| {
+            let limit = Limit { requests: 1, period: Duration::from_secs(60), burst: 1 };
+            let router = Router::new(Site::new(TestServer::document_root())).rate_limit(RateLimiter::default().route("/", limit));
+            Server::new(address, VirtualHosts::new(router)).proxy_protocol(ProxyProtocol::Required)
+        };
+        with_unix_server("proxy-protocol", server, |path| {
+            let from = |client: &str| ProxyHeader::Proxied {
+                source: client.parse().unwrap(),
+                destination: "192.0.2.100:80".parse().unwrap(),
+            };
+            // Each client has a limit of its own, whichever version of the header names it.
+            let alice = [proxy_protocol::write_v1(&from("192.0.2.1:5000")).as_bytes(), HEALTH].concat();
+            TestResponse::parse(&exchange(path, &alice)).assert_status(200);
+            TestResponse::parse(&exchange(path, &alice)).assert_status(429);
+            let bob = [proxy_protocol::write_v2(&from("[2001:db8::2]:5000")), HEALTH.to_vec()].concat();
+            TestResponse::parse(&exchange(path, &bob)).assert_status(200);
+            // Without a header, or with a broken one, the connection is closed unanswered.
+            assert_eq!(exchange(path, HEALTH), b"");
+            assert_eq!(exchange(path, &[b"PROXY TCP4 nonsense\r\n", HEALTH].concat()), b"");
+        });
     }
 }
@@ -0,0 +1,154 @@
+use crate::accesslog::ReopenHandle;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+struct ShutdownState {
+    stopping: AtomicBool,
+    in_flight: Mutex<usize>,
+    idle: Condvar,
+}
+
+/// Asks a running `Server` to stop accepting connections and lets it wait for
+/// the ones in flight. Clones share the same state.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        ShutdownHandle {
+            state: Arc::new(ShutdownState {
+                stopping: AtomicBool::new(false),
+                in_flight: Mutex::new(0),
+                idle: Condvar::new(),
+            }),
+        }
+    }
+}
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.state.stopping.store(true, Ordering::SeqCst);
+    }
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.stopping.load(Ordering::SeqCst)
+    }
+    /// Counts a connection as in flight until the returned guard is dropped.
+    pub fn start(&self) -> InFlight {
+        *self.state.in_flight.lock().unwrap() += 1;
+        InFlight {
+            state: Arc::clone(&self.state),
+        }
+    }
+    pub fn in_flight(&self) -> usize {
+        *self.state.in_flight.lock().unwrap()
+    }
+    /// Blocks until nothing is in flight or `timeout` passes; returns whether everything finished.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut in_flight = self.state.in_flight.lock().unwrap();
+        while *in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            in_flight = self.state.idle.wait_timeout(in_flight, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+/// Marks one connection as finished when dropped.
+pub struct InFlight {
+    state: Arc<ShutdownState>,
+}
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = self.state.in_flight.lock().unwrap();
+        *in_flight -= 1;
+        if *in_flight == 0 {
+            self.state.idle.notify_all();
+        }
+    }
+}
+
+/// Asks a running `Server` to reload its configuration before accepting the next connection.
+#[derive(Clone, Default)]
+pub struct ReloadHandle {
+    requested: Arc<AtomicBool>,
+}
+impl ReloadHandle {
+    pub fn reload(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+    /// Whether a reload was asked for since the last call.
+    pub fn take_request(&self) -> bool {
+        self.requested.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// SIGTERM and SIGINT shut the server down gracefully (a second one exits at once);
+/// SIGHUP reloads its configuration, and SIGUSR1 reopens the access log.
+pub fn handle_signals(shutdown: ShutdownHandle, reload: ReloadHandle, reopen: ReopenHandle) -> io::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP, SIGUSR1])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGHUP => {
+                    info!("SIGHUP received, reloading configuration");
+                    reload.reload();
+                }
+                SIGUSR1 => {
+                    info!("SIGUSR1 received, reopening the access log");
+                    reopen.reopen();
+                }
+                _ if shutdown.is_shutting_down() => {
+                    warn!(in_flight = shutdown.in_flight(), "second shutdown signal, exiting now");
+                    process::exit(1);
+                }
+                _ => {
+                    info!(signal, "shutting down");
+                    shutdown.shutdown();
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_idle() {
+        let shutdown = ShutdownHandle::default();
+        assert!(shutdown.wait_idle(Duration::ZERO));
+        let guard = shutdown.start();
+        let slow = shutdown.start();
+        assert_eq!(shutdown.in_flight(), 2);
+        assert!(!shutdown.wait_idle(Duration::from_millis(10)));
+        drop(guard);
+        let worker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(slow);
+        });
+        assert!(shutdown.wait_idle(Duration::from_secs(5)));
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_reload_request_is_taken_once() {
+        let reload = ReloadHandle::default();
+        assert!(!reload.take_request());
+        reload.clone().reload();
+        assert!(reload.take_request());
+        assert!(!reload.take_request());
+    }
+}
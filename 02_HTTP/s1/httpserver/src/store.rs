@@ -0,0 +1,445 @@
+use chrono::{DateTime, NaiveDate};
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// Formats accepted for `order_date`, tried in order. Dates are always written as ISO-8601 (`2022-01-21`).
+const DATE_FORMATS: [&str; 7] = ["%Y-%m-%d", "%d %b %Y", "%d %B %Y", "%b %d, %Y", "%B %d, %Y", "%Y/%m/%d", "%d.%m.%Y"];
+
+/// Parses a date in any of the accepted formats, or an RFC 3339 timestamp.
+pub fn parse_date(s: &str) -> Option<NaiveDate> {
+    let s = s.trim();
+    DATE_FORMATS
+        .iter()
+        .find_map(|f| NaiveDate::parse_from_str(s, f).ok())
+        .or_else(|| DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.date_naive()))
+}
+
+fn serialize_date<S: Serializer>(date: &NaiveDate, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&date.format("%Y-%m-%d").to_string())
+}
+
+/// Declared in lifecycle order, which is also how statuses sort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum Status {
+    Pending,
+    Shipped,
+    Delivered,
+    Cancelled,
+}
+impl Status {
+    /// Orders move forward only: a pending order ships or is cancelled, a shipped one is delivered.
+    pub fn can_become(self, next: Status) -> bool {
+        use Status::*;
+        self == next || matches!((self, next), (Pending, Shipped) | (Pending, Cancelled) | (Shipped, Delivered))
+    }
+}
+impl FromStr for Status {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "pending" => Ok(Status::Pending),
+            "shipped" => Ok(Status::Shipped),
+            "delivered" => Ok(Status::Delivered),
+            "cancelled" | "canceled" => Ok(Status::Cancelled),
+            _ => Err(format!("must be one of Pending, Shipped, Delivered, Cancelled, got \"{}\"", s)),
+        }
+    }
+}
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// What is wrong with one field of an order; `field` is prefixed with the order's
+/// index when the problem is in the data file, e.g. `[1].order_date`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+impl FieldError {
+    pub fn new(field: &str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrderStatus {
+    pub order_id: i32,
+    #[serde(serialize_with = "serialize_date")]
+    pub order_date: NaiveDate,
+    pub order_status: Status,
+}
+/// Reads an order from JSON, reporting every invalid field rather than just the first.
+/// A missing `order_id` becomes 0, for the store to assign. `i32::MAX` is refused, as the
+/// store couldn't number orders after it.
+impl TryFrom<&Value> for OrderStatus {
+    type Error = Vec<FieldError>;
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        let fields = match value.as_object() {
+            Some(fields) => fields,
+            None => return Err(vec![FieldError::new("", "must be a JSON object")]),
+        };
+        let mut errors = Vec::new();
+        let order_id = match fields.get("order_id") {
+            None | Some(Value::Null) => 0,
+            Some(v) => match v.as_i64().and_then(|id| i32::try_from(id).ok()).filter(|id| *id > 0 && *id < i32::MAX) {
+                Some(id) => id,
+                None => {
+                    errors.push(FieldError::new("order_id", format!("must be a positive integer below {}", i32::MAX)));
+                    0
+                }
+            },
+        };
+        let order_date = match fields.get("order_date").and_then(Value::as_str) {
+            Some(s) => parse_date(s).or_else(|| {
+                errors.push(FieldError::new("order_date", format!("is not a date: \"{}\"", s)));
+                None
+            }),
+            None => {
+                errors.push(FieldError::new("order_date", "is required and must be a string"));
+                None
+            }
+        };
+        let order_status = match fields.get("order_status").and_then(Value::as_str) {
+            Some(s) => s.parse::<Status>().map_err(|e| errors.push(FieldError::new("order_status", e))).ok(),
+            None => {
+                errors.push(FieldError::new("order_status", "is required and must be a string"));
+                None
+            }
+        };
+        match (order_date, order_status) {
+            (Some(order_date), Some(order_status)) if errors.is_empty() => Ok(OrderStatus {
+                order_id,
+                order_date,
+                order_status,
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    NotFound(i32),
+    Conflict(i32),
+    /// The request could not be understood at all, e.g. its body is not JSON.
+    BadRequest(String),
+    /// Well-formed input, or data file contents, with invalid values.
+    Invalid(Vec<FieldError>),
+}
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "Could not access the orders file: {}", e),
+            StoreError::Parse(e) => write!(f, "The orders file is not valid JSON: {}", e),
+            StoreError::NotFound(id) => write!(f, "Order {} not found", id),
+            StoreError::Conflict(id) => write!(f, "Order {} already exists", id),
+            StoreError::BadRequest(msg) => write!(f, "{}", msg),
+            StoreError::Invalid(errors) => {
+                let fields: Vec<String> = errors.iter().map(|e| format!("{} {}", e.field, e.message)).collect();
+                write!(f, "Validation failed: {}", fields.join("; "))
+            }
+        }
+    }
+}
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::Parse(e)
+    }
+}
+
+/// Reads the data file's orders, collecting the field errors of all of them.
+fn parse_orders(values: &[Value]) -> Result<Vec<OrderStatus>, StoreError> {
+    let mut orders = Vec::new();
+    let mut errors = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        match OrderStatus::try_from(value) {
+            Ok(order) if order.order_id == 0 => errors.push(FieldError::new(&format!("[{}].order_id", i), "is required")),
+            Ok(order) => orders.push(order),
+            Err(field_errors) => errors.extend(field_errors.into_iter().map(|e| FieldError {
+                field: format!("[{}].{}", i, e.field),
+                message: e.message,
+            })),
+        }
+    }
+    if errors.is_empty() {
+        Ok(orders)
+    } else {
+        Err(StoreError::Invalid(errors))
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Modification time of the file the orders were read from; `None` before the first load.
+    modified: Option<SystemTime>,
+    loaded: bool,
+    orders: Vec<OrderStatus>,
+}
+
+/// The orders in a JSON file, cached in memory and reloaded when the file changes
+/// on disk. Writes replace the file atomically, so readers never see half of one.
+pub struct OrderStore {
+    path: PathBuf,
+    cache: Mutex<Cache>,
+}
+impl OrderStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        OrderStore {
+            path: path.into(),
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+    /// The store for `orders.json` in `DATA_PATH`, or `data/` next to `Cargo.toml`.
+    pub fn shared() -> &'static OrderStore {
+        static STORE: OnceLock<OrderStore> = OnceLock::new();
+        STORE.get_or_init(|| {
+            let default_path = format!("{}/data", env!("CARGO_MANIFEST_DIR"));
+            let data_path = env::var("DATA_PATH").unwrap_or(default_path);
+            OrderStore::new(Path::new(&data_path).join("orders.json"))
+        })
+    }
+    pub fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+
+    pub fn list(&self) -> Result<Vec<OrderStatus>, StoreError> {
+        let mut cache = self.cache.lock().unwrap();
+        self.reload(&mut cache)?;
+        Ok(cache.orders.clone())
+    }
+    pub fn get(&self, id: i32) -> Result<OrderStatus, StoreError> {
+        self.list()?
+            .into_iter()
+            .find(|o| o.order_id == id)
+            .ok_or(StoreError::NotFound(id))
+    }
+    /// Adds `order`, giving it the ID after the highest one if it has none.
+    pub fn create(&self, mut order: OrderStatus) -> Result<OrderStatus, StoreError> {
+        self.modify(|orders| {
+            if order.order_id == 0 {
+                let max = orders.iter().map(|o| o.order_id).max().unwrap_or(0);
+                order.order_id = max.checked_add(1).ok_or_else(|| {
+                    StoreError::Invalid(vec![FieldError::new("order_id", "is required, no IDs are left to assign")])
+                })?;
+            }
+            if orders.iter().any(|o| o.order_id == order.order_id) {
+                return Err(StoreError::Conflict(order.order_id));
+            }
+            orders.push(order.clone());
+            Ok(order)
+        })
+    }
+    /// Applies `change` to order `id`. The result must keep its ID, and its status
+    /// may only move along the allowed transitions.
+    pub fn update(
+        &self,
+        id: i32,
+        change: impl FnOnce(&OrderStatus) -> Result<OrderStatus, StoreError>,
+    ) -> Result<OrderStatus, StoreError> {
+        self.modify(|orders| {
+            let order = orders
+                .iter_mut()
+                .find(|o| o.order_id == id)
+                .ok_or(StoreError::NotFound(id))?;
+            let updated = change(order)?;
+            let mut errors = Vec::new();
+            if updated.order_id != id {
+                errors.push(FieldError::new("order_id", "cannot be changed"));
+            }
+            if !order.order_status.can_become(updated.order_status) {
+                errors.push(FieldError::new(
+                    "order_status",
+                    format!("cannot change from {} to {}", order.order_status, updated.order_status),
+                ));
+            }
+            if !errors.is_empty() {
+                return Err(StoreError::Invalid(errors));
+            }
+            *order = updated.clone();
+            Ok(updated)
+        })
+    }
+    pub fn delete(&self, id: i32) -> Result<(), StoreError> {
+        self.modify(|orders| {
+            let len = orders.len();
+            orders.retain(|o| o.order_id != id);
+            if orders.len() == len {
+                return Err(StoreError::NotFound(id));
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads the file again if it changed since it was last read. A missing file is an empty list.
+    fn reload(&self, cache: &mut Cache) -> Result<(), StoreError> {
+        let modified = self.modified();
+        if cache.loaded && modified.is_some() && modified == cache.modified {
+            return Ok(());
+        }
+        cache.orders = match fs::read_to_string(&self.path) {
+            Ok(contents) => parse_orders(&serde_json::from_str::<Vec<Value>>(&contents)?)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        cache.modified = modified;
+        cache.loaded = true;
+        Ok(())
+    }
+
+    /// Runs `change` on the current orders and saves them if it succeeds.
+    /// The lock is held throughout, so concurrent requests can't lose each other's writes.
+    fn modify<T>(
+        &self,
+        change: impl FnOnce(&mut Vec<OrderStatus>) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        let mut cache = self.cache.lock().unwrap();
+        self.reload(&mut cache)?;
+        let mut orders = cache.orders.clone();
+        let result = change(&mut orders)?;
+        self.save(&orders)?;
+        cache.orders = orders;
+        cache.modified = self.modified();
+        Ok(result)
+    }
+
+    /// Writes a temporary file next to the real one and renames it into place.
+    fn save(&self, orders: &[OrderStatus]) -> Result<(), StoreError> {
+        let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = self.path.with_file_name(tmp_name);
+        let write = || -> io::Result<()> {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(serde_json::to_string_pretty(orders)?.as_bytes())?;
+            file.write_all(b"\n")?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &self.path)
+        };
+        write().map_err(|e| {
+            let _ = fs::remove_file(&tmp_path);
+            StoreError::Io(e)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn order(id: i32, date: &str, status: Status) -> OrderStatus {
+        OrderStatus {
+            order_id: id,
+            order_date: parse_date(date).unwrap(),
+            order_status: status,
+        }
+    }
+
+    fn temp_store(name: &str) -> (PathBuf, OrderStore) {
+        let dir = env::temp_dir().join(format!("httpserver-store-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("orders.json");
+        let _ = fs::remove_file(&path);
+        (dir, OrderStore::new(path))
+    }
+
+    #[test]
+    fn test_parse_date_formats() {
+        let expected = NaiveDate::from_ymd_opt(2022, 1, 21);
+        for s in ["2022-01-21", "21 Jan 2022", "21 January 2022", "Jan 21, 2022", "2022/01/21", "21.01.2022", "2022-01-21T10:00:00Z"] {
+            assert_eq!(parse_date(s), expected, "{}", s);
+        }
+        assert_eq!(parse_date("2022-02-30"), None);
+        let json = serde_json::to_value(order(1, "21 Jan 2022", Status::Pending)).unwrap();
+        assert_eq!(json, json!({"order_id": 1, "order_date": "2022-01-21", "order_status": "Pending"}));
+    }
+
+    #[test]
+    fn test_status_transitions() {
+        assert!(Status::Pending.can_become(Status::Shipped));
+        assert!(Status::Shipped.can_become(Status::Delivered));
+        assert!(Status::Pending.can_become(Status::Cancelled));
+        assert!(!Status::Delivered.can_become(Status::Pending));
+        assert!(!Status::Cancelled.can_become(Status::Shipped));
+        assert_eq!("canceled".parse::<Status>(), Ok(Status::Cancelled));
+    }
+
+    #[test]
+    fn test_field_errors() {
+        let errors = OrderStatus::try_from(&json!({"order_id": -1, "order_date": "someday", "order_status": "Lost"})).unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["order_id", "order_date", "order_status"]);
+        assert!(OrderStatus::try_from(&json!({"order_date": "21 Jan 2022"})).is_err());
+    }
+
+    #[test]
+    fn test_invalid_data_file() {
+        let (dir, store) = temp_store("invalid");
+        fs::write(dir.join("orders.json"), r#"[{"order_id": 1, "order_date": "21 Jan 2022", "order_status": "Nope"}]"#).unwrap();
+        match store.list() {
+            Err(StoreError::Invalid(errors)) => assert_eq!(errors[0].field, "[0].order_status"),
+            other => panic!("unexpected {:?}", other),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_crud_persists_to_file() {
+        let (dir, store) = temp_store("crud");
+        assert!(store.list().unwrap().is_empty());
+        let created = store.create(order(0, "21 Jan 2022", Status::Pending)).unwrap();
+        assert_eq!(created.order_id, 1);
+        assert!(matches!(
+            store.create(order(1, "22 Jan 2022", Status::Pending)),
+            Err(StoreError::Conflict(1))
+        ));
+        store
+            .update(1, |o| Ok(OrderStatus { order_status: Status::Shipped, ..o.clone() }))
+            .unwrap();
+        assert!(store.update(1, |o| Ok(OrderStatus { order_id: 2, ..o.clone() })).is_err());
+        assert!(store.update(1, |o| Ok(OrderStatus { order_status: Status::Pending, ..o.clone() })).is_err());
+
+        let reopened = OrderStore::new(dir.join("orders.json"));
+        assert_eq!(reopened.get(1).unwrap().order_status, Status::Shipped);
+        reopened.delete(1).unwrap();
+        assert!(matches!(reopened.delete(1), Err(StoreError::NotFound(1))));
+        assert!(!dir.join("orders.json.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_ids_run_out() {
+        let json = json!({"order_id": i32::MAX, "order_date": "21 Jan 2022", "order_status": "Pending"});
+        assert_eq!(OrderStatus::try_from(&json).unwrap_err()[0].field, "order_id");
+        let (dir, store) = temp_store("ids");
+        store.create(order(i32::MAX, "21 Jan 2022", Status::Pending)).unwrap();
+        match store.create(order(0, "22 Jan 2022", Status::Pending)) {
+            Err(StoreError::Invalid(errors)) => assert_eq!(errors[0].field, "order_id"),
+            other => panic!("unexpected {:?}", other),
+        }
+        // The failed create leaves the store usable.
+        assert_eq!(store.create(order(7, "22 Jan 2022", Status::Pending)).unwrap().order_id, 7);
+        assert_eq!(store.list().unwrap().len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
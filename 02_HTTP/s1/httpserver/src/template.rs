@@ -0,0 +1,391 @@
+use crate::handler::resolve_path;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// How deeply includes may nest, so that a template including itself fails instead of looping.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    NotFound(String),
+    Io(String, io::Error),
+    Syntax(String, String),
+    IncludeTooDeep(String),
+}
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::NotFound(name) => write!(f, "template {} not found", name),
+            TemplateError::Io(name, e) => write!(f, "template {}: {}", name, e),
+            TemplateError::Syntax(name, msg) => write!(f, "template {}: {}", name, msg),
+            TemplateError::IncludeTooDeep(name) => write!(f, "template {}: includes nested too deeply", name),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    /// `{{ path }}`, HTML-escaped unless written `{{ path | raw }}`.
+    Var { path: String, raw: bool },
+    /// `{% if [not] path %} ... {% else %} ... {% endif %}`
+    If { path: String, negate: bool, then: Vec<Node>, otherwise: Vec<Node> },
+    /// `{% for name in path %} ... {% endfor %}`, with `loop.index`, `loop.first` and `loop.last` in the body.
+    For { name: String, path: String, body: Vec<Node> },
+    /// `{% include "file.html" %}`, rendered with the current variables.
+    Include(String),
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Var(&'a str),
+    Tag(&'a str),
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = src;
+    while let Some(start) = rest.find('{') {
+        let (open, close) = match rest[start..].get(..2) {
+            Some("{{") => ("{{", "}}"),
+            Some("{%") => ("{%", "%}"),
+            Some("{#") => ("{#", "#}"),
+            _ => {
+                tokens.push(Token::Text(&rest[..start + 1]));
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+        if start > 0 {
+            tokens.push(Token::Text(&rest[..start]));
+        }
+        let inner_start = start + open.len();
+        let end = match rest[inner_start..].find(close) {
+            Some(end) => inner_start + end,
+            None => return Err(format!("unclosed {}", open)),
+        };
+        let inner = rest[inner_start..end].trim();
+        match open {
+            "{{" => tokens.push(Token::Var(inner)),
+            "{%" => tokens.push(Token::Tag(inner)),
+            _ => {}
+        }
+        rest = &rest[end + close.len()..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest));
+    }
+    Ok(tokens)
+}
+
+/// Parses nodes until one of `end_tags`, which is returned along with them.
+fn parse_nodes<'a>(
+    tokens: &[Token<'a>],
+    pos: &mut usize,
+    end_tags: &[&str],
+) -> Result<(Vec<Node>, Option<&'a str>), String> {
+    let mut nodes = Vec::new();
+    while let Some(token) = tokens.get(*pos) {
+        *pos += 1;
+        match token {
+            Token::Text(text) => nodes.push(Node::Text(text.to_string())),
+            Token::Var(expr) => {
+                let (path, raw) = match expr.split_once('|') {
+                    Some((path, filter)) if filter.trim() == "raw" => (path.trim(), true),
+                    Some((_, filter)) => return Err(format!("unknown filter \"{}\"", filter.trim())),
+                    None => (*expr, false),
+                };
+                nodes.push(Node::Var {
+                    path: path.to_string(),
+                    raw,
+                });
+            }
+            Token::Tag(tag) => {
+                let words: Vec<&str> = tag.split_whitespace().collect();
+                match words.as_slice() {
+                    [end] if end_tags.contains(end) => return Ok((nodes, Some(*end))),
+                    ["if", cond @ ..] => {
+                        let (negate, path) = match cond {
+                            ["not", path] => (true, *path),
+                            [path] => (false, *path),
+                            _ => return Err(format!("bad condition in {{% {} %}}", tag)),
+                        };
+                        let (then, end) = parse_nodes(tokens, pos, &["else", "endif"])?;
+                        let otherwise = match end {
+                            Some("else") => parse_nodes(tokens, pos, &["endif"])?.0,
+                            Some(_) => Vec::new(),
+                            None => return Err("missing {% endif %}".into()),
+                        };
+                        nodes.push(Node::If {
+                            path: path.to_string(),
+                            negate,
+                            then,
+                            otherwise,
+                        });
+                    }
+                    ["for", name, "in", path] => {
+                        let (body, end) = parse_nodes(tokens, pos, &["endfor"])?;
+                        if end.is_none() {
+                            return Err("missing {% endfor %}".into());
+                        }
+                        nodes.push(Node::For {
+                            name: name.to_string(),
+                            path: path.to_string(),
+                            body,
+                        });
+                    }
+                    ["include", name] if name.len() > 1 && name.starts_with('"') && name.ends_with('"') => {
+                        nodes.push(Node::Include(name.trim_matches('"').to_string()));
+                    }
+                    _ => return Err(format!("unexpected {{% {} %}}", tag)),
+                }
+            }
+        }
+    }
+    Ok((nodes, None))
+}
+
+/// A parsed template. Missing variables render as nothing and are false in conditions.
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+impl Template {
+    pub fn parse(name: &str, src: &str) -> Result<Template, TemplateError> {
+        let syntax = |msg: String| TemplateError::Syntax(name.to_string(), msg);
+        let tokens = tokenize(src).map_err(syntax)?;
+        let mut pos = 0;
+        match parse_nodes(&tokens, &mut pos, &[]).map_err(syntax)? {
+            (nodes, None) => Ok(Template { nodes }),
+            (_, Some(tag)) => Err(syntax(format!("unexpected {{% {} %}}", tag))),
+        }
+    }
+}
+
+/// Variables visible while rendering: loop variables, innermost last, over the context.
+struct Scope<'a> {
+    context: &'a Value,
+    locals: Vec<(String, Value)>,
+}
+impl Scope<'_> {
+    fn lookup(&self, path: &str) -> Option<&Value> {
+        let mut parts = path.split('.');
+        let first = parts.next()?;
+        let mut value = match self.locals.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => value,
+            None => self.context.get(first)?,
+        };
+        for part in parts {
+            value = match value {
+                Value::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+                _ => value.get(part)?,
+            };
+        }
+        Some(value)
+    }
+}
+
+fn is_truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(a)) => !a.is_empty(),
+        Some(Value::Object(o)) => !o.is_empty(),
+    }
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+type CachedTemplate = (Option<SystemTime>, Arc<Template>);
+
+/// Loads templates from a directory, keeping each parsed until it changes on disk
+/// if `reload` is set (development mode), or for good otherwise.
+#[derive(Debug)]
+pub struct Templates {
+    root: PathBuf,
+    reload: bool,
+    /// Parsed templates by name, with the modification time they were read at.
+    cache: Mutex<HashMap<String, CachedTemplate>>,
+}
+impl Templates {
+    pub fn new(root: impl Into<PathBuf>, reload: bool) -> Self {
+        Templates {
+            root: root.into(),
+            reload,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let path = resolve_path(&self.root, name).ok_or_else(|| TemplateError::NotFound(name.to_string()))?;
+        let mut cache = self.cache.lock().unwrap();
+        let modified = match cache.get(name) {
+            Some((_, template)) if !self.reload => return Ok(Arc::clone(template)),
+            _ => fs::metadata(&path).and_then(|m| m.modified()).ok(),
+        };
+        if let Some((cached_modified, template)) = cache.get(name) {
+            if modified.is_some() && *cached_modified == modified {
+                return Ok(Arc::clone(template));
+            }
+        }
+        let src = fs::read_to_string(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => TemplateError::NotFound(name.to_string()),
+            _ => TemplateError::Io(name.to_string(), e),
+        })?;
+        let template = Arc::new(Template::parse(name, &src)?);
+        cache.insert(name.to_string(), (modified, Arc::clone(&template)));
+        Ok(template)
+    }
+
+    pub fn render(&self, name: &str, context: &Value) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut scope = Scope {
+            context,
+            locals: Vec::new(),
+        };
+        let template = self.get(name)?;
+        self.render_nodes(name, &template.nodes, &mut scope, &mut out, 0)?;
+        Ok(out)
+    }
+
+    fn render_nodes(
+        &self,
+        name: &str,
+        nodes: &[Node],
+        scope: &mut Scope,
+        out: &mut String,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Var { path, raw } => {
+                    let text = match scope.lookup(path) {
+                        None | Some(Value::Null) => String::new(),
+                        Some(Value::String(s)) => s.clone(),
+                        Some(value) => value.to_string(),
+                    };
+                    if *raw {
+                        out.push_str(&text);
+                    } else {
+                        out.push_str(&escape_html(&text));
+                    }
+                }
+                Node::If { path, negate, then, otherwise } => {
+                    let branch = if is_truthy(scope.lookup(path)) != *negate { then } else { otherwise };
+                    self.render_nodes(name, branch, scope, out, depth)?;
+                }
+                Node::For { name: var, path, body } => {
+                    let items = match scope.lookup(path) {
+                        Some(Value::Array(items)) => items.clone(),
+                        _ => Vec::new(),
+                    };
+                    let len = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let info = serde_json::json!({ "index": i + 1, "first": i == 0, "last": i + 1 == len });
+                        scope.locals.push(("loop".to_string(), info));
+                        scope.locals.push((var.clone(), item));
+                        let result = self.render_nodes(name, body, scope, out, depth);
+                        scope.locals.truncate(scope.locals.len() - 2);
+                        result?;
+                    }
+                }
+                Node::Include(included) => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(TemplateError::IncludeTooDeep(name.to_string()));
+                    }
+                    let template = self.get(included)?;
+                    self.render_nodes(included, &template.nodes, scope, out, depth + 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::env;
+
+    fn temp_templates(name: &str, files: &[(&str, &str)], reload: bool) -> (PathBuf, Templates) {
+        let dir = env::temp_dir().join(format!("httpserver-templates-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("partials")).unwrap();
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        (dir.clone(), Templates::new(dir, reload))
+    }
+
+    #[test]
+    fn test_render() {
+        let page = "{# comment #}<h1>{{ title }}</h1>{% if not items %}none{% else %}<ul>\
+                    {% for item in items %}<li>{{ loop.index }}. {{ item.name }}{% if loop.last %}!{% endif %}</li>{% endfor %}\
+                    </ul>{% endif %}{{ html | raw }}{% include \"partials/footer.html\" %}{ok}";
+        let (dir, templates) = temp_templates(
+            "render",
+            &[("page.html", page), ("partials/footer.html", "<p>{{ title }}</p>")],
+            false,
+        );
+        let context = json!({
+            "title": "<Orders & more>",
+            "items": [{ "name": "a" }, { "name": "b" }],
+            "html": "<br>",
+        });
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<h1>&lt;Orders &amp; more&gt;</h1><ul><li>1. a</li><li>2. b!</li></ul><br>\
+             <p>&lt;Orders &amp; more&gt;</p>{ok}"
+        );
+        assert_eq!(
+            templates.render("page.html", &json!({})).unwrap(),
+            "<h1></h1>none<p></p>{ok}"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        for src in ["{{ x", "{% if x %}", "{% for x in %}{% endfor %}", "{% endif %}", "{{ x | upper }}"] {
+            assert!(matches!(Template::parse("t", src), Err(TemplateError::Syntax(..))), "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_includes_and_reload() {
+        let (dir, templates) = temp_templates("reload", &[("loop.html", "{% include \"loop.html\" %}")], true);
+        assert!(matches!(templates.render("loop.html", &json!({})), Err(TemplateError::IncludeTooDeep(_))));
+        assert!(matches!(templates.render("../etc/passwd", &json!({})), Err(TemplateError::NotFound(_))));
+
+        fs::write(dir.join("page.html"), "one").unwrap();
+        assert_eq!(templates.render("page.html", &json!({})).unwrap(), "one");
+        fs::write(dir.join("page.html"), "two").unwrap();
+        // Make sure the change is visible even on file systems with coarse timestamps.
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        fs::File::options().write(true).open(dir.join("page.html")).unwrap().set_modified(later).unwrap();
+        assert_eq!(templates.render("page.html", &json!({})).unwrap(), "two");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
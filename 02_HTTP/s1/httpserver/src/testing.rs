@@ -0,0 +1,349 @@
+//! Runs requests through the routers over an in-memory connection, so that
+//! handlers can be tested without binding a port.
+
+use crate::router::Router;
+use crate::server::Connection;
+use crate::vhost::VirtualHosts;
+use http::chunked::ChunkedReader;
+use http::httprequest::HttpRequest;
+use serde_json::Value;
+use std::io::{self, BufRead, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, fs};
+
+/// Headers left out of snapshots because they change from run to run.
+const VOLATILE_HEADERS: [&str; 2] = ["date", "age"];
+
+/// A connection whose request comes from a buffer and whose response goes to one.
+pub struct MemoryStream {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+    peer: Option<SocketAddr>,
+}
+impl MemoryStream {
+    pub fn new(input: Vec<u8>, peer: SocketAddr) -> Self {
+        MemoryStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+            peer: Some(peer),
+        }
+    }
+    /// A connection from a Unix socket client, which has no address.
+    pub fn unix(input: Vec<u8>) -> Self {
+        MemoryStream {
+            peer: None,
+            ..MemoryStream::new(input, ([0, 0, 0, 0], 0).into())
+        }
+    }
+    /// What was written to the connection so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+impl BufRead for MemoryStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.input.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        self.input.consume(amt)
+    }
+}
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl Connection for MemoryStream {
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+}
+
+/// The hosts under test. Requests are routed as `handle_connection` would,
+/// minus the metrics and logging around them.
+pub struct TestServer {
+    hosts: VirtualHosts,
+}
+impl TestServer {
+    pub fn new(router: Router) -> Self {
+        TestServer::with_hosts(VirtualHosts::new(router))
+    }
+    pub fn with_hosts(hosts: VirtualHosts) -> Self {
+        TestServer { hosts }
+    }
+    /// The public files and templates of this crate, as served by default.
+    pub fn document_root() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("public")
+    }
+    pub fn client(&self) -> TestClient<'_> {
+        TestClient {
+            server: self,
+            headers: Vec::new(),
+            peer: "127.0.0.1:40000".parse().unwrap(),
+        }
+    }
+
+    /// Routes the raw request `input` and returns what was written back.
+    pub fn exchange(&self, input: Vec<u8>, peer: SocketAddr) -> Vec<u8> {
+        let mut stream = MemoryStream::new(input, peer);
+        let mut req = HttpRequest::read_head(&mut stream).expect("request head");
+        self.hosts.select(req.header("Host")).route(&mut req, &mut stream);
+        stream.output
+    }
+}
+
+/// Sends requests to a `TestServer`, with headers common to all of them.
+pub struct TestClient<'s> {
+    server: &'s TestServer,
+    headers: Vec<(String, String)>,
+    peer: SocketAddr,
+}
+impl<'s> TestClient<'s> {
+    /// Sends `name: value` with every request, e.g. `Authorization`.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    /// The address requests appear to come from.
+    pub fn peer(mut self, addr: &str) -> Self {
+        self.peer = addr.parse().expect("socket address");
+        self
+    }
+    pub fn get(&self, path: &str) -> TestRequest<'_, 's> {
+        self.request("GET", path)
+    }
+    pub fn post(&self, path: &str) -> TestRequest<'_, 's> {
+        self.request("POST", path)
+    }
+    pub fn put(&self, path: &str) -> TestRequest<'_, 's> {
+        self.request("PUT", path)
+    }
+    pub fn patch(&self, path: &str) -> TestRequest<'_, 's> {
+        self.request("PATCH", path)
+    }
+    pub fn delete(&self, path: &str) -> TestRequest<'_, 's> {
+        self.request("DELETE", path)
+    }
+    pub fn request(&self, method: &str, path: &str) -> TestRequest<'_, 's> {
+        TestRequest {
+            client: self,
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: self.headers.clone(),
+            body: Vec::new(),
+        }
+    }
+}
+
+pub struct TestRequest<'c, 's> {
+    client: &'c TestClient<'s>,
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+impl TestRequest<'_, '_> {
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+    pub fn json(self, body: &Value) -> Self {
+        self.header("Content-Type", "application/json").body(body.to_string())
+    }
+
+    pub fn send(self) -> TestResponse {
+        let has = |name: &str| self.headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name));
+        let mut raw = format!("{} {} HTTP/1.1\r\n", self.method, self.path);
+        if !has("Host") {
+            raw.push_str("Host: localhost\r\n");
+        }
+        if !self.body.is_empty() && !has("Content-Length") {
+            raw.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        for (name, value) in &self.headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        let mut input = raw.into_bytes();
+        input.extend_from_slice(&self.body);
+        TestResponse::parse(&self.client.server.exchange(input, self.client.peer))
+    }
+}
+
+/// A response read back from the connection, with assertions that print the body when they fail.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+impl TestResponse {
+    /// Parses an HTTP/1.1 response, decoding a chunked body.
+    pub fn parse(raw: &[u8]) -> TestResponse {
+        let end = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap_or_else(|| panic!("no response head in {:?}", String::from_utf8_lossy(raw)));
+        let head = String::from_utf8_lossy(&raw[..end]);
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or("");
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_else(|| panic!("bad status line {:?}", status_line));
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let mut body = raw[end + 4..].to_vec();
+        let chunked = headers
+            .iter()
+            .any(|(n, v)| n.eq_ignore_ascii_case("Transfer-Encoding") && v.eq_ignore_ascii_case("chunked"));
+        if chunked {
+            let mut decoded = Vec::new();
+            ChunkedReader::new(&body[..]).read_to_end(&mut decoded).expect("chunked body");
+            body = decoded;
+        }
+        TestResponse { status, headers, body }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or_else(|e| panic!("body is not JSON ({}): {}", e, self.text()))
+    }
+
+    #[track_caller]
+    pub fn assert_status(&self, status: u16) -> &Self {
+        assert_eq!(self.status, status, "unexpected status, body: {}", self.text());
+        self
+    }
+    #[track_caller]
+    pub fn assert_header(&self, name: &str, value: &str) -> &Self {
+        assert_eq!(self.header(name), Some(value), "header {} of {:?}", name, self.headers);
+        self
+    }
+    #[track_caller]
+    pub fn assert_no_header(&self, name: &str) -> &Self {
+        assert_eq!(self.header(name), None, "header {} of {:?}", name, self.headers);
+        self
+    }
+    #[track_caller]
+    pub fn assert_json(&self, expected: &Value) -> &Self {
+        assert_eq!(&self.json(), expected);
+        self
+    }
+    /// Compares the value at a JSON pointer such as `/0/order_id`.
+    #[track_caller]
+    pub fn assert_json_at(&self, pointer: &str, expected: &Value) -> &Self {
+        let json = self.json();
+        assert_eq!(json.pointer(pointer), Some(expected), "{} in {}", pointer, json);
+        self
+    }
+    #[track_caller]
+    pub fn assert_body_contains(&self, needle: &str) -> &Self {
+        assert!(self.text().contains(needle), "{:?} not in body: {}", needle, self.text());
+        self
+    }
+
+    /// The status, sorted headers and body, with JSON pretty-printed, as kept in snapshot files.
+    pub fn snapshot(&self) -> String {
+        let mut headers: Vec<String> = self
+            .headers
+            .iter()
+            .map(|(n, v)| (n.to_ascii_lowercase(), v))
+            .filter(|(n, _)| !VOLATILE_HEADERS.contains(&n.as_str()))
+            .map(|(n, v)| format!("{}: {}", n, v))
+            .collect();
+        headers.sort();
+        let body = match serde_json::from_slice::<Value>(&self.body) {
+            Ok(json) => serde_json::to_string_pretty(&json).unwrap_or_default(),
+            Err(_) => self.text(),
+        };
+        format!("{}\n{}\n\n{}\n", self.status, headers.join("\n"), body)
+    }
+    /// Compares the response with `src/snapshots/<name>.snap`, which is written
+    /// if it doesn't exist yet or `UPDATE_SNAPSHOTS` is set.
+    #[track_caller]
+    pub fn assert_snapshot(&self, name: &str) -> &Self {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/snapshots")
+            .join(format!("{}.snap", name));
+        let actual = self.snapshot();
+        match fs::read_to_string(&path) {
+            Ok(expected) if env::var_os("UPDATE_SNAPSHOTS").is_none() => assert_eq!(
+                actual,
+                expected,
+                "snapshot {} differs; rerun with UPDATE_SNAPSHOTS=1 to accept",
+                path.display()
+            ),
+            _ => {
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, actual).unwrap();
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::Site;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_chunked_response() {
+        let raw = b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\nX-Id: 7\r\n\r\n4\r\n{\"a\"\r\n3\r\n:1}\r\n0\r\n\r\n";
+        let resp = TestResponse::parse(raw);
+        resp.assert_status(201)
+            .assert_header("x-id", "7")
+            .assert_json(&json!({ "a": 1 }))
+            .assert_json_at("/a", &json!(1));
+        assert_eq!(resp.snapshot(), "201\ntransfer-encoding: chunked\nx-id: 7\n\n{\n  \"a\": 1\n}\n");
+    }
+
+    #[test]
+    fn test_requests_reach_the_router() {
+        let server = TestServer::new(Router::new(Site::new(TestServer::document_root())));
+        let client = server.client();
+        client
+            .get("/")
+            .send()
+            .assert_status(200)
+            .assert_header("Content-Type", "text/html")
+            .assert_body_contains("<html");
+        client.get("/missing.html").send().assert_status(404).assert_body_contains("404");
+        client
+            .post("/api/shipping/orders")
+            .json(&json!({ "order_date": "someday" }))
+            .send()
+            .assert_status(422)
+            .assert_json_at("/errors/0/field", &json!("order_date"));
+    }
+}
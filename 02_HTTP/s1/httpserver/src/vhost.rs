@@ -0,0 +1,130 @@
+use crate::router::Router;
+
+/// A host name to serve: an exact name such as `example.com`, or `*.example.com`
+/// for any subdomain of `example.com` (but not `example.com` itself).
+#[derive(Debug, PartialEq)]
+pub enum HostPattern {
+    Exact(String),
+    /// The suffix including its leading dot, e.g. `.example.com`.
+    Wildcard(String),
+}
+impl From<&str> for HostPattern {
+    fn from(s: &str) -> HostPattern {
+        let name = normalize_host(s);
+        match name.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostPattern::Wildcard(suffix.to_string()),
+            _ => HostPattern::Exact(name),
+        }
+    }
+}
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => name == host,
+            HostPattern::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+/// Lower-cases a `Host` header value and strips its port and any trailing dot.
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let without_port = if host.starts_with('[') {
+        // IPv6 literal, e.g. `[::1]:3000`.
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        }
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        }
+    };
+    without_port.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Picks the router for a request by its `Host` header. Exact names win over
+/// wildcards, longer wildcards over shorter ones, and anything else goes to the default host.
+pub struct VirtualHosts {
+    hosts: Vec<(Vec<HostPattern>, Router)>,
+    default: Router,
+}
+impl VirtualHosts {
+    pub fn new(default: Router) -> Self {
+        VirtualHosts {
+            hosts: Vec::new(),
+            default,
+        }
+    }
+    pub fn add(&mut self, names: &[String], router: Router) {
+        let patterns = names.iter().map(|n| HostPattern::from(n.as_str())).collect();
+        self.hosts.push((patterns, router));
+    }
+    pub fn select(&self, host: Option<&str>) -> &Router {
+        let host = match host {
+            Some(h) => normalize_host(h),
+            None => return &self.default,
+        };
+        let patterns = || {
+            self.hosts
+                .iter()
+                .flat_map(|(patterns, router)| patterns.iter().map(move |p| (p, router)))
+        };
+        if let Some((_, router)) = patterns().find(|(p, _)| matches!(p, HostPattern::Exact(_)) && p.matches(&host)) {
+            return router;
+        }
+        patterns()
+            .filter_map(|(p, router)| match p {
+                HostPattern::Wildcard(suffix) if p.matches(&host) => Some((suffix.len(), router)),
+                _ => None,
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, router)| router)
+            .unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::Site;
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("Example.COM:3000"), "example.com");
+        assert_eq!(normalize_host(" example.com. "), "example.com");
+        assert_eq!(normalize_host("[::1]:3000"), "[::1]");
+    }
+
+    #[test]
+    fn test_host_patterns() {
+        let wildcard = HostPattern::from("*.Example.com");
+        assert_eq!(wildcard, HostPattern::Wildcard(".example.com".into()));
+        assert!(wildcard.matches("a.example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+        assert!(HostPattern::from("example.com:80").matches("example.com"));
+    }
+
+    #[test]
+    fn test_select_prefers_exact_then_longest_wildcard() {
+        let mut hosts = VirtualHosts::new(Router::new(Site::new("default")));
+        hosts.add(&["*.example.com".into()], Router::new(Site::new("wild")));
+        hosts.add(&["*.shop.example.com".into()], Router::new(Site::new("shop")));
+        hosts.add(&["www.shop.example.com".into()], Router::new(Site::new("www")));
+        let is = |host: Option<&str>, idx: Option<usize>| {
+            let expected = match idx {
+                Some(i) => &hosts.hosts[i].1,
+                None => &hosts.default,
+            };
+            std::ptr::eq(hosts.select(host), expected)
+        };
+        assert!(is(Some("WWW.shop.example.com:3000"), Some(2)));
+        assert!(is(Some("api.shop.example.com"), Some(1)));
+        assert!(is(Some("blog.example.com"), Some(0)));
+        assert!(is(Some("example.com"), None));
+        assert!(is(None, None));
+    }
+}